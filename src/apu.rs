@@ -3,6 +3,7 @@ mod envelope;
 mod filter;
 mod frame_counter;
 mod length_counter;
+mod mmc5_audio;
 mod noise_channel;
 mod pulse_channel;
 mod sequencer;
@@ -14,11 +15,14 @@ use self::envelope::Envelope;
 use self::filter::FirstOrderFilter;
 use self::frame_counter::{FrameCounter, FrameResult};
 use self::length_counter::LengthCounter;
+pub use self::mmc5_audio::Mmc5Audio;
 use self::noise_channel::NoiseChannel;
 use self::pulse_channel::PulseChannel;
 use self::sequencer::Sequencer;
 use self::sweep::{Sweep, SweepNegationMode};
 use self::triangle_channel::TriangleChannel;
+use crate::region::Region;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 pub struct Apu {
     pub buffer: Vec<i16>,
@@ -28,7 +32,16 @@ pub struct Apu {
     triangle: TriangleChannel,
     noise: NoiseChannel,
     pub dmc: DmcChannel,
+    // Sound from the cartridge's own channels, mixed in with the rest
+    pub expansion: f64,
     filters: [FirstOrderFilter; 3],
+    region: Region,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Apu {
@@ -41,14 +54,23 @@ impl Apu {
             triangle: TriangleChannel::new(),
             noise: NoiseChannel::new(),
             dmc: DmcChannel::new(),
+            expansion: 0.0,
             filters: [
                 FirstOrderFilter::high_pass(44100.0, 90.0),
                 FirstOrderFilter::high_pass(44100.0, 440.0),
                 FirstOrderFilter::low_pass(44100.0, 14_000.0),
             ],
+            region: Region::Ntsc,
         }
     }
 
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.frame_counter.region = region;
+        self.noise.region = region;
+        self.dmc.region = region;
+    }
+
     pub fn reset(&mut self) {
         self.write_register(0x4017, 0, 0);
         for i in 0..11 {
@@ -57,6 +79,14 @@ impl Apu {
     }
 
     pub fn read_register(&mut self) -> u8 {
+        let result = self.peek_register();
+        self.frame_counter.private_irq_flag = false;
+        self.frame_counter.public_irq_flag = false;
+        result
+    }
+
+    // The $4015 status a read would return, without acknowledging the frame IRQ
+    pub fn peek_register(&self) -> u8 {
         let mut result = 0;
         if self.dmc.irq_flag {
             result |= 0b1000_0000;
@@ -79,9 +109,6 @@ impl Apu {
         if self.pulse_0.playing() {
             result |= 0b0000_0001;
         }
-
-        self.frame_counter.private_irq_flag = false;
-        self.frame_counter.public_irq_flag = false;
         result
     }
 
@@ -129,8 +156,9 @@ impl Apu {
 
         // We need 730 stereo audio samples per frame for 60 fps.
         // Each frame lasts a minimum of 29,779 CPU cycles. This
-        // works out to around 40 CPU cycles per sample.
-        if cpu_cycles % 40 == 0 {
+        // works out to around 40 CPU cycles per sample, or 37 on the
+        // slower PAL CPU.
+        if cpu_cycles.is_multiple_of(self.region.cpu_cycles_per_sample()) {
             let s = self.sample();
             self.buffer.push(s);
             self.buffer.push(s);
@@ -175,7 +203,7 @@ impl Apu {
         let tnd_out = 159.79 / ((1.0 / (t / 8227.0 + n / 12241.0 + d / 22638.0)) + 100.0);
 
         // Scale to 0..65536
-        let mut output = (pulse_out + tnd_out) * 65535.0;
+        let mut output = (pulse_out + tnd_out + self.expansion) * 65535.0;
 
         // Apply high pass and low pass filters
         for i in 0..3 {
@@ -186,3 +214,66 @@ impl Apu {
         output as i16
     }
 }
+
+impl SaveState for Apu {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.frame_counter.save_state(state);
+        self.pulse_0.save_state(state);
+        self.pulse_1.save_state(state);
+        self.triangle.save_state(state);
+        self.noise.save_state(state);
+        self.dmc.save_state(state);
+        for f in self.filters.iter() {
+            f.save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.frame_counter.load_state(state)?;
+        self.pulse_0.load_state(state)?;
+        self.pulse_1.load_state(state)?;
+        self.triangle.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.dmc.load_state(state)?;
+        for f in self.filters.iter_mut() {
+            f.load_state(state)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_peek_keeps_frame_irq() {
+        let mut apu = Apu::new();
+        apu.frame_counter.private_irq_flag = true;
+        apu.frame_counter.public_irq_flag = true;
+        assert_eq!(apu.peek_register(), 0b0100_0000);
+        assert!(apu.irq_flag());
+        assert_eq!(apu.read_register(), 0b0100_0000);
+        assert!(!apu.irq_flag());
+    }
+
+    // The frame IRQ comes once every 4-step sequence
+    fn frame_irq_cycle(region: Region) -> u64 {
+        let mut apu = Apu::new();
+        apu.set_region(region);
+        apu.write_register(0x4017, 0, 0);
+        (1..50_000)
+            .find(|&c| {
+                apu.tick(c);
+                apu.irq_flag()
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_region_frame_irq() {
+        assert_eq!(frame_irq_cycle(Region::Ntsc), 29_832);
+        assert_eq!(frame_irq_cycle(Region::Dendy), 29_832);
+        assert_eq!(frame_irq_cycle(Region::Pal), 33_256);
+    }
+}
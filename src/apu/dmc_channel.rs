@@ -1,4 +1,7 @@
+use crate::cdl;
 use crate::cartridge::Cartridge;
+use crate::region::Region;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -7,6 +10,11 @@ pub const PERIODS: [u8; 16] = [
     214, 190, 170, 160, 143, 127, 113, 107, 95, 80, 71, 64, 53, 42, 36, 27
 ];
 
+#[cfg_attr(rustfmt, rustfmt_skip)]
+pub const PAL_PERIODS: [u8; 16] = [
+    199, 177, 158, 149, 138, 118, 105, 99, 88, 74, 66, 59, 49, 39, 33, 25
+];
+
 pub struct DmcChannel {
     cartridge: Option<Rc<RefCell<Cartridge>>>,
     pub irq_enabled: bool,
@@ -23,6 +31,7 @@ pub struct DmcChannel {
     counter: u8,
     looping: bool,
     cpu_stall_cycles: u8,
+    pub region: Region,
 }
 
 impl DmcChannel {
@@ -43,6 +52,7 @@ impl DmcChannel {
             counter: 0,
             looping: false,
             cpu_stall_cycles: 0,
+            region: Region::Ntsc,
         }
     }
 
@@ -62,7 +72,11 @@ impl DmcChannel {
                 self.irq_enabled = value & 0b1000_0000 != 0;
                 self.irq_flag &= self.irq_enabled;
                 self.looping = value & 0b0100_0000 != 0;
-                self.period = PERIODS[value as usize & 0x0F];
+                let periods = match self.region {
+                    Region::Pal => &PAL_PERIODS,
+                    Region::Ntsc | Region::Dendy => &PERIODS,
+                };
+                self.period = periods[value as usize & 0x0F];
             }
             0x4011 => {
                 self.output = value & 0b0111_1111;
@@ -111,7 +125,11 @@ impl DmcChannel {
             self.cpu_stall_cycles += 4;
             let a = self.current_address;
             self.shift_register = match self.cartridge {
-                Some(ref c) => c.borrow_mut().read_prg_byte(a),
+                Some(ref c) => {
+                    let mut c = c.borrow_mut();
+                    c.log_prg(a, cdl::PCM);
+                    c.read_prg_byte(a)
+                }
                 None => 0,
             };
             self.bit_count = 8;
@@ -153,3 +171,40 @@ impl DmcChannel {
         self.current_length > 0
     }
 }
+
+impl SaveState for DmcChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.irq_flag);
+        state.write_bool(self.enabled);
+        state.write_u8(self.output);
+        state.write_u16(self.sample_address);
+        state.write_u16(self.sample_length);
+        state.write_u16(self.current_address);
+        state.write_u16(self.current_length);
+        state.write_u8(self.shift_register);
+        state.write_u8(self.bit_count);
+        state.write_u8(self.period);
+        state.write_u8(self.counter);
+        state.write_bool(self.looping);
+        state.write_u8(self.cpu_stall_cycles);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.irq_enabled = state.read_bool()?;
+        self.irq_flag = state.read_bool()?;
+        self.enabled = state.read_bool()?;
+        self.output = state.read_u8()?;
+        self.sample_address = state.read_u16()?;
+        self.sample_length = state.read_u16()?;
+        self.current_address = state.read_u16()?;
+        self.current_length = state.read_u16()?;
+        self.shift_register = state.read_u8()?;
+        self.bit_count = state.read_u8()?;
+        self.period = state.read_u8()?;
+        self.counter = state.read_u8()?;
+        self.looping = state.read_bool()?;
+        self.cpu_stall_cycles = state.read_u8()?;
+        Ok(())
+    }
+}
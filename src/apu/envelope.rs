@@ -1,3 +1,5 @@
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
 bitfield! {
     #[derive(Copy, Clone, PartialEq)]
     pub struct EnvelopeControl(u8);
@@ -64,3 +66,20 @@ impl Envelope {
         }
     }
 }
+
+impl SaveState for Envelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.control.0);
+        state.write_u8(self.counter);
+        state.write_u8(self.level);
+        state.write_bool(self.start);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.control = EnvelopeControl(state.read_u8()?);
+        self.counter = state.read_u8()?;
+        self.level = state.read_u8()?;
+        self.start = state.read_bool()?;
+        Ok(())
+    }
+}
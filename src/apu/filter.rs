@@ -1,4 +1,5 @@
 use std::f64::consts::PI;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 pub struct FirstOrderFilter {
     b0: f64,
@@ -42,3 +43,16 @@ impl FirstOrderFilter {
         y
     }
 }
+
+impl SaveState for FirstOrderFilter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_f64(self.prev_x);
+        state.write_f64(self.prev_y);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.prev_x = state.read_f64()?;
+        self.prev_y = state.read_f64()?;
+        Ok(())
+    }
+}
@@ -1,3 +1,12 @@
+use crate::region::Region;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+// When each step comes, counted the way tick() counts: the three quarter and
+// half frames both modes share, the end of the 4-step sequence and the end of
+// the 5-step one
+const NTSC_STEPS: [i64; 5] = [7_459, 14_915, 22_373, 29_830, 37_283];
+const PAL_STEPS: [i64; 5] = [8_315, 16_629, 24_941, 33_254, 41_567];
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 enum Mode {
     Zero,
@@ -19,6 +28,7 @@ pub struct FrameCounter {
     pub public_irq_flag: bool,
     pub private_irq_flag: bool,
     mode: Mode,
+    pub region: Region,
 }
 
 impl FrameCounter {
@@ -30,6 +40,14 @@ impl FrameCounter {
             public_irq_flag: false,
             private_irq_flag: false,
             mode: Mode::Zero,
+            region: Region::Ntsc,
+        }
+    }
+
+    fn steps(&self) -> [i64; 5] {
+        match self.region {
+            Region::Pal => PAL_STEPS,
+            Region::Ntsc | Region::Dendy => NTSC_STEPS,
         }
     }
 
@@ -64,20 +82,20 @@ impl FrameCounter {
     }
 
     fn tick_mode_zero(&mut self) -> FrameResult {
+        let steps = self.steps();
         match self.counter {
-            7_459 => FrameResult::Quarter,
-            14_915 => FrameResult::Half,
-            22_373 => FrameResult::Quarter,
-            29_830 => {
+            c if c == steps[0] || c == steps[2] => FrameResult::Quarter,
+            c if c == steps[1] => FrameResult::Half,
+            c if c == steps[3] => {
                 self.trigger_irq();
                 FrameResult::None
             }
-            29_831 => {
+            c if c == steps[3] + 1 => {
                 self.trigger_irq();
                 self.publish_irq();
                 FrameResult::Half
             }
-            29_832 => {
+            c if c == steps[3] + 2 => {
                 self.trigger_irq();
                 self.publish_irq();
                 // The counter *actually* rolls over to zero on cycle 29_830.
@@ -92,11 +110,11 @@ impl FrameCounter {
     }
 
     fn tick_mode_one(&mut self) -> FrameResult {
+        let steps = self.steps();
         match self.counter {
-            7_459 => FrameResult::Quarter,
-            14_915 => FrameResult::Half,
-            22_373 => FrameResult::Quarter,
-            37_283 => {
+            c if c == steps[0] || c == steps[2] => FrameResult::Quarter,
+            c if c == steps[1] => FrameResult::Half,
+            c if c == steps[4] => {
                 // The counter *actually* rolls over to zero on cycle 37_282.
                 // The Half-frame signal is sent 1 tick after. We emulate this
                 // behavior by adding an extra tick to the clock, then skipping
@@ -117,3 +135,28 @@ impl FrameCounter {
         self.public_irq_flag = self.private_irq_flag;
     }
 }
+
+impl SaveState for FrameCounter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_i64(self.counter);
+        state.write_u64(self.cycles);
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.public_irq_flag);
+        state.write_bool(self.private_irq_flag);
+        state.write_u8(self.mode as u8);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.read_i64()?;
+        self.cycles = state.read_u64()?;
+        self.irq_enabled = state.read_bool()?;
+        self.public_irq_flag = state.read_bool()?;
+        self.private_irq_flag = state.read_bool()?;
+        self.mode = match state.read_u8()? {
+            0 => Mode::Zero,
+            1 => Mode::One,
+            _ => return Err(StateError::Invalid("bad frame counter mode")),
+        };
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
 #[cfg_attr(rustfmt, rustfmt_skip)]
 pub const LENGTHS: [u8; 32] = [
     0x0a, 0xfe, 0x14, 0x02, 0x28, 0x04, 0x50, 0x06, 0xa0, 0x08, 0x3c, 0x0a, 0x0e, 0x0c, 0x1a,
@@ -73,3 +75,28 @@ impl LengthCounter {
         self.counter > 0
     }
 }
+
+impl SaveState for LengthCounter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.counter);
+        state.write_bool(self.enabled);
+        state.write_bool(self.halted);
+        state.write_bool(self.pending_halted.is_some());
+        state.write_bool(self.pending_halted.unwrap_or(false));
+        state.write_bool(self.pending_register.is_some());
+        state.write_u8(self.pending_register.unwrap_or(0));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.read_u8()?;
+        self.enabled = state.read_bool()?;
+        self.halted = state.read_bool()?;
+        let has_halted = state.read_bool()?;
+        let halted = state.read_bool()?;
+        self.pending_halted = if has_halted { Some(halted) } else { None };
+        let has_register = state.read_bool()?;
+        let register = state.read_u8()?;
+        self.pending_register = if has_register { Some(register) } else { None };
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+// The MMC5's sound: two pulse channels like the APU's but without sweep
+// units, and an 8-bit PCM channel. The mapper owns it and passes on writes
+// to its registers.
+
+use super::PulseChannel;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+// Envelopes and length counters are clocked at a steady 240Hz rather than
+// by the APU's frame counter
+const FRAME_PERIOD: u64 = 7457;
+
+pub struct Mmc5Audio {
+    pulse_0: PulseChannel,
+    pulse_1: PulseChannel,
+    pub pcm: u8,
+    cycles: u64,
+}
+
+impl Default for Mmc5Audio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mmc5Audio {
+    pub fn new() -> Self {
+        Mmc5Audio {
+            pulse_0: PulseChannel::without_sweep(),
+            pulse_1: PulseChannel::without_sweep(),
+            pcm: 0,
+            cycles: 0,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x5000..=0x5003 => self.pulse_0.write_register(address, value),
+            0x5004..=0x5007 => self.pulse_1.write_register(address, value),
+            0x5015 => {
+                self.pulse_0.set_enabled(value & 0b01 != 0);
+                self.pulse_1.set_enabled(value & 0b10 != 0);
+            }
+            _ => (),
+        }
+    }
+
+    // $5015 reads back which pulse channels are still playing
+    pub fn status(&self) -> u8 {
+        self.pulse_0.playing() as u8 | (self.pulse_1.playing() as u8) << 1
+    }
+
+    pub fn tick(&mut self) {
+        self.cycles += 1;
+        if self.cycles % 2 == 1 {
+            self.pulse_0.tick_sequencer();
+            self.pulse_1.tick_sequencer();
+        }
+        if self.cycles.is_multiple_of(FRAME_PERIOD) {
+            for pulse in [&mut self.pulse_0, &mut self.pulse_1] {
+                pulse.tick_quarter_frame();
+                pulse.tick_half_frame();
+            }
+        }
+        self.pulse_0.update_pending_length_counter();
+        self.pulse_1.update_pending_length_counter();
+    }
+
+    // Mixed like the APU's pulse and DMC channels
+    pub fn output(&self) -> f64 {
+        let pulses = (self.pulse_0.sample() + self.pulse_1.sample()) as f64;
+        let pulse_out = 95.88 / ((8218.0 / pulses) + 100.0);
+        let pcm_out = 159.79 / ((22638.0 / self.pcm as f64) + 100.0);
+        pulse_out + pcm_out
+    }
+}
+
+impl SaveState for Mmc5Audio {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.pulse_0.save_state(state);
+        self.pulse_1.save_state(state);
+        state.write_u8(self.pcm);
+        state.write_u64(self.cycles);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.pulse_0.load_state(state)?;
+        self.pulse_1.load_state(state)?;
+        self.pcm = state.read_u8()?;
+        self.cycles = state.read_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pulse() {
+        let mut audio = Mmc5Audio::new();
+        assert_eq!(audio.output(), 0.0);
+        audio.write_register(0x5015, 0b01);
+        audio.write_register(0x5000, 0b1011_1111); // Constant volume 15
+        // A period this short would be muted on the APU
+        audio.write_register(0x5002, 0x02);
+        audio.write_register(0x5003, 0x08);
+        let mut heard = false;
+        for _ in 0..32 {
+            audio.tick();
+            heard |= audio.output() > 0.0;
+        }
+        assert!(heard);
+        assert_eq!(audio.status(), 0b01);
+        audio.write_register(0x5015, 0);
+        assert_eq!(audio.status(), 0);
+    }
+
+    #[test]
+    fn test_pcm() {
+        let mut audio = Mmc5Audio::new();
+        audio.pcm = 0xFF;
+        let loud = audio.output();
+        audio.pcm = 0x40;
+        assert!(audio.output() > 0.0 && audio.output() < loud);
+    }
+}
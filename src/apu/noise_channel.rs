@@ -1,11 +1,18 @@
 use super::Envelope;
 use super::LengthCounter;
+use crate::region::Region;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 #[cfg_attr(rustfmt, rustfmt_skip)]
 const PERIODS: [u16; 16] = [
     4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
 ];
 
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const PAL_PERIODS: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
 pub struct NoiseChannel {
     envelope: Envelope,
     length_counter: LengthCounter,
@@ -13,6 +20,7 @@ pub struct NoiseChannel {
     period: u16,
     counter: u16,
     shift: u16,
+    pub region: Region,
 }
 
 impl NoiseChannel {
@@ -24,6 +32,7 @@ impl NoiseChannel {
             period: 0,
             counter: 0,
             shift: 1,
+            region: Region::Ntsc,
         }
     }
 
@@ -36,7 +45,11 @@ impl NoiseChannel {
             0x400D => (),
             0x400E => {
                 self.mode = value & 0b1000_0000 != 0;
-                self.period = PERIODS[value as usize & 0b1111];
+                let periods = match self.region {
+                    Region::Pal => &PAL_PERIODS,
+                    Region::Ntsc | Region::Dendy => &PERIODS,
+                };
+                self.period = periods[value as usize & 0b1111];
             }
             0x400F => {
                 self.length_counter.write_register(value);
@@ -74,7 +87,7 @@ impl NoiseChannel {
         self.length_counter.tick();
     }
 
-    pub fn playing(&mut self) -> bool {
+    pub fn playing(&self) -> bool {
         self.length_counter.playing()
     }
 
@@ -86,3 +99,24 @@ impl NoiseChannel {
         self.length_counter.update_pending();
     }
 }
+
+impl SaveState for NoiseChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.envelope.save_state(state);
+        self.length_counter.save_state(state);
+        state.write_bool(self.mode);
+        state.write_u16(self.period);
+        state.write_u16(self.counter);
+        state.write_u16(self.shift);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.envelope.load_state(state)?;
+        self.length_counter.load_state(state)?;
+        self.mode = state.read_bool()?;
+        self.period = state.read_u16()?;
+        self.counter = state.read_u16()?;
+        self.shift = state.read_u16()?;
+        Ok(())
+    }
+}
@@ -2,6 +2,7 @@ use super::Envelope;
 use super::LengthCounter;
 use super::Sequencer;
 use super::{Sweep, SweepNegationMode};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 pub const PULSE_WAVEFORMS: [[u8; 8]; 4] = [
     [0, 1, 0, 0, 0, 0, 0, 0],
@@ -16,6 +17,8 @@ pub struct PulseChannel {
     sequencer: Sequencer,
     length_counter: LengthCounter,
     duty_cycle: usize,
+    // The MMC5's copies of this channel have no sweep unit
+    has_sweep: bool,
 }
 
 impl PulseChannel {
@@ -26,6 +29,14 @@ impl PulseChannel {
             sequencer: Sequencer::new(PULSE_WAVEFORMS[0].len()),
             sweep: Sweep::new(sweep_negation_mode),
             duty_cycle: 0,
+            has_sweep: true,
+        }
+    }
+
+    pub fn without_sweep() -> Self {
+        PulseChannel {
+            has_sweep: false,
+            ..PulseChannel::new(SweepNegationMode::TwosCompliment)
         }
     }
 
@@ -36,7 +47,11 @@ impl PulseChannel {
                 self.envelope.write_register(value);
                 self.length_counter.set_halted(value & 0b0010_0000 != 0)
             }
-            1 => self.sweep.write_register(value),
+            1 => {
+                if self.has_sweep {
+                    self.sweep.write_register(value)
+                }
+            }
             2 => self.sequencer.set_period_low(value),
             3 => {
                 self.length_counter.write_register(value);
@@ -51,10 +66,9 @@ impl PulseChannel {
 
     pub fn sample(&self) -> u8 {
         // TODO: removing the target period check makes arkanoid sound effects work
-        if self.length_counter.active()
-            && self.sequencer.period >= 8
-            && self.sweep.target_period(&self.sequencer) < 0x800
-        {
+        let muted = self.has_sweep
+            && (self.sequencer.period < 8 || self.sweep.target_period(&self.sequencer) >= 0x800);
+        if self.length_counter.active() && !muted {
             PULSE_WAVEFORMS[self.duty_cycle][self.sequencer.current_step] * self.envelope.volume()
         } else {
             0
@@ -74,7 +88,7 @@ impl PulseChannel {
         self.sequencer.tick(true);
     }
 
-    pub fn playing(&mut self) -> bool {
+    pub fn playing(&self) -> bool {
         self.length_counter.playing()
     }
 
@@ -86,3 +100,25 @@ impl PulseChannel {
         self.length_counter.update_pending();
     }
 }
+
+impl SaveState for PulseChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.sweep.save_state(state);
+        self.envelope.save_state(state);
+        self.sequencer.save_state(state);
+        self.length_counter.save_state(state);
+        state.write_usize(self.duty_cycle);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.sweep.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.sequencer.load_state(state)?;
+        self.length_counter.load_state(state)?;
+        self.duty_cycle = state.read_usize()?;
+        if self.duty_cycle >= PULSE_WAVEFORMS.len() {
+            return Err(StateError::Invalid("bad pulse duty cycle"));
+        }
+        Ok(())
+    }
+}
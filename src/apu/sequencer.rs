@@ -1,3 +1,5 @@
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
 pub struct Sequencer {
     pub counter: u16,
     pub period: u16,
@@ -36,3 +38,21 @@ impl Sequencer {
         self.period = (self.period & 0x00FF) | ((value as u16 & 0b111) << 8);
     }
 }
+
+impl SaveState for Sequencer {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.counter);
+        state.write_u16(self.period);
+        state.write_usize(self.current_step);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.read_u16()?;
+        self.period = state.read_u16()?;
+        self.current_step = state.read_usize()?;
+        if self.current_step >= self.steps {
+            return Err(StateError::Invalid("sequencer step out of range"));
+        }
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
 use super::Sequencer;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 #[derive(Copy, Clone, PartialEq)]
 pub enum SweepNegationMode {
@@ -66,3 +67,24 @@ impl Sweep {
         0
     }
 }
+
+impl SaveState for Sweep {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.reload);
+        state.write_u8(self.shift);
+        state.write_bool(self.negate);
+        state.write_u8(self.period);
+        state.write_u8(self.counter);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.reload = state.read_bool()?;
+        self.shift = state.read_u8()?;
+        self.negate = state.read_bool()?;
+        self.period = state.read_u8()?;
+        self.counter = state.read_u8()?;
+        Ok(())
+    }
+}
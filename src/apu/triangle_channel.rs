@@ -1,5 +1,6 @@
 use super::LengthCounter;
 use super::Sequencer;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 #[cfg_attr(rustfmt, rustfmt_skip)]
 pub const TRIANGLE_WAVEFORM: [u8; 32] = [
@@ -83,7 +84,7 @@ impl TriangleChannel {
         self.length_counter.active() && self.linear_counter > 0
     }
 
-    pub fn playing(&mut self) -> bool {
+    pub fn playing(&self) -> bool {
         self.length_counter.playing()
     }
 
@@ -95,3 +96,24 @@ impl TriangleChannel {
         self.length_counter.update_pending();
     }
 }
+
+impl SaveState for TriangleChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.length_counter.save_state(state);
+        self.sequencer.save_state(state);
+        state.write_u8(self.linear_counter);
+        state.write_bool(self.linear_counter_start);
+        state.write_u8(self.linear_counter_period);
+        state.write_bool(self.control_flag);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.length_counter.load_state(state)?;
+        self.sequencer.load_state(state)?;
+        self.linear_counter = state.read_u8()?;
+        self.linear_counter_start = state.read_bool()?;
+        self.linear_counter_period = state.read_u8()?;
+        self.control_flag = state.read_bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+// Headless runner for blargg-style test ROMs. Takes ROM files or directories
+// of them, prints a line per ROM and exits non-zero if any of them failed.
+
+extern crate nes_emu;
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use nes_emu::test_rom::{self, TestStatus};
+use nes_emu::Nes;
+
+const USAGE: &str = "Usage: test_runner [OPTIONS] <ROM or DIR>...
+
+Options:
+    --frames <N>      Give up on a ROM after this many frames (default: 3600)
+    --seed <N>        Seed for console-to-console variation (default: fixed)
+    -v, --verbose     Print the text of passing tests too
+    -h, --help        Print this message";
+
+struct Options {
+    paths: Vec<PathBuf>,
+    frames: u32,
+    seed: Option<u64>,
+    verbose: bool,
+}
+
+impl Options {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Self>, String> {
+        let mut options = Options {
+            paths: Vec::new(),
+            frames: 3600,
+            seed: None,
+            verbose: false,
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "-v" | "--verbose" => options.verbose = true,
+                "--frames" => {
+                    let value = option_value(&mut args, &arg)?;
+                    options.frames = match value.parse() {
+                        Ok(n) if n > 0 => n,
+                        _ => return Err(format!("invalid frame count '{}'", value)),
+                    };
+                }
+                "--seed" => {
+                    let value = option_value(&mut args, &arg)?;
+                    options.seed = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("invalid seed '{}'", value))?,
+                    );
+                }
+                a if a.starts_with('-') => return Err(format!("unknown option '{}'", a)),
+                a => options.paths.push(PathBuf::from(a)),
+            }
+        }
+
+        if options.paths.is_empty() {
+            return Err("no ROM files given".to_string());
+        }
+        Ok(Some(options))
+    }
+}
+
+fn option_value<I: Iterator<Item = String>>(args: &mut I, name: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("missing value for '{}'", name))
+}
+
+// Directories are searched recursively for .nes files, in a stable order
+fn collect_roms(path: &Path, roms: &mut Vec<PathBuf>) -> Result<(), String> {
+    if !path.is_dir() {
+        roms.push(path.to_path_buf());
+        return Ok(());
+    }
+
+    let mut entries = std::fs::read_dir(path)
+        .and_then(|dir| {
+            dir.map(|e| e.map(|e| e.path()))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    entries.sort();
+    for entry in entries {
+        let is_rom = entry
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("nes"));
+        if entry.is_dir() || is_rom {
+            collect_roms(&entry, roms)?;
+        }
+    }
+    Ok(())
+}
+
+// Returns whether the ROM passed
+fn run_rom(path: &Path, options: &Options) -> bool {
+    let bytes = match std::fs::read(path) {
+        Ok(b) => b,
+        Err(e) => {
+            println!("ERROR {}: {}", path.display(), e);
+            return false;
+        }
+    };
+
+    let mut nes = Nes::new();
+    if let Some(seed) = options.seed {
+        nes.set_seed(seed);
+    }
+    if let Err(e) = nes.load_rom(&bytes) {
+        println!("ERROR {}: {}", path.display(), e);
+        return false;
+    }
+
+    let result = test_rom::run(&mut nes, options.frames);
+    let label = match result.status {
+        TestStatus::Passed => "PASS".to_string(),
+        TestStatus::Failed(code) => format!("FAIL({})", code),
+        TestStatus::TimedOut => "TIMEOUT".to_string(),
+    };
+    println!("{} {} [{} frames]", label, path.display(), result.frames);
+    if !result.text.is_empty() && (options.verbose || !result.passed()) {
+        for line in result.text.lines() {
+            println!("    {}", line);
+        }
+    }
+    result.passed()
+}
+
+fn main() {
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let mut roms = Vec::new();
+    for path in &options.paths {
+        if let Err(e) = collect_roms(path, &mut roms) {
+            eprintln!("error: {}", e);
+            process::exit(2);
+        }
+    }
+    if roms.is_empty() {
+        eprintln!("error: no .nes files found");
+        process::exit(2);
+    }
+
+    let passed = roms.iter().filter(|rom| run_rom(rom, &options)).count();
+    println!("{}/{} passed", passed, roms.len());
+    if passed != roms.len() {
+        process::exit(1);
+    }
+}
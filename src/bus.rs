@@ -1,9 +1,16 @@
 use crate::apu::Apu;
-use crate::cartridge::Cartridge;
+use crate::cartridge::{Cartridge, RomError};
+use crate::cdl;
+use crate::cheats::Cheats;
 use crate::controller::Controller;
 use crate::ppu::{result::PpuResult, Ppu};
+use crate::region::Region;
+use crate::rng::{Rng, DEFAULT_SEED};
+use crate::symbols::{Symbol, Symbols};
 use std::cell::RefCell;
+use std::collections::BTreeSet;
 use std::rc::Rc;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 pub struct Interrupt {
     schedule: Option<u8>,
@@ -41,6 +48,67 @@ impl Interrupt {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// Addresses that stop a debugger when the CPU reads or writes them
+pub struct Watchpoints {
+    pub reads: BTreeSet<u16>,
+    pub writes: BTreeSet<u16>,
+    // The first access that matched since this was last cleared
+    pub hit: Option<(Access, u16, u8)>,
+    // Addresses whose accesses go to `log` without stopping anything, kept
+    // apart from the debugger's own
+    pub logged_reads: BTreeSet<u16>,
+    pub logged_writes: BTreeSet<u16>,
+    // Every logged access, when whoever set this up drains it as it goes
+    pub log: Option<Vec<(Access, u16, u8)>>,
+}
+
+impl Default for Watchpoints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Watchpoints {
+    fn new() -> Self {
+        Watchpoints {
+            reads: BTreeSet::new(),
+            writes: BTreeSet::new(),
+            hit: None,
+            logged_reads: BTreeSet::new(),
+            logged_writes: BTreeSet::new(),
+            log: None,
+        }
+    }
+
+    fn watching(&self, access: Access) -> bool {
+        match access {
+            Access::Read => !self.reads.is_empty() || !self.logged_reads.is_empty(),
+            Access::Write => !self.writes.is_empty() || !self.logged_writes.is_empty(),
+        }
+    }
+
+    fn check(&mut self, access: Access, address: u16, value: u8) {
+        let (stops, logged) = match access {
+            Access::Read => (&self.reads, &self.logged_reads),
+            Access::Write => (&self.writes, &self.logged_writes),
+        };
+        if stops.contains(&address) && self.hit.is_none() {
+            self.hit = Some((access, address, value));
+        }
+        if logged.contains(&address) {
+            if let Some(ref mut log) = self.log {
+                log.push((access, address, value));
+            }
+        }
+    }
+}
+
 pub struct Bus {
     pub ram: [u8; 2048],
     pub apu: Apu,
@@ -51,12 +119,23 @@ pub struct Bus {
     pub cycles: u64,
     pub nmi: Interrupt,
     pub draw: bool,
+    pub rng: Rng,
+    pub watchpoints: Watchpoints,
+    pub symbols: Symbols,
+    pub cheats: Cheats,
+    region: Region,
     cpu_stall_cycles: usize,
 }
 
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Bus {
     pub fn new() -> Self {
-        Bus {
+        let mut bus = Bus {
             ppu: Ppu::new(),
             apu: Apu::new(),
             cartridge: None,
@@ -66,7 +145,54 @@ impl Bus {
             cycles: 0,
             nmi: Interrupt::new(),
             draw: false, // add: mapper/cartridge
+            rng: Rng::new(DEFAULT_SEED),
+            watchpoints: Watchpoints::new(),
+            symbols: Symbols::new(),
+            cheats: Cheats::new(),
+            region: Region::Ntsc,
             cpu_stall_cycles: 0,
+        };
+        bus.seed(DEFAULT_SEED);
+        bus
+    }
+
+    // Rolls everything that differs from one console to the next
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+        self.ppu.registers.randomize_decay(&mut self.rng);
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.ppu.renderer.region = region;
+        self.apu.set_region(region);
+    }
+
+    // Where the cartridge maps `address` in PRG-ROM right now
+    pub fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000..=0xFFFF => self.cartridge.as_ref()?.borrow().prg_rom_offset(address),
+            _ => None,
+        }
+    }
+
+    // The symbol starting at `address` in the currently mapped banks
+    pub fn symbol(&self, address: u16) -> Option<&Symbol> {
+        self.symbols.get(address, self.prg_rom_offset(address))
+    }
+
+    pub fn label(&self, address: u16) -> Option<String> {
+        if self.symbols.is_empty() {
+            return None;
+        }
+        self.symbols.label(address, self.prg_rom_offset(address))
+    }
+
+    // Pokes the RAM cheats' values in again, once a frame
+    pub fn apply_ram_cheats(&mut self) {
+        for i in 0..self.cheats.ram_writes().len() {
+            let (address, value) = self.cheats.ram_writes()[i];
+            self.unclocked_write_byte(address, value);
         }
     }
 
@@ -86,7 +212,8 @@ impl Bus {
             0x4017 => self.controller_1.read_register(),
             0x4018..=0xFFFF => {
                 if let Some(ref c) = self.cartridge {
-                    c.borrow().read_prg_byte(address)
+                    let value = c.borrow().read_prg_byte(address);
+                    self.cheats.read_prg_byte(address, value)
                 } else {
                     (address >> 8) as u8
                 }
@@ -95,10 +222,36 @@ impl Bus {
         }
     }
 
-    fn unclocked_write_byte(&mut self, address: u16, value: u8) {
+    // What a read of `address` would return, without any of its side effects.
+    // Debugging tools use this so they don't disturb what they look at.
+    pub fn peek(&self, address: u16) -> u8 {
+        match address {
+            0..=0x1FFF => self.ram[address as usize % 0x0800],
+            0x2000..=0x3FFF => self.ppu.peek_register(address),
+            0x4015 => self.apu.peek_register(),
+            0x4016 => self.controller_0.peek_register(),
+            0x4017 => self.controller_1.peek_register(),
+            0x4018..=0xFFFF => {
+                if let Some(ref c) = self.cartridge {
+                    let value = c.borrow().peek_prg_byte(address);
+                    self.cheats.read_prg_byte(address, value)
+                } else {
+                    (address >> 8) as u8
+                }
+            }
+            address => (address >> 8) as u8,
+        }
+    }
+
+    pub fn unclocked_write_byte(&mut self, address: u16, value: u8) {
         match address {
             0..=0x1FFF => self.ram[address as usize % 0x0800] = value,
-            0x2000..=0x3FFF => self.ppu.write_register(address, value),
+            0x2000..=0x3FFF => {
+                self.ppu.write_register(address, value);
+                if let Some(ref c) = self.cartridge {
+                    c.borrow_mut().ppu_register_written(address, value);
+                }
+            }
             0x4000..=0x4013 | 0x4015 => self.apu.write_register(address, value, self.cycles),
             0x4017 => self.apu.write_register(address, value, self.cycles),
             0x4014 => self.oam_dma(value as u16),
@@ -116,6 +269,17 @@ impl Bus {
         }
     }
 
+    // Writes memory for a debugger without any side effects, which only RAM
+    // and the cartridge's PRG-RAM allow. Returns false for anything else.
+    pub fn poke(&mut self, address: u16, value: u8) -> bool {
+        match (address, &self.cartridge) {
+            (0..=0x1FFF, _) => self.ram[address as usize % 0x0800] = value,
+            (0x6000..=0x7FFF, Some(c)) => c.borrow_mut().write_prg_byte(address, value),
+            _ => return false,
+        }
+        true
+    }
+
     fn oam_dma(&mut self, bank: u16) {
         self.cpu_stall_cycles += 513 + (self.cycles as usize % 2);
         for i in 0..256 {
@@ -125,13 +289,37 @@ impl Bus {
     }
 
     pub fn read_byte<T: Into<u16>>(&mut self, address: T) -> u8 {
+        self.read_logged(address.into(), cdl::DATA)
+    }
+
+    // A read of an opcode or operand byte, which the code/data logger counts as code
+    pub fn read_code_byte(&mut self, address: u16) -> u8 {
+        self.read_logged(address, cdl::CODE)
+    }
+
+    fn read_logged(&mut self, address: u16, flags: u8) -> u8 {
         self.tick();
-        self.unclocked_read_byte(address.into())
+        let value = self.unclocked_read_byte(address);
+        if self.watchpoints.watching(Access::Read) {
+            self.watchpoints.check(Access::Read, address, value);
+        }
+        // Some mappers bank PRG-ROM in below $8000, and log_prg skips
+        // anything prg_rom_offset() doesn't place in the ROM
+        if address >= 0x6000 {
+            if let Some(ref c) = self.cartridge {
+                c.borrow_mut().log_prg(address, flags);
+            }
+        }
+        value
     }
 
     pub fn write_byte<T: Into<u16>>(&mut self, address: T, value: u8) {
         self.tick();
-        self.unclocked_write_byte(address.into(), value)
+        let address = address.into();
+        if self.watchpoints.watching(Access::Write) {
+            self.watchpoints.check(Access::Write, address, value);
+        }
+        self.unclocked_write_byte(address, value)
     }
 
     pub fn read_noncontinuous_word<T: Into<u16>, U: Into<u16>>(&mut self, a: T, b: U) -> u16 {
@@ -143,27 +331,30 @@ impl Bus {
         self.read_noncontinuous_word(address, address + 1)
     }
 
+    pub fn read_code_word(&mut self, address: u16) -> u16 {
+        let low = self.read_code_byte(address) as u16;
+        let high = self.read_code_byte(address.wrapping_add(1)) as u16;
+        low | high << 8
+    }
+
     pub fn tick(&mut self) {
         self.cycles += 1;
 
+        if let Some(ref c) = self.cartridge {
+            let mut c = c.borrow_mut();
+            c.tick();
+            self.apu.expansion = c.audio_output();
+        }
+
         let c = self.cycles;
         self.apu.tick(c);
 
         self.nmi.tick();
 
-        // Roughly 3x per frame. I made this number up.
-        if c % 10_000 == 0 {
-            self.ppu.tick_decay();
+        for _ in 0..self.region.ppu_dots(self.cycles) {
+            let r = self.ppu.tick();
+            self.handle_ppu_result(r);
         }
-
-        let r = self.ppu.tick();
-        self.handle_ppu_result(r);
-
-        let r = self.ppu.tick();
-        self.handle_ppu_result(r);
-
-        let r = self.ppu.tick();
-        self.handle_ppu_result(r);
     }
 
     pub fn irq(&self) -> bool {
@@ -180,11 +371,6 @@ impl Bus {
             PpuResult::Nmi => {
                 self.nmi.schedule(1);
             }
-            PpuResult::Scanline => {
-                if let Some(ref c) = self.cartridge {
-                    c.borrow_mut().signal_scanline();
-                }
-            }
             PpuResult::Draw => {
                 self.draw = true;
             }
@@ -192,11 +378,12 @@ impl Bus {
         }
     }
 
-    pub fn load_rom_from_memory(&mut self, data: &[u8]) {
-        let c = Rc::new(RefCell::new(Cartridge::new(data)));
+    pub fn load_rom_from_memory(&mut self, data: &[u8]) -> Result<(), RomError> {
+        let c = Rc::new(RefCell::new(Cartridge::new(data)?));
         self.ppu.registers.vram.set_cartridge(c.clone());
         self.apu.dmc.set_cartridge(c.clone());
         self.cartridge = Some(c);
+        Ok(())
     }
 
     pub fn reset(&mut self) {
@@ -205,7 +392,160 @@ impl Bus {
     }
 }
 
+impl SaveState for Interrupt {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.schedule.is_some());
+        state.write_u8(self.schedule.unwrap_or(0));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let scheduled = state.read_bool()?;
+        let n = state.read_u8()?;
+        self.schedule = if scheduled { Some(n) } else { None };
+        Ok(())
+    }
+}
+
+impl SaveState for Bus {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.ram);
+        state.write_u64(self.cycles);
+        self.rng.save_state(state);
+        self.nmi.save_state(state);
+        state.write_bool(self.draw);
+        state.write_usize(self.cpu_stall_cycles);
+        self.controller_0.save_state(state);
+        self.controller_1.save_state(state);
+        self.ppu.save_state(state);
+        self.apu.save_state(state);
+        if let Some(ref c) = self.cartridge {
+            c.borrow().save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes_into(&mut self.ram)?;
+        self.cycles = state.read_u64()?;
+        self.rng.load_state(state)?;
+        self.nmi.load_state(state)?;
+        self.draw = state.read_bool()?;
+        self.cpu_stall_cycles = state.read_usize()?;
+        self.controller_0.load_state(state)?;
+        self.controller_1.load_state(state)?;
+        self.ppu.load_state(state)?;
+        self.apu.load_state(state)?;
+        match self.cartridge {
+            Some(ref c) => c.borrow_mut().load_state(state),
+            None => Err(StateError::NoCartridge),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::cartridge::build_test_rom;
+
+    #[test]
+    fn test_load_bad_rom() {
+        let mut bus = Bus::new();
+        let mut data = build_test_rom(0, &[0; 0x8000], &[]);
+        data.truncate(16);
+        assert_eq!(
+            Err(RomError::TruncatedPrg {
+                expected: 0x8000,
+                actual: 0
+            }),
+            bus.load_rom_from_memory(&data)
+        );
+        assert!(bus.cartridge.is_none());
+    }
+
+    #[test]
+    fn test_banked_labels() {
+        // UxROM with four 16KB banks, each one filled with its own number
+        let prg: Vec<u8> = (0..4u8).flat_map(|bank| [bank; 0x4000]).collect();
+        let data = build_test_rom(0x20, &prg, &[]);
+        let mut bus = Bus::new();
+        bus.load_rom_from_memory(&data).unwrap();
+        bus.symbols.parse_nl("$8000#bank_0#", Some(0)).unwrap();
+        bus.symbols.parse_nl("$8000#bank_2#", Some(2)).unwrap();
+        bus.symbols.parse_nl("$C000#fixed#", Some(3)).unwrap();
+        bus.symbols.parse_nl("$0200#sprites#", None).unwrap();
+
+        assert_eq!(Some("bank_0".to_string()), bus.label(0x8000));
+        assert_eq!(Some("fixed".to_string()), bus.label(0xC000));
+        assert_eq!(Some("sprites".to_string()), bus.label(0x0200));
+        bus.write_byte(0x8000u16, 1);
+        assert_eq!(None, bus.label(0x8000));
+        bus.write_byte(0x8000u16, 2);
+        assert_eq!(Some("bank_2".to_string()), bus.label(0x8000));
+        assert_eq!(Some(0x8000), bus.prg_rom_offset(0x8000));
+    }
+
+    #[test]
+    fn test_peek_io_registers() {
+        let mut bus = Bus::new();
+        bus.controller_0.set_button_states(0b0000_0010);
+        bus.write_byte(0x4016u16, 1);
+        bus.write_byte(0x4016u16, 0);
+        assert_eq!(0x40, bus.peek(0x4016));
+        assert_eq!(0x40, bus.peek(0x4016));
+        assert_eq!(0x40, bus.unclocked_read_byte(0x4016));
+        assert_eq!(0x41, bus.peek(0x4016));
+
+        bus.ram[0x0123] = 0xAB;
+        assert_eq!(0xAB, bus.peek(0x0923));
+    }
+
+    #[test]
+    fn test_peek_cartridge_space() {
+        for mapper in [0u8, 1, 2, 3, 4, 5, 7] {
+            let mut bus = Bus::new();
+            let rom = build_test_rom(mapper << 4, &[0xEA; 0x8000], &[0; 0x2000]);
+            bus.load_rom_from_memory(&rom).unwrap();
+            for address in 0x4018..=0x7FFF {
+                bus.peek(address);
+            }
+        }
+
+        // Open bus where NROM has nothing mapped
+        let mut bus = Bus::new();
+        bus.load_rom_from_memory(&build_test_rom(0, &[0xEA; 0x4000], &[])).unwrap();
+        assert_eq!(0x50, bus.peek(0x5000));
+    }
+
+    #[test]
+    fn test_poke() {
+        let mut bus = Bus::new();
+        bus.load_rom_from_memory(&build_test_rom(0, &[0xEA; 0x4000], &[])).unwrap();
+        assert!(bus.poke(0x0810, 0x12));
+        assert_eq!(0x12, bus.ram[0x10]);
+        assert!(bus.poke(0x6000, 0x34));
+        assert_eq!(0x34, bus.peek(0x6000));
+        assert!(!bus.poke(0x8000, 0x56));
+        assert_eq!(0xEA, bus.peek(0x8000));
+        assert!(!bus.poke(0x2000, 0x80));
+        assert!(!bus.poke(0x4014, 0x02));
+
+        // UxROM has nothing at $6000 for the write to land in
+        bus.load_rom_from_memory(&build_test_rom(0x20, &[0xEA; 0x8000], &[])).unwrap();
+        bus.poke(0x6000, 0x34);
+    }
+
+    #[test]
+    fn test_cheats() {
+        let data = build_test_rom(0, &[0xEA; 0x4000], &[]);
+        let mut bus = Bus::new();
+        bus.load_rom_from_memory(&data).unwrap();
+        bus.cheats.parse("GOSSIP\n0075:09").unwrap();
+        assert_eq!(0x14, bus.unclocked_read_byte(0xD1DD));
+        assert_eq!(0x14, bus.peek(0xD1DD));
+        assert_eq!(0xEA, bus.unclocked_read_byte(0xD1DE));
+
+        bus.apply_ram_cheats();
+        assert_eq!(0x09, bus.ram[0x75]);
+        bus.cheats.set_active(false);
+        assert_eq!(0xEA, bus.unclocked_read_byte(0xD1DD));
+    }
 }
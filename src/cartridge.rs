@@ -1,35 +1,78 @@
 mod cartridge_data;
-mod cartridge_header;
+pub mod cartridge_header;
 mod mapper;
 mod mapper0;
 mod mapper1;
 mod mapper2;
 mod mapper3;
 mod mapper4;
+mod mapper5;
+mod mapper7;
 mod pager;
+mod rom_error;
 
 use self::cartridge_data::CartridgeData;
-use self::mapper::Mapper;
+use self::cartridge_header::CartridgeHeader;
+pub(crate) use self::mapper::Mapper;
 use self::mapper0::Mapper0;
 use self::mapper1::Mapper1;
 use self::mapper2::Mapper2;
 use self::mapper3::Mapper3;
 use self::mapper4::Mapper4;
+use self::mapper5::Mapper5;
+use self::mapper7::Mapper7;
+pub use self::rom_error::RomError;
+use crate::cdl::CodeDataLog;
+use crate::ppu::vram::mirror_nametable;
+use crate::savestate::{self, SaveState, StateError, StateReader, StateWriter};
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Mirroring {
     Vertical,
     Horizontal,
     None,
+    // Every nametable shows the first or the second 1KB of the PPU's RAM
+    SingleScreenA,
+    SingleScreenB,
+    // Four separate nametables, the cartridge supplies the RAM for two of them
+    FourScreen,
+}
+
+impl SaveState for Mirroring {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(*self as u8);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        *self = match state.read_u8()? {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::None,
+            3 => Mirroring::SingleScreenA,
+            4 => Mirroring::SingleScreenB,
+            5 => Mirroring::FourScreen,
+            _ => return Err(StateError::Invalid("bad mirroring mode")),
+        };
+        Ok(())
+    }
 }
 
 pub struct Cartridge {
+    header: CartridgeHeader,
+    rom_hash: u64,
     mapper: Box<dyn Mapper>,
+    // Only recorded while it is switched on, see start_code_data_log
+    pub cdl: Option<CodeDataLog>,
+    // The extra 2KB of nametables on four-screen boards
+    nametable_ram: Vec<u8>,
 }
 
 impl Cartridge {
-    pub fn new(data: &[u8]) -> Self {
-        let data = CartridgeData::new(data);
+    pub fn new(data: &[u8]) -> Result<Self, RomError> {
+        let rom_hash = savestate::hash(data);
+        let data = CartridgeData::new(data)?;
+        let header = data.header;
+        header.validate_banks()?;
 
         let mapper: Box<dyn Mapper> = match data.header.mapper_number {
             0 => Box::new(Mapper0::new(data)),
@@ -37,20 +80,44 @@ impl Cartridge {
             2 => Box::new(Mapper2::new(data)),
             3 => Box::new(Mapper3::new(data)),
             4 => Box::new(Mapper4::new(data)),
-            n => panic!("Mapper {} not implemented", n),
+            5 => Box::new(Mapper5::new(data)),
+            7 => Box::new(Mapper7::new(data)),
+            n => return Err(RomError::UnsupportedMapper(n)),
         };
 
-        Cartridge { mapper: mapper }
+        Ok(Cartridge {
+            header,
+            rom_hash,
+            mapper,
+            cdl: None,
+            nametable_ram: vec![0; if header.four_screen { 0x800 } else { 0 }],
+        })
+    }
+
+    // Swaps in a stand-in mapper, for tests of how the rest of the machine
+    // drives the cartridge
+    #[cfg(test)]
+    pub(crate) fn set_mapper(&mut self, mapper: Box<dyn Mapper>) {
+        self.mapper = mapper;
     }
 
-    pub fn signal_scanline(&mut self) {
-        self.mapper.signal_scanline();
+    pub fn header(&self) -> &CartridgeHeader {
+        &self.header
+    }
+
+    // Identifies the ROM image, so save states can't be loaded into another game
+    pub fn rom_hash(&self) -> u64 {
+        self.rom_hash
     }
 
     pub fn read_prg_byte(&self, address: u16) -> u8 {
         self.mapper.read_prg_byte(address)
     }
 
+    pub fn peek_prg_byte(&self, address: u16) -> u8 {
+        self.mapper.peek_prg_byte(address)
+    }
+
     pub fn write_prg_byte(&mut self, address: u16, value: u8) {
         self.mapper.write_prg_byte(address, value);
     }
@@ -63,13 +130,138 @@ impl Cartridge {
         self.mapper.write_chr_byte(address, value)
     }
 
+    // Four-screen boards wire the nametables up themselves, whatever the
+    // mapper's mirroring register says
     pub fn mirroring(&self) -> Mirroring {
-        self.mapper.mirroring()
+        if self.header.four_screen {
+            Mirroring::FourScreen
+        } else {
+            self.mapper.mirroring()
+        }
+    }
+
+    // `ciram` is the console's own 2KB of nametable RAM. Four-screen boards
+    // put their extra 2KB after it, otherwise the mapper decides.
+    pub fn read_nametable(&self, address: u16, ciram: &[u8]) -> u8 {
+        if !self.header.four_screen {
+            return self.mapper.read_nametable(address, ciram);
+        }
+        match mirror_nametable(Mirroring::FourScreen, address) {
+            i if i < ciram.len() => ciram[i],
+            i => self.nametable_ram[i - ciram.len()],
+        }
+    }
+
+    pub fn write_nametable(&mut self, address: u16, value: u8, ciram: &mut [u8]) {
+        if !self.header.four_screen {
+            return self.mapper.write_nametable(address, value, ciram);
+        }
+        match mirror_nametable(Mirroring::FourScreen, address) {
+            i if i < ciram.len() => ciram[i] = value,
+            i => self.nametable_ram[i - ciram.len()] = value,
+        }
+    }
+
+    pub fn ppu_fetch(&mut self, address: u16) {
+        self.mapper.ppu_address(address);
+        self.mapper.ppu_fetch(address);
+    }
+
+    pub fn ppu_address(&mut self, address: u16) {
+        self.mapper.ppu_address(address);
+    }
+
+    pub fn ppu_register_written(&mut self, address: u16, value: u8) {
+        self.mapper.ppu_register_written(address, value);
+    }
+
+    pub fn tick(&mut self) {
+        self.mapper.tick();
+    }
+
+    pub fn audio_output(&self) -> f64 {
+        self.mapper.audio_output()
     }
 
     pub fn irq_flag(&self) -> bool {
         self.mapper.irq_flag()
     }
+
+    pub fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        self.mapper.prg_rom_offset(address)
+    }
+
+    pub fn chr_rom_offset(&self, address: u16) -> Option<usize> {
+        self.mapper.chr_rom_offset(address)
+    }
+
+    pub fn start_code_data_log(&mut self) {
+        if self.cdl.is_none() {
+            let cdl = CodeDataLog::new(self.header.prg_rom_bytes(), self.header.chr_rom_bytes());
+            self.cdl = Some(cdl);
+        }
+    }
+
+    // Marks the PRG-ROM byte mapped at a CPU address with code/data log flags
+    pub fn log_prg(&mut self, address: u16, flags: u8) {
+        if let Some(ref mut cdl) = self.cdl {
+            if let Some(offset) = self.mapper.prg_rom_offset(address) {
+                cdl.log_prg(offset, address, flags);
+            }
+        }
+    }
+
+    pub fn log_chr(&mut self, address: u16, flags: u8) {
+        if let Some(ref mut cdl) = self.cdl {
+            if let Some(offset) = self.mapper.chr_rom_offset(address) {
+                cdl.log_chr(offset, flags);
+            }
+        }
+    }
+
+    pub fn prg_ram(&self) -> &[u8] {
+        self.mapper.prg_ram()
+    }
+
+    pub fn prg_ram_mut(&mut self) -> &mut [u8] {
+        self.mapper.prg_ram_mut()
+    }
+}
+
+impl SaveState for Cartridge {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.mapper.save_state(state);
+        state.write_bytes(&self.nametable_ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.mapper.load_state(state)?;
+        state.read_bytes_into(&mut self.nametable_ram)
+    }
+}
+
+// An iNES image for tests. flags6 holds the mirroring and the low nibble of
+// the mapper number, the header asks for one page of PRG-RAM, and no CHR-ROM
+// means CHR-RAM.
+#[cfg(test)]
+pub(crate) fn build_test_rom(flags6: u8, prg: &[u8], chr: &[u8]) -> Vec<u8> {
+    let mut data = vec![0x4e, 0x45, 0x53, 0x1a];
+    data.push((prg.len() / 0x4000) as u8);
+    data.push((chr.len() / 0x2000) as u8);
+    data.extend_from_slice(&[flags6, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    data.extend_from_slice(prg);
+    data.extend_from_slice(chr);
+    data
+}
+
+// 32kb of PRG-ROM that starts running `program` from $8000 on reset
+#[cfg(test)]
+pub(crate) fn build_test_prg(program: &[u8]) -> Vec<u8> {
+    let mut prg = vec![0u8; 0x8000];
+    prg[0..program.len()].copy_from_slice(program);
+    prg[0x7FFC] = 0x00;
+    prg[0x7FFD] = 0x80;
+    prg
 }
 
 #[cfg(test)]
@@ -109,7 +301,7 @@ mod ppu_test {
             }
         }
 
-        Cartridge::new(&data)
+        Cartridge::new(&data).unwrap()
     }
 
     #[test]
@@ -153,4 +345,33 @@ mod ppu_test {
             assert_eq!(cartridge.read_chr_byte(i), i as u8);
         }
     }
+
+    #[test]
+    fn test_partial_banks() {
+        // A NES 2.0 header can ask for 2^13 * 3 bytes of PRG-ROM, which UxROM can't bank
+        let mut data = build_test_rom(0x20, &[0; 0x6000], &[]);
+        data[4] = 0x35;
+        data[7] = 0x08; // NES 2.0
+        data[8] = 0x00;
+        data[9] = 0x0f;
+        assert_eq!(
+            Some(RomError::InconsistentSize(
+                "PRG-ROM is not a whole number of the mapper's banks"
+            )),
+            Cartridge::new(&data).err()
+        );
+
+        // MMC3 banks 8 KB at a time
+        data[6] = 0x40;
+        assert!(Cartridge::new(&data).is_ok());
+    }
+
+    #[test]
+    fn test_unsupported_mapper() {
+        let data = build_test_rom(0xf0, &[0; 0x4000], &[]);
+        assert_eq!(
+            Some(RomError::UnsupportedMapper(0x0f)),
+            Cartridge::new(&data).err()
+        );
+    }
 }
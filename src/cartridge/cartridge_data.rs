@@ -1,5 +1,7 @@
 use super::cartridge_header::CartridgeHeader;
 use super::pager::Pager;
+use super::RomError;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 pub struct CartridgeData {
     pub header: CartridgeHeader,
@@ -10,15 +12,28 @@ pub struct CartridgeData {
 }
 
 impl CartridgeData {
-    pub fn new(data: &[u8]) -> Self {
-        let header = CartridgeHeader::new(data);
+    pub fn new(data: &[u8]) -> Result<Self, RomError> {
+        let header = CartridgeHeader::new(data)?;
+        header.validate_length(data.len())?;
 
-        CartridgeData {
+        Ok(CartridgeData {
             header: header,
             prg_rom: Pager::new(data[header.prg_rom_range()].to_vec()),
             chr_rom: Pager::new(data[header.chr_rom_range()].to_vec()),
             prg_ram: Pager::new(vec![0u8; header.prg_ram_bytes()]),
             chr_ram: Pager::new(vec![0u8; header.chr_ram_bytes()]),
-        }
+        })
+    }
+}
+
+impl SaveState for CartridgeData {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.prg_ram.data);
+        state.write_bytes(&self.chr_ram.data);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes_into(&mut self.prg_ram.data)?;
+        state.read_bytes_into(&mut self.chr_ram.data)
     }
 }
@@ -1,39 +1,206 @@
 use super::Mirroring;
+use super::RomError;
 use std::ops::Range;
 
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 0x200;
 const PRG_ROM_PAGE_SIZE: usize = 0x4000;
 const PRG_RAM_PAGE_SIZE: usize = 0x2000;
 const CHR_ROM_PAGE_SIZE: usize = 0x2000;
 const CHR_RAM_PAGE_SIZE: usize = 0x2000;
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem,
+    Playchoice10,
+    Extended(u8),
+}
+
+// Header formats are described at
+// https://wiki.nesdev.com/w/index.php/INES and https://wiki.nesdev.com/w/index.php/NES_2.0
 #[derive(Copy, Clone)]
 pub struct CartridgeHeader {
-    pub mapper_number: u8,
+    pub nes2: bool,
+    pub mapper_number: u16,
+    pub submapper_number: u8,
     pub mirroring: Mirroring,
-    pub prg_rom_pages: usize,
-    pub prg_ram_pages: usize,
-    pub chr_rom_pages: usize,
-    pub preamble: bool,
+    pub four_screen: bool,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub trainer: bool,
+    pub battery: bool,
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+    pub expansion_device: u8,
 }
 
 impl CartridgeHeader {
-    pub fn new(data: &[u8]) -> Self {
+    pub fn new(data: &[u8]) -> Result<Self, RomError> {
+        if data.len() < HEADER_SIZE {
+            return Err(RomError::TruncatedHeader(data.len()));
+        }
+        if data[0..4] != [0x4e, 0x45, 0x53, 0x1a] {
+            return Err(RomError::BadMagic([data[0], data[1], data[2], data[3]]));
+        }
+
+        let header = if data[7] & 0b1100 == 0b1000 {
+            Self::new_nes2(data)?
+        } else {
+            Self::new_ines(data)
+        };
+
+        if header.prg_rom_size == 0 {
+            return Err(RomError::InconsistentSize("header declares no PRG-ROM"));
+        }
+        // Banks are at least 8 KB for PRG and 1 KB for CHR. Anything smaller
+        // than a bank gets mirrored, anything larger has to be whole banks.
+        if header.prg_rom_size > 0x2000 && header.prg_rom_size % 0x2000 != 0 {
+            return Err(RomError::InconsistentSize(
+                "PRG-ROM is not a whole number of 8 KB banks",
+            ));
+        }
+        if header.chr_rom_size > 0x400 && header.chr_rom_size % 0x400 != 0 {
+            return Err(RomError::InconsistentSize(
+                "CHR-ROM is not a whole number of 1 KB banks",
+            ));
+        }
+
+        Ok(header)
+    }
+
+    // The mappers can only switch whole banks, so ROM larger than a bank has
+    // to be a whole number of them. Smaller ROM gets mirrored.
+    pub fn validate_banks(&self) -> Result<(), RomError> {
+        let (prg_bank, chr_bank) = match self.mapper_number {
+            0 | 2 | 3 => (0x4000, 0x2000),
+            1 => (0x4000, 0x1000),
+            4 => (0x2000, 0x400),
+            5 => (0x2000, 0x2000),
+            7 => (0x8000, 0x2000),
+            n => return Err(RomError::UnsupportedMapper(n)),
+        };
+
+        if self.prg_rom_size > prg_bank && !self.prg_rom_size.is_multiple_of(prg_bank) {
+            return Err(RomError::InconsistentSize(
+                "PRG-ROM is not a whole number of the mapper's banks",
+            ));
+        }
+        if self.chr_rom_size > chr_bank && !self.chr_rom_size.is_multiple_of(chr_bank) {
+            return Err(RomError::InconsistentSize(
+                "CHR-ROM is not a whole number of the mapper's banks",
+            ));
+        }
+        Ok(())
+    }
+
+    fn new_ines(data: &[u8]) -> Self {
+        // Some old dumps have garbage such as "DiskDude!" in bytes 7-15,
+        // in which case the upper nibble of the mapper number can't be trusted.
+        let mapper_high = if data[12..16].iter().all(|&b| b == 0) {
+            data[7] & 0xf0
+        } else {
+            0
+        };
+        let chr_rom_size = data[5] as usize * CHR_ROM_PAGE_SIZE;
+
         CartridgeHeader {
-            preamble: data[0..4] == [0x4e, 0x45, 0x53, 0x1a],
-            mirroring: if data[6] & 1 == 0 {
-                Mirroring::Horizontal
+            nes2: false,
+            mapper_number: ((data[6] >> 4) | mapper_high) as u16,
+            submapper_number: 0,
+            mirroring: mirroring(data[6]),
+            four_screen: data[6] & 0b1000 != 0,
+            trainer: data[6] & 0b100 != 0,
+            battery: data[6] & 0b10 != 0,
+            prg_rom_size: data[4] as usize * PRG_ROM_PAGE_SIZE,
+            chr_rom_size,
+            prg_ram_size: if data[8] == 0 { 1 } else { data[8] } as usize * PRG_RAM_PAGE_SIZE,
+            prg_nvram_size: 0,
+            chr_ram_size: if chr_rom_size == 0 {
+                CHR_RAM_PAGE_SIZE
             } else {
-                Mirroring::Vertical
+                0
+            },
+            chr_nvram_size: 0,
+            timing: Timing::Ntsc,
+            console_type: ConsoleType::Nes,
+            expansion_device: 0,
+        }
+    }
+
+    fn new_nes2(data: &[u8]) -> Result<Self, RomError> {
+        Ok(CartridgeHeader {
+            nes2: true,
+            mapper_number: (data[6] >> 4) as u16
+                | (data[7] & 0xf0) as u16
+                | ((data[8] & 0x0f) as u16) << 8,
+            submapper_number: data[8] >> 4,
+            mirroring: mirroring(data[6]),
+            four_screen: data[6] & 0b1000 != 0,
+            trainer: data[6] & 0b100 != 0,
+            battery: data[6] & 0b10 != 0,
+            prg_rom_size: nes2_rom_size(data[4], data[9] & 0x0f, PRG_ROM_PAGE_SIZE)?,
+            chr_rom_size: nes2_rom_size(data[5], data[9] >> 4, CHR_ROM_PAGE_SIZE)?,
+            prg_ram_size: nes2_ram_size(data[10] & 0x0f),
+            prg_nvram_size: nes2_ram_size(data[10] >> 4),
+            chr_ram_size: nes2_ram_size(data[11] & 0x0f),
+            chr_nvram_size: nes2_ram_size(data[11] >> 4),
+            timing: match data[12] & 0b11 {
+                0 => Timing::Ntsc,
+                1 => Timing::Pal,
+                2 => Timing::MultiRegion,
+                _ => Timing::Dendy,
+            },
+            console_type: match data[7] & 0b11 {
+                0 => ConsoleType::Nes,
+                1 => ConsoleType::VsSystem,
+                2 => ConsoleType::Playchoice10,
+                _ => ConsoleType::Extended(data[13] & 0x0f),
             },
-            prg_rom_pages: data[4] as usize,
-            chr_rom_pages: data[5] as usize,
-            prg_ram_pages: if data[8] == 0 { 1 } else { data[8] } as usize,
-            mapper_number: (data[6] >> 4) | (data[7] & 0xf0),
+            expansion_device: data[15] & 0b0011_1111,
+        })
+    }
+
+    // Checks that the file is large enough to hold everything the header declares
+    pub fn validate_length(&self, length: usize) -> Result<(), RomError> {
+        let prg_range = self.prg_rom_range();
+        if length < prg_range.start {
+            return Err(RomError::TruncatedTrainer);
+        }
+        if length < prg_range.end {
+            return Err(RomError::TruncatedPrg {
+                expected: self.prg_rom_bytes(),
+                actual: length - prg_range.start,
+            });
+        }
+        if length < self.chr_rom_range().end {
+            return Err(RomError::TruncatedChr {
+                expected: self.chr_rom_bytes(),
+                actual: length - prg_range.end,
+            });
         }
+        Ok(())
     }
 
     pub fn prg_rom_range(&self) -> Range<usize> {
-        16..16 + self.prg_rom_bytes()
+        let start = if self.trainer {
+            HEADER_SIZE + TRAINER_SIZE
+        } else {
+            HEADER_SIZE
+        };
+        start..start + self.prg_rom_bytes()
     }
 
     pub fn chr_rom_range(&self) -> Range<usize> {
@@ -42,23 +209,55 @@ impl CartridgeHeader {
     }
 
     pub fn prg_rom_bytes(&self) -> usize {
-        self.prg_rom_pages * PRG_ROM_PAGE_SIZE
+        self.prg_rom_size
     }
 
+    // Volatile and battery-backed PRG-RAM share the $6000-$7FFF window
     pub fn prg_ram_bytes(&self) -> usize {
-        self.prg_ram_pages * PRG_RAM_PAGE_SIZE
+        self.prg_ram_size + self.prg_nvram_size
     }
 
     pub fn chr_rom_bytes(&self) -> usize {
-        self.chr_rom_pages * CHR_ROM_PAGE_SIZE
+        self.chr_rom_size
     }
 
     pub fn chr_ram_bytes(&self) -> usize {
-        if self.chr_rom_pages == 0 {
-            CHR_RAM_PAGE_SIZE
-        } else {
-            0
-        }
+        self.chr_ram_size + self.chr_nvram_size
+    }
+}
+
+fn mirroring(flags: u8) -> Mirroring {
+    if flags & 0b1000 != 0 {
+        Mirroring::FourScreen
+    } else if flags & 1 == 0 {
+        Mirroring::Horizontal
+    } else {
+        Mirroring::Vertical
+    }
+}
+
+// ROM sizes are either a 12 bit count of pages, or when the high nibble
+// is $F, an exponent-multiplier pair: 2^EEEEEE * (MM * 2 + 1) bytes.
+fn nes2_rom_size(low: u8, high: u8, page_size: usize) -> Result<usize, RomError> {
+    if high == 0x0f {
+        let exponent = (low >> 2) as u32;
+        let multiplier = (low & 0b11) as usize * 2 + 1;
+        1usize
+            .checked_shl(exponent)
+            .and_then(|n| n.checked_mul(multiplier))
+            .filter(|&n| n <= 1 << 30)
+            .ok_or(RomError::InconsistentSize("ROM size is too large"))
+    } else {
+        Ok((((high as usize) << 8) | low as usize) * page_size)
+    }
+}
+
+// RAM sizes are stored as a shift count: 64 << n bytes, or 0 for none
+fn nes2_ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
     }
 }
 
@@ -73,14 +272,13 @@ mod test {
 
     #[test]
     fn test_sizes() {
-        let header = CartridgeHeader::new(&HEADER);
-        assert!(header.preamble);
+        let header = CartridgeHeader::new(&HEADER).unwrap();
+        assert!(!header.nes2);
+        assert!(!header.battery);
         assert_eq!(Mirroring::Vertical, header.mirroring);
-        assert_eq!(0x10, header.prg_rom_pages);
         assert_eq!(0x10 * PRG_ROM_PAGE_SIZE, header.prg_rom_bytes());
         assert_eq!(16..16 + 0x10 * PRG_ROM_PAGE_SIZE, header.prg_rom_range());
 
-        assert_eq!(0x12, header.chr_rom_pages);
         assert_eq!(0x12 * CHR_ROM_PAGE_SIZE, header.chr_rom_bytes());
         assert_eq!(
             16 + 0x10 * PRG_ROM_PAGE_SIZE
@@ -88,9 +286,122 @@ mod test {
             header.chr_rom_range()
         );
 
-        assert_eq!(0x13, header.prg_ram_pages);
         assert_eq!(0x13 * PRG_RAM_PAGE_SIZE, header.prg_ram_bytes());
+        assert_eq!(0, header.chr_ram_bytes());
 
         assert_eq!(0x01, header.mapper_number);
     }
+
+    #[test]
+    fn test_four_screen() {
+        let mut data = HEADER;
+        data[6] |= 0b1000;
+        let header = CartridgeHeader::new(&data).unwrap();
+        assert!(header.four_screen);
+        assert_eq!(Mirroring::FourScreen, header.mirroring);
+    }
+
+    #[test]
+    fn test_trainer() {
+        let mut data = HEADER;
+        data[6] |= 0b100;
+        let header = CartridgeHeader::new(&data).unwrap();
+        assert_eq!(16 + 0x200..16 + 0x200 + 0x10 * PRG_ROM_PAGE_SIZE, header.prg_rom_range());
+    }
+
+    #[test]
+    fn test_bad_magic() {
+        let mut data = HEADER;
+        data[3] = 0;
+        assert_eq!(
+            Err(RomError::BadMagic([0x4e, 0x45, 0x53, 0x00])),
+            CartridgeHeader::new(&data).map(|_| ())
+        );
+        assert_eq!(
+            Err(RomError::TruncatedHeader(4)),
+            CartridgeHeader::new(&HEADER[0..4]).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_validate_length() {
+        let header = CartridgeHeader::new(&HEADER).unwrap();
+        let prg_end = 16 + 0x10 * PRG_ROM_PAGE_SIZE;
+        assert_eq!(
+            Err(RomError::TruncatedPrg {
+                expected: 0x10 * PRG_ROM_PAGE_SIZE,
+                actual: 100
+            }),
+            header.validate_length(16 + 100)
+        );
+        assert_eq!(
+            Err(RomError::TruncatedChr {
+                expected: 0x12 * CHR_ROM_PAGE_SIZE,
+                actual: 5
+            }),
+            header.validate_length(prg_end + 5)
+        );
+        assert_eq!(
+            Ok(()),
+            header.validate_length(prg_end + 0x12 * CHR_ROM_PAGE_SIZE)
+        );
+    }
+
+    #[test]
+    fn test_ines_garbage_tail() {
+        let mut data = HEADER;
+        data[7] = 0x40;
+        assert_eq!(0x41, CartridgeHeader::new(&data).unwrap().mapper_number);
+        data[12..16].copy_from_slice(b"Dude");
+        assert_eq!(0x01, CartridgeHeader::new(&data).unwrap().mapper_number);
+    }
+
+    #[test]
+    fn test_nes2() {
+        let data = [
+            0x4e, 0x45, 0x53, 0x1a, 0x10, 0x12, 0x12, // Battery bit set
+            0x48, // NES 2.0, mapper high nibble 4
+            0x31, // Submapper 3, mapper bits 8-11 are 1
+            0x21, // PRG-ROM MSB 1, CHR-ROM MSB 2
+            0x70, // 8 KB of battery-backed PRG-RAM
+            0x07, // 8 KB of CHR-RAM
+            0x01, // PAL
+            0x00, 0x00, 0x0a,
+        ];
+        let header = CartridgeHeader::new(&data).unwrap();
+        assert!(header.nes2);
+        assert!(header.battery);
+        assert_eq!(0x141, header.mapper_number);
+        assert_eq!(3, header.submapper_number);
+        assert_eq!(0x110 * PRG_ROM_PAGE_SIZE, header.prg_rom_bytes());
+        assert_eq!(0x212 * CHR_ROM_PAGE_SIZE, header.chr_rom_bytes());
+        assert_eq!(0, header.prg_ram_size);
+        assert_eq!(0x2000, header.prg_nvram_size);
+        assert_eq!(0x2000, header.prg_ram_bytes());
+        assert_eq!(0x2000, header.chr_ram_bytes());
+        assert_eq!(Timing::Pal, header.timing);
+        assert_eq!(ConsoleType::Nes, header.console_type);
+        assert_eq!(0x0a, header.expansion_device);
+    }
+
+    #[test]
+    fn test_nes2_exponent_sizes() {
+        let mut data = [0u8; 16];
+        data[0..4].copy_from_slice(&[0x4e, 0x45, 0x53, 0x1a]);
+        data[4] = 0x41; // 2^16 * 3 bytes
+        data[5] = 0x28; // 2^10 * 1 bytes
+        data[7] = 0x0b; // NES 2.0 on an extended console
+        data[9] = 0xff;
+        data[13] = 0x03;
+        let header = CartridgeHeader::new(&data).unwrap();
+        assert_eq!(0x10000 * 3, header.prg_rom_bytes());
+        assert_eq!(0x400, header.chr_rom_bytes());
+        assert_eq!(ConsoleType::Extended(3), header.console_type);
+
+        data[4] = 0xfc;
+        assert_eq!(
+            Some(RomError::InconsistentSize("ROM size is too large")),
+            CartridgeHeader::new(&data).err()
+        );
+    }
 }
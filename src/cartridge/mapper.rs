@@ -1,15 +1,50 @@
 use super::Mirroring;
+use crate::ppu::vram::mirror_nametable;
+use crate::savestate::SaveState;
 
-pub trait Mapper {
-    fn signal_scanline(&mut self) {
-        // A blank placeholder is fine for most mappers
-    }
+// Mappers save their banking registers and cartridge RAM through SaveState
+pub trait Mapper: SaveState {
     fn read_prg_byte(&self, address: u16) -> u8;
     fn write_prg_byte(&mut self, address: u16, value: u8);
+    // A read without side effects, for mappers whose registers change when read
+    fn peek_prg_byte(&self, address: u16) -> u8 {
+        self.read_prg_byte(address)
+    }
     fn read_chr_byte(&self, address: u16) -> u8;
     fn write_chr_byte(&mut self, address: u16, value: u8);
     fn mirroring(&self) -> Mirroring;
     fn irq_flag(&self) -> bool {
         false
     }
+    // The PRG-ROM byte currently mapped at a CPU address, as an offset into
+    // the ROM, so debugging tools can tell banks apart
+    fn prg_rom_offset(&self, address: u16) -> Option<usize>;
+    // The same for PPU addresses and CHR-ROM, None when the cart uses CHR-RAM
+    fn chr_rom_offset(&self, address: u16) -> Option<usize>;
+    fn prg_ram(&self) -> &[u8];
+    fn prg_ram_mut(&mut self) -> &mut [u8];
+    // Nametable reads and writes, $2000-$3EFF. `ciram` is the console's own
+    // 2KB of nametable RAM, which most mappers lay out by mirroring()
+    fn read_nametable(&self, address: u16, ciram: &[u8]) -> u8 {
+        ciram[mirror_nametable(self.mirroring(), address) % ciram.len()]
+    }
+    fn write_nametable(&mut self, address: u16, value: u8, ciram: &mut [u8]) {
+        ciram[mirror_nametable(self.mirroring(), address) % ciram.len()] = value;
+    }
+    // Called with each address the PPU reads while rendering, just before
+    // the read, for mappers that switch banks by what the PPU is fetching
+    fn ppu_fetch(&mut self, _address: u16) {}
+    // Called with each address the PPU puts on its bus: every fetch while
+    // rendering, and otherwise the VRAM address the CPU moves through $2006
+    // and $2007. Mappers that watch A12 want this rather than ppu_fetch.
+    fn ppu_address(&mut self, _address: u16) {}
+    // CPU writes to the PPU's registers, which some mappers listen in on
+    fn ppu_register_written(&mut self, _address: u16, _value: u8) {}
+    // Called once every CPU cycle
+    fn tick(&mut self) {}
+    // The level of the cartridge's own sound channels, on the scale of the
+    // APU's mixer output
+    fn audio_output(&self) -> f64 {
+        0.0
+    }
 }
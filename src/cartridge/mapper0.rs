@@ -6,6 +6,7 @@ use super::pager::PageSize;
 use super::CartridgeData;
 use super::Mapper;
 use super::Mirroring;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 pub struct Mapper0 {
     data: CartridgeData,
@@ -15,6 +16,13 @@ impl Mapper0 {
     pub fn new(data: CartridgeData) -> Self {
         Mapper0 { data: data }
     }
+
+    fn prg_rom_page(&self, address: u16) -> (Page, u16) {
+        match address {
+            0x8000..=0xBFFF => (Page::First(PageSize::SixteenKb), address - 0x8000),
+            _ => (Page::Last(PageSize::SixteenKb), address - 0xC000),
+        }
+    }
 }
 
 impl Mapper for Mapper0 {
@@ -24,15 +32,12 @@ impl Mapper for Mapper0 {
                 .data
                 .prg_ram
                 .read(Page::First(PageSize::EightKb), address - 0x6000),
-            0x8000..=0xBFFF => self
-                .data
-                .prg_rom
-                .read(Page::First(PageSize::SixteenKb), address - 0x8000),
-            0xC000..=0xFFFF => self
-                .data
-                .prg_rom
-                .read(Page::Last(PageSize::SixteenKb), address - 0xC000),
-            a => panic!("bad address: {:04X}", a),
+            0x8000..=0xFFFF => {
+                let (page, offset) = self.prg_rom_page(address);
+                self.data.prg_rom.read(page, offset)
+            }
+            // Nothing drives the bus there
+            a => (a >> 8) as u8,
         }
     }
 
@@ -43,12 +48,13 @@ impl Mapper for Mapper0 {
                     .prg_ram
                     .write(Page::First(PageSize::EightKb), address - 0x6000, value)
             }
-            _ => panic!("bad address"),
+            // Writes to ROM or to nothing at all are ignored
+            _ => (),
         }
     }
 
     fn read_chr_byte(&self, address: u16) -> u8 {
-        if self.data.header.chr_rom_pages == 0 {
+        if self.data.header.chr_rom_bytes() == 0 {
             self.data
                 .chr_ram
                 .read(Page::First(PageSize::EightKb), address)
@@ -60,14 +66,48 @@ impl Mapper for Mapper0 {
     }
 
     fn write_chr_byte(&mut self, address: u16, value: u8) {
-        if self.data.header.chr_rom_pages == 0 {
+        if self.data.header.chr_rom_bytes() == 0 {
             self.data
                 .chr_ram
                 .write(Page::First(PageSize::EightKb), address, value)
         }
     }
 
+    fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000..=0xFFFF => {
+                let (page, offset) = self.prg_rom_page(address);
+                self.data.prg_rom.offset(page, offset)
+            }
+            _ => None,
+        }
+    }
+
+    fn chr_rom_offset(&self, address: u16) -> Option<usize> {
+        self.data
+            .chr_rom
+            .offset(Page::First(PageSize::EightKb), address)
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.data.prg_ram.data
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.data.prg_ram.data
+    }
+
     fn mirroring(&self) -> Mirroring {
         self.data.header.mirroring
     }
 }
+
+impl SaveState for Mapper0 {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.data.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.data.load_state(state)
+    }
+}
@@ -6,6 +6,7 @@ use super::pager::PageSize;
 use super::CartridgeData;
 use super::Mapper;
 use super::Mirroring;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 enum AddressRange {
@@ -38,9 +39,11 @@ bitfield! {
 impl ControlRegister {
     fn mirroring(&self) -> Mirroring {
         match self.nt_mode_id() {
+            0 => Mirroring::SingleScreenA,
+            1 => Mirroring::SingleScreenB,
             2 => Mirroring::Vertical,
             3 => Mirroring::Horizontal,
-            _ => panic!("Unsupported Mirroring"), // TODO: Implement additional modes
+            _ => unreachable!(),
         }
     }
 
@@ -141,7 +144,12 @@ impl Mapper1 {
     }
 
     fn write_paged_chr_ram(&mut self, address_range: AddressRange, offset: u16, value: u8) {
-        let page = match self.control.chr_mode() {
+        let page = self.chr_page(address_range);
+        self.data.chr_ram.write(page, offset, value)
+    }
+
+    fn chr_page(&self, address_range: AddressRange) -> Page {
+        match self.control.chr_mode() {
             ChrMode::Consecutive => match address_range {
                 AddressRange::Low => Page::Number(self.chr_0, PageSize::FourKb),
                 AddressRange::High => Page::Number(self.chr_0 + 1, PageSize::FourKb),
@@ -150,12 +158,11 @@ impl Mapper1 {
                 AddressRange::Low => Page::Number(self.chr_0, PageSize::FourKb), // TODO !? Low bit??
                 AddressRange::High => Page::Number(self.chr_1, PageSize::FourKb),
             },
-        };
-        self.data.chr_ram.write(page, offset, value)
+        }
     }
 
-    fn read_paged_prg_rom(&self, address_range: AddressRange, offset: u16) -> u8 {
-        let page = match self.control.prg_mode() {
+    fn prg_rom_page(&self, address_range: AddressRange) -> Page {
+        match self.control.prg_mode() {
             PrgMode::FixFirst => match address_range {
                 AddressRange::Low => Page::First(PageSize::SixteenKb),
                 AddressRange::High => Page::Number(self.prg_0, PageSize::SixteenKb),
@@ -168,23 +175,17 @@ impl Mapper1 {
                 AddressRange::Low => Page::Number(self.prg_0 & !1, PageSize::SixteenKb),
                 AddressRange::High => Page::Number(self.prg_0 | 1, PageSize::SixteenKb),
             },
-        };
-        self.data.prg_rom.read(page, offset)
+        }
+    }
+
+    fn read_paged_prg_rom(&self, address_range: AddressRange, offset: u16) -> u8 {
+        self.data.prg_rom.read(self.prg_rom_page(address_range), offset)
     }
 
     fn read_paged_chr_rom(&self, address_range: AddressRange, offset: u16) -> u8 {
-        let page = match self.control.chr_mode() {
-            ChrMode::Consecutive => match address_range {
-                AddressRange::Low => Page::Number(self.chr_0, PageSize::FourKb),
-                AddressRange::High => Page::Number(self.chr_0 + 1, PageSize::FourKb),
-            },
-            ChrMode::NonConsecutive => match address_range {
-                AddressRange::Low => Page::Number(self.chr_0, PageSize::FourKb), // TODO !? Low bit??
-                AddressRange::High => Page::Number(self.chr_1, PageSize::FourKb),
-            },
-        };
+        let page = self.chr_page(address_range);
 
-        if self.data.header.chr_rom_pages == 0 {
+        if self.data.header.chr_rom_bytes() == 0 {
             self.data.chr_ram.read(page, offset)
         } else {
             self.data.chr_rom.read(page, offset)
@@ -198,7 +199,8 @@ impl Mapper for Mapper1 {
             0x6000..=0x7FFF => self.read_paged_prg_ram(address - 0x6000),
             0x8000..=0xBFFF => self.read_paged_prg_rom(AddressRange::Low, address - 0x8000),
             0xC000..=0xFFFF => self.read_paged_prg_rom(AddressRange::High, address - 0xC000),
-            _ => panic!("bad address"),
+            // Nothing drives the bus there
+            a => (a >> 8) as u8,
         }
     }
 
@@ -206,7 +208,7 @@ impl Mapper for Mapper1 {
         match address {
             0x6000..=0x7FFF => self.write_paged_prg_ram(address - 0x6000, value),
             0x8000..=0xFFFF => self.write_shift(address, value),
-            _ => panic!("bad address"),
+            _ => (),
         }
     }
 
@@ -228,12 +230,74 @@ impl Mapper for Mapper1 {
         }
     }
 
+    fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        let (page, offset) = match address {
+            0x8000..=0xBFFF => (self.prg_rom_page(AddressRange::Low), address - 0x8000),
+            0xC000..=0xFFFF => (self.prg_rom_page(AddressRange::High), address - 0xC000),
+            _ => return None,
+        };
+        self.data.prg_rom.offset(page, offset)
+    }
+
+    fn chr_rom_offset(&self, address: u16) -> Option<usize> {
+        let (page, offset) = match address {
+            0x0000..=0x0FFF => (self.chr_page(AddressRange::Low), address),
+            0x1000..=0x1FFF => (self.chr_page(AddressRange::High), address - 0x1000),
+            _ => return None,
+        };
+        self.data.chr_rom.offset(page, offset)
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.data.prg_ram.data
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.data.prg_ram.data
+    }
+
     fn mirroring(&self) -> Mirroring {
         // Todo - what about the mirroring mode from the ines file header?
         self.control.mirroring()
     }
 }
 
+impl SaveState for Mapper1 {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.data.save_state(state);
+        state.write_u8(self.shift.value);
+        state.write_u8(self.shift.bit_index);
+        state.write_u8(self.control.0);
+        state.write_usize(self.prg_0);
+        state.write_usize(self.chr_0);
+        state.write_usize(self.chr_1);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.data.load_state(state)?;
+        self.shift.value = state.read_u8()?;
+        self.shift.bit_index = state.read_u8()?;
+        self.control = ControlRegister(state.read_u8()?);
+        self.prg_0 = state.read_usize()?;
+        self.chr_0 = state.read_usize()?;
+        self.chr_1 = state.read_usize()?;
+
+        let ranges = [AddressRange::Low, AddressRange::High];
+        if !ranges.iter().all(|r| self.data.prg_rom.contains(self.prg_rom_page(*r))) {
+            return Err(StateError::Invalid("MMC1 PRG bank out of range"));
+        }
+        let chr = if self.data.header.chr_rom_bytes() == 0 {
+            &self.data.chr_ram
+        } else {
+            &self.data.chr_rom
+        };
+        if !ranges.iter().all(|r| chr.contains(self.chr_page(*r))) {
+            return Err(StateError::Invalid("MMC1 CHR bank out of range"));
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -279,7 +343,7 @@ mod test {
             data.push(i as u8);
         }
 
-        CartridgeData::new(&data)
+        CartridgeData::new(&data).unwrap()
     }
 
     fn configure_mapper(mapper: &mut Mapper1, address: u16, value: u8) {
@@ -303,6 +367,11 @@ mod test {
         assert_eq!(mapper.control.mirroring(), Mirroring::Vertical);
         assert_eq!(mapper.control.prg_mode(), PrgMode::Consecutive);
         assert_eq!(mapper.control.chr_mode(), ChrMode::NonConsecutive);
+
+        configure_mapper(&mut mapper, 0x8000, 0b00000);
+        assert_eq!(mapper.control.mirroring(), Mirroring::SingleScreenA);
+        configure_mapper(&mut mapper, 0x8000, 0b00001);
+        assert_eq!(mapper.control.mirroring(), Mirroring::SingleScreenB);
     }
 
     #[test]
@@ -375,4 +444,24 @@ mod test {
         mapper.data.chr_rom.data[PageSize::FourKb as usize * 5 + 9] = 0xFD;
         assert_eq!(mapper.read_chr_byte(0x1009), 0xFD);
     }
+
+    #[test]
+    fn test_load_state_checks_banks() {
+        let mut mapper = Mapper1::new(build_cartridge_data());
+        configure_mapper(&mut mapper, 0xE000, 14);
+        let mut state = StateWriter::new();
+        mapper.save_state(&mut state);
+        let mut loaded = Mapper1::new(build_cartridge_data());
+        loaded.load_state(&mut StateReader::new(&state.data)).unwrap();
+        assert_eq!(loaded.prg_0, 14);
+
+        // Only 15 PRG banks, so bank 15 would panic on the next read
+        mapper.prg_0 = 15;
+        let mut state = StateWriter::new();
+        mapper.save_state(&mut state);
+        assert!(matches!(
+            loaded.load_state(&mut StateReader::new(&state.data)),
+            Err(StateError::Invalid(_))
+        ));
+    }
 }
@@ -6,6 +6,7 @@ use super::pager::PageSize;
 use super::CartridgeData;
 use super::Mapper;
 use super::Mirroring;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 pub struct Mapper2 {
     data: CartridgeData,
@@ -19,20 +20,24 @@ impl Mapper2 {
             prg_0: 0,
         }
     }
+
+    fn prg_rom_page(&self, address: u16) -> (Page, u16) {
+        match address {
+            0x8000..=0xBFFF => (Page::Number(self.prg_0, PageSize::SixteenKb), address - 0x8000),
+            _ => (Page::Last(PageSize::SixteenKb), address - 0xC000),
+        }
+    }
 }
 
 impl Mapper for Mapper2 {
     fn read_prg_byte(&self, address: u16) -> u8 {
         match address {
-            0x8000..=0xBFFF => self.data.prg_rom.read(
-                Page::Number(self.prg_0, PageSize::SixteenKb),
-                address - 0x8000,
-            ),
-            0xC000..=0xFFFF => self
-                .data
-                .prg_rom
-                .read(Page::Last(PageSize::SixteenKb), address - 0xC000),
-            a => panic!("bad address: {:04X}", a),
+            0x8000..=0xFFFF => {
+                let (page, offset) = self.prg_rom_page(address);
+                self.data.prg_rom.read(page, offset)
+            }
+            // Nothing drives the bus there
+            a => (a >> 8) as u8,
         }
     }
 
@@ -41,12 +46,12 @@ impl Mapper for Mapper2 {
             0x8000..=0xFFFF => {
                 self.prg_0 = value as usize & 0x0F;
             }
-            _ => panic!("bad address"),
+            _ => (),
         }
     }
 
     fn read_chr_byte(&self, address: u16) -> u8 {
-        if self.data.header.chr_rom_pages == 0 {
+        if self.data.header.chr_rom_bytes() == 0 {
             self.data
                 .chr_ram
                 .read(Page::First(PageSize::EightKb), address)
@@ -58,14 +63,54 @@ impl Mapper for Mapper2 {
     }
 
     fn write_chr_byte(&mut self, address: u16, value: u8) {
-        if self.data.header.chr_rom_pages == 0 {
+        if self.data.header.chr_rom_bytes() == 0 {
             self.data
                 .chr_ram
                 .write(Page::First(PageSize::EightKb), address, value)
         }
     }
 
+    fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000..=0xFFFF => {
+                let (page, offset) = self.prg_rom_page(address);
+                self.data.prg_rom.offset(page, offset)
+            }
+            _ => None,
+        }
+    }
+
+    fn chr_rom_offset(&self, address: u16) -> Option<usize> {
+        self.data
+            .chr_rom
+            .offset(Page::First(PageSize::EightKb), address)
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.data.prg_ram.data
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.data.prg_ram.data
+    }
+
     fn mirroring(&self) -> Mirroring {
         self.data.header.mirroring
     }
 }
+
+impl SaveState for Mapper2 {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.data.save_state(state);
+        state.write_usize(self.prg_0);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.data.load_state(state)?;
+        self.prg_0 = state.read_usize()?;
+        if !self.data.prg_rom.contains(Page::Number(self.prg_0, PageSize::SixteenKb)) {
+            return Err(StateError::Invalid("UxROM PRG bank out of range"));
+        }
+        Ok(())
+    }
+}
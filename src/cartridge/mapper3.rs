@@ -6,6 +6,7 @@ use super::pager::PageSize;
 use super::CartridgeData;
 use super::Mapper;
 use super::Mirroring;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 pub struct Mapper3 {
     data: CartridgeData,
@@ -19,20 +20,24 @@ impl Mapper3 {
             chr_0: 0,
         }
     }
+
+    fn prg_rom_page(&self, address: u16) -> (Page, u16) {
+        match address {
+            0x8000..=0xBFFF => (Page::First(PageSize::SixteenKb), address - 0x8000),
+            _ => (Page::Last(PageSize::SixteenKb), address - 0xC000),
+        }
+    }
 }
 
 impl Mapper for Mapper3 {
     fn read_prg_byte(&self, address: u16) -> u8 {
         match address {
-            0x8000..=0xBFFF => self
-                .data
-                .prg_rom
-                .read(Page::First(PageSize::SixteenKb), address - 0x8000),
-            0xC000..=0xFFFF => self
-                .data
-                .prg_rom
-                .read(Page::Last(PageSize::SixteenKb), address - 0xC000),
-            a => panic!("bad address: {:04X}", a),
+            0x8000..=0xFFFF => {
+                let (page, offset) = self.prg_rom_page(address);
+                self.data.prg_rom.read(page, offset)
+            }
+            // Nothing drives the bus there
+            a => (a >> 8) as u8,
         }
     }
 
@@ -53,7 +58,47 @@ impl Mapper for Mapper3 {
 
     fn write_chr_byte(&mut self, _: u16, _: u8) {}
 
+    fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000..=0xFFFF => {
+                let (page, offset) = self.prg_rom_page(address);
+                self.data.prg_rom.offset(page, offset)
+            }
+            _ => None,
+        }
+    }
+
+    fn chr_rom_offset(&self, address: u16) -> Option<usize> {
+        self.data
+            .chr_rom
+            .offset(Page::Number(self.chr_0, PageSize::EightKb), address)
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.data.prg_ram.data
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.data.prg_ram.data
+    }
+
     fn mirroring(&self) -> Mirroring {
         self.data.header.mirroring
     }
 }
+
+impl SaveState for Mapper3 {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.data.save_state(state);
+        state.write_usize(self.chr_0);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.data.load_state(state)?;
+        self.chr_0 = state.read_usize()?;
+        if !self.data.chr_rom.contains(Page::Number(self.chr_0, PageSize::EightKb)) {
+            return Err(StateError::Invalid("CNROM CHR bank out of range"));
+        }
+        Ok(())
+    }
+}
@@ -1,11 +1,23 @@
 // Mapper4 implements ines mapper 4 (MMC3)
 // https://wiki.nesdev.com/w/index.php/MMC3
+//
+// The IRQ counter is clocked by rising edges on PPU A12. The MMC3 ignores a
+// rise unless A12 was low for a few CPU cycles first, which filters out the
+// short lows between pattern fetches when the background and sprites use
+// different pattern tables.
 
 use super::pager::Page;
 use super::pager::PageSize;
 use super::CartridgeData;
 use super::Mapper;
 use super::Mirroring;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+// How many CPU cycles A12 has to stay low for the next rise to count
+const A12_LOW_CYCLES: u8 = 3;
+
+// NES 2.0 submapper for boards with the older MMC3A
+const SUBMAPPER_REV_A: u8 = 4;
 
 pub struct Mapper4 {
     data: CartridgeData,
@@ -19,12 +31,16 @@ pub struct Mapper4 {
     irq_enabled: bool,
     irq_reset: bool,
     irq_flag: bool,
+    // MMC3A only fires when the counter counts down to 0 or a $C001 write
+    // reloads it with 0, later chips fire every time it ends up at 0
+    rev_a: bool,
+    a12: bool,
+    a12_low_cycles: u8,
 }
 
 impl Mapper4 {
     pub fn new(data: CartridgeData) -> Self {
         Mapper4 {
-            data: data,
             registers: [0; 8],
             index: 0,
             prg_mode: false,
@@ -35,42 +51,87 @@ impl Mapper4 {
             irq_enabled: false,
             irq_reset: false,
             irq_flag: false,
+            rev_a: data.header.submapper_number == SUBMAPPER_REV_A,
+            a12: false,
+            a12_low_cycles: 0,
+            data,
         }
     }
+
+    fn clock_irq_counter(&mut self) {
+        let counter = self.irq_counter;
+        let reloaded = self.irq_reset;
+        if self.irq_counter == 0 || self.irq_reset {
+            self.irq_counter = self.irq_period;
+            self.irq_reset = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        let fire = self.irq_counter == 0 && (!self.rev_a || counter != 0 || reloaded);
+        if fire && self.irq_enabled {
+            self.irq_flag = true;
+        }
+    }
+
+    fn prg_rom_page(&self, address: u16) -> (Page, u16) {
+        let offset = address & 0x1FFF;
+        let page = match (address, self.prg_mode) {
+            (0x8000..=0x9FFF, false) => Page::Number(self.registers[6], PageSize::EightKb),
+            (0x8000..=0x9FFF, true) => Page::FromEnd(1, PageSize::EightKb),
+            (0xA000..=0xBFFF, _) => Page::Number(self.registers[7], PageSize::EightKb),
+            (0xC000..=0xDFFF, false) => Page::FromEnd(1, PageSize::EightKb),
+            (0xC000..=0xDFFF, true) => Page::Number(self.registers[6], PageSize::EightKb),
+            _ => Page::FromEnd(0, PageSize::EightKb),
+        };
+        (page, offset)
+    }
+
+    // $0000-$03FF 	R0 AND $FE 	R2
+    // $0400-$07FF 	R0 OR 1 	R3
+    // $0800-$0BFF 	R1 AND $FE 	R4
+    // $0C00-$0FFF 	R1 OR 1 	R5
+    // $1000-$13FF 	R2 	R0 AND $FE
+    // $1400-$17FF 	R3 	R0 OR 1
+    // $1800-$1BFF 	R4 	R1 AND $FE
+    // $1C00-$1FFF 	R5 	R1 OR 1
+    fn chr_rom_page(&self, address: u16) -> Page {
+        let bank = match (address, self.chr_mode) {
+            (0x0000..=0x03FF, false) => self.registers[0] & !1,
+            (0x0000..=0x03FF, true) => self.registers[2],
+            (0x0400..=0x07FF, false) => self.registers[0] | 1,
+            (0x0400..=0x07FF, true) => self.registers[3],
+            (0x0800..=0x0BFF, false) => self.registers[1] & !1,
+            (0x0800..=0x0BFF, true) => self.registers[4],
+            (0x0C00..=0x0FFF, false) => self.registers[1] | 1,
+            (0x0C00..=0x0FFF, true) => self.registers[5],
+
+            (0x1000..=0x13FF, false) => self.registers[2],
+            (0x1000..=0x13FF, true) => self.registers[0] & !1,
+            (0x1400..=0x17FF, false) => self.registers[3],
+            (0x1400..=0x17FF, true) => self.registers[0] | 1,
+            (0x1800..=0x1BFF, false) => self.registers[4],
+            (0x1800..=0x1BFF, true) => self.registers[1] & !1,
+            (0x1C00..=0x1FFF, false) => self.registers[5],
+            (0x1C00..=0x1FFF, true) => self.registers[1] | 1,
+            _ => panic!(),
+        };
+        Page::Number(bank, PageSize::OneKb)
+    }
 }
 
 impl Mapper for Mapper4 {
     fn read_prg_byte(&self, address: u16) -> u8 {
-        match (address, self.prg_mode) {
-            (0x6000..=0x7FFF, _) => self
+        match address {
+            0x6000..=0x7FFF => self
                 .data
                 .prg_ram
                 .read(Page::First(PageSize::EightKb), address - 0x6000),
-            (0x8000..=0x9FFF, false) => self.data.prg_rom.read(
-                Page::Number(self.registers[6], PageSize::EightKb),
-                address - 0x8000,
-            ),
-            (0x8000..=0x9FFF, true) => self
-                .data
-                .prg_rom
-                .read(Page::FromEnd(1, PageSize::EightKb), address - 0x8000),
-            (0xA000..=0xBFFF, _) => self.data.prg_rom.read(
-                Page::Number(self.registers[7], PageSize::EightKb),
-                address - 0xA000,
-            ),
-            (0xC000..=0xDFFF, false) => self
-                .data
-                .prg_rom
-                .read(Page::FromEnd(1, PageSize::EightKb), address - 0xC000),
-            (0xC000..=0xDFFF, true) => self.data.prg_rom.read(
-                Page::Number(self.registers[6], PageSize::EightKb),
-                address - 0xC000,
-            ),
-            (0xE000..=0xFFFF, _) => self
-                .data
-                .prg_rom
-                .read(Page::FromEnd(0, PageSize::EightKb), address - 0xE000),
-            (a, _m) => panic!("bad address: {:04X}", a),
+            0x8000..=0xFFFF => {
+                let (page, offset) = self.prg_rom_page(address);
+                self.data.prg_rom.read(page, offset)
+            }
+            // Nothing drives the bus there
+            a => (a >> 8) as u8,
         }
     }
 
@@ -102,48 +163,48 @@ impl Mapper for Mapper4 {
                 self.irq_enabled = false;
                 self.irq_flag = false;
             }
-            (0xF000..=0xFFFF, 1) => self.irq_enabled = true,
+            (0xE000..=0xFFFF, 1) => self.irq_enabled = true,
 
             _ => (),
         }
     }
-    // $0000-$03FF 	R0 AND $FE 	R2
-    // $0400-$07FF 	R0 OR 1 	R3
-    // $0800-$0BFF 	R1 AND $FE 	R4
-    // $0C00-$0FFF 	R1 OR 1 	R5
-    // $1000-$13FF 	R2 	R0 AND $FE
-    // $1400-$17FF 	R3 	R0 OR 1
-    // $1800-$1BFF 	R4 	R1 AND $FE
-    // $1C00-$1FFF 	R5 	R1 OR 1
-    fn read_chr_byte(&self, address: u16) -> u8 {
-        let bank = match (address, self.chr_mode) {
-            (0x0000..=0x03FF, false) => self.registers[0] & !1,
-            (0x0000..=0x03FF, true) => self.registers[2],
-            (0x0400..=0x07FF, false) => self.registers[0] | 1,
-            (0x0400..=0x07FF, true) => self.registers[3],
-            (0x0800..=0x0BFF, false) => self.registers[1] & !1,
-            (0x0800..=0x0BFF, true) => self.registers[4],
-            (0x0C00..=0x0FFF, false) => self.registers[1] | 1,
-            (0x0C00..=0x0FFF, true) => self.registers[5],
-
-            (0x1000..=0x13FF, false) => self.registers[2],
-            (0x1000..=0x13FF, true) => self.registers[0] & !1,
-            (0x1400..=0x17FF, false) => self.registers[3],
-            (0x1400..=0x17FF, true) => self.registers[0] | 1,
-            (0x1800..=0x1BFF, false) => self.registers[4],
-            (0x1800..=0x1BFF, true) => self.registers[1] & !1,
-            (0x1C00..=0x1FFF, false) => self.registers[5],
-            (0x1C00..=0x1FFF, true) => self.registers[1] | 1,
-            _ => panic!(),
-        };
 
+    fn read_chr_byte(&self, address: u16) -> u8 {
         self.data
             .chr_rom
-            .read(Page::Number(bank, PageSize::OneKb), address % 0x0400)
+            .read(self.chr_rom_page(address), address % 0x0400)
     }
 
     fn write_chr_byte(&mut self, _: u16, _: u8) {}
 
+    fn chr_rom_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x0000..=0x1FFF => self
+                .data
+                .chr_rom
+                .offset(self.chr_rom_page(address), address % 0x0400),
+            _ => None,
+        }
+    }
+
+    fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000..=0xFFFF => {
+                let (page, offset) = self.prg_rom_page(address);
+                self.data.prg_rom.offset(page, offset)
+            }
+            _ => None,
+        }
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.data.prg_ram.data
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.data.prg_ram.data
+    }
+
     fn mirroring(&self) -> Mirroring {
         self.mirroring
     }
@@ -151,14 +212,199 @@ impl Mapper for Mapper4 {
     fn irq_flag(&self) -> bool {
         self.irq_flag
     }
-    fn signal_scanline(&mut self) {
-        if self.irq_counter == 0 || self.irq_reset {
-            if self.irq_enabled {
-                self.irq_flag = true;
-            }
-            self.irq_counter = self.irq_period;
-        } else {
-            self.irq_counter -= 1;
+
+    fn ppu_address(&mut self, address: u16) {
+        let a12 = address & 0x1000 != 0;
+        if a12 && !self.a12 && self.a12_low_cycles >= A12_LOW_CYCLES {
+            self.clock_irq_counter();
+        }
+        if a12 {
+            self.a12_low_cycles = 0;
+        }
+        self.a12 = a12;
+    }
+
+    fn tick(&mut self) {
+        if !self.a12 {
+            self.a12_low_cycles = self.a12_low_cycles.saturating_add(1);
         }
     }
 }
+
+impl SaveState for Mapper4 {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.data.save_state(state);
+        for r in self.registers.iter() {
+            state.write_usize(*r);
+        }
+        state.write_usize(self.index);
+        state.write_bool(self.prg_mode);
+        state.write_bool(self.chr_mode);
+        self.mirroring.save_state(state);
+        state.write_u8(self.irq_counter);
+        state.write_u8(self.irq_period);
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.irq_reset);
+        state.write_bool(self.irq_flag);
+        state.write_bool(self.a12);
+        state.write_u8(self.a12_low_cycles);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.data.load_state(state)?;
+        for r in self.registers.iter_mut() {
+            *r = state.read_usize()?;
+        }
+        self.index = state.read_usize()?;
+        if self.index >= self.registers.len() {
+            return Err(StateError::Invalid("bad MMC3 bank select"));
+        }
+        self.prg_mode = state.read_bool()?;
+        self.chr_mode = state.read_bool()?;
+        self.mirroring.load_state(state)?;
+        self.irq_counter = state.read_u8()?;
+        self.irq_period = state.read_u8()?;
+        self.irq_enabled = state.read_bool()?;
+        self.irq_reset = state.read_bool()?;
+        self.irq_flag = state.read_bool()?;
+        self.a12 = state.read_bool()?;
+        self.a12_low_cycles = state.read_u8()?;
+
+        let prg_rom = &self.data.prg_rom;
+        if !(0x8000..=0xFFFF)
+            .step_by(0x2000)
+            .all(|a| prg_rom.contains(self.prg_rom_page(a).0))
+        {
+            return Err(StateError::Invalid("MMC3 PRG bank out of range"));
+        }
+        let chr_rom = &self.data.chr_rom;
+        if !(0x0000..0x2000)
+            .step_by(0x0400)
+            .all(|a| chr_rom.contains(self.chr_rom_page(a)))
+        {
+            return Err(StateError::Invalid("MMC3 CHR bank out of range"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::build_test_rom;
+
+    fn build_cartridge_data(submapper: u8) -> CartridgeData {
+        let mut data = build_test_rom(0x40, &[0; 0x8000], &[0; 0x2000]);
+        data[7] = 0x08; // NES 2.0
+        data[8] = submapper << 4;
+        data[10] = 0x07; // 8kb prg ram
+        CartridgeData::new(&data).unwrap()
+    }
+
+    fn build_mapper(submapper: u8, period: u8) -> Mapper4 {
+        let mut mapper = Mapper4::new(build_cartridge_data(submapper));
+        mapper.write_prg_byte(0xC000, period);
+        mapper.write_prg_byte(0xC001, 0);
+        mapper.write_prg_byte(0xE001, 0);
+        mapper
+    }
+
+    // Brings A12 low for `cycles` CPU cycles, then high again
+    fn toggle_a12(mapper: &mut Mapper4, cycles: u8) {
+        mapper.ppu_address(0x0FF0);
+        for _ in 0..cycles {
+            mapper.tick();
+        }
+        mapper.ppu_address(0x1FF0);
+    }
+
+    #[test]
+    fn test_a12_clocks_irq() {
+        let mut mapper = build_mapper(0, 2);
+        toggle_a12(&mut mapper, A12_LOW_CYCLES);
+        assert_eq!(mapper.irq_counter, 2);
+        toggle_a12(&mut mapper, A12_LOW_CYCLES);
+        assert!(!mapper.irq_flag());
+        // Staying high, or reads in the same half, don't count
+        mapper.ppu_address(0x1000);
+        assert_eq!(mapper.irq_counter, 1);
+        toggle_a12(&mut mapper, A12_LOW_CYCLES);
+        assert!(mapper.irq_flag());
+
+        mapper.write_prg_byte(0xE000, 0);
+        assert!(!mapper.irq_flag());
+    }
+
+    #[test]
+    fn test_a12_filter() {
+        let mut mapper = build_mapper(0, 2);
+        toggle_a12(&mut mapper, A12_LOW_CYCLES);
+        toggle_a12(&mut mapper, 1);
+        toggle_a12(&mut mapper, A12_LOW_CYCLES - 1);
+        assert_eq!(mapper.irq_counter, 2);
+        toggle_a12(&mut mapper, A12_LOW_CYCLES);
+        assert_eq!(mapper.irq_counter, 1);
+    }
+
+    #[test]
+    fn test_zero_period() {
+        // Later chips fire on every clock while the period is 0
+        let mut mapper = build_mapper(0, 0);
+        for _ in 0..3 {
+            toggle_a12(&mut mapper, A12_LOW_CYCLES);
+            assert!(mapper.irq_flag());
+            mapper.write_prg_byte(0xE000, 0);
+            mapper.write_prg_byte(0xE001, 0);
+        }
+
+        // MMC3A only fires after a $C001 write
+        let mut mapper = build_mapper(SUBMAPPER_REV_A, 0);
+        toggle_a12(&mut mapper, A12_LOW_CYCLES);
+        assert!(mapper.irq_flag());
+        mapper.write_prg_byte(0xE000, 0);
+        mapper.write_prg_byte(0xE001, 0);
+        toggle_a12(&mut mapper, A12_LOW_CYCLES);
+        assert!(!mapper.irq_flag());
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut mapper = build_mapper(0, 5);
+        toggle_a12(&mut mapper, A12_LOW_CYCLES);
+        mapper.ppu_address(0x0000);
+        mapper.tick();
+        let mut state = StateWriter::new();
+        mapper.save_state(&mut state);
+
+        let mut loaded = Mapper4::new(build_cartridge_data(0));
+        let data = state.data;
+        loaded.load_state(&mut StateReader::new(&data)).unwrap();
+        assert_eq!(loaded.irq_counter, 5);
+        assert!(!loaded.a12);
+        assert_eq!(loaded.a12_low_cycles, 1);
+    }
+
+    #[test]
+    fn test_load_state_checks_banks() {
+        // 32kb of PRG-ROM is only four 8kb banks
+        let mut mapper = build_mapper(0, 0);
+        mapper.registers[7] = 4;
+        let mut state = StateWriter::new();
+        mapper.save_state(&mut state);
+        let mut loaded = Mapper4::new(build_cartridge_data(0));
+        assert!(matches!(
+            loaded.load_state(&mut StateReader::new(&state.data)),
+            Err(StateError::Invalid(_))
+        ));
+
+        // And 8kb of CHR-ROM is eight 1kb banks
+        let mut mapper = build_mapper(0, 0);
+        mapper.registers[5] = 8;
+        let mut state = StateWriter::new();
+        mapper.save_state(&mut state);
+        assert!(matches!(
+            loaded.load_state(&mut StateReader::new(&state.data)),
+            Err(StateError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,877 @@
+// Mapper5 implements ines mapper 5 (MMC5)
+// https://wiki.nesdev.com/w/index.php/MMC5
+//
+// The MMC5 works out what the PPU is doing by watching its reads. Three reads
+// in a row from the same nametable address mark the start of a scanline, and
+// counting reads from there tells background fetches from sprite ones, which
+// the 8x16 sprite banks, extended attributes and the split screen rely on.
+
+use super::pager::Page;
+use super::pager::PageSize;
+use super::pager::Pager;
+use super::CartridgeData;
+use super::Mapper;
+use super::Mirroring;
+use crate::apu::Mmc5Audio;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+use std::cell::Cell;
+
+const EXRAM_SIZE: usize = 0x400;
+// iNES 1.0 headers can't be trusted with the RAM size, so those get the most
+// any MMC5 board has
+const DEFAULT_PRG_RAM_SIZE: usize = 0x10000;
+
+// The reads of a scanline, counting the one that marked its start as 0: 32
+// background tiles, 8 sprites, 2 tiles for the next scanline and 2 spare
+// nametable reads. Each tile and sprite is four reads.
+const SPRITE_FETCHES_START: usize = 128;
+const SPRITE_FETCHES_END: usize = 160;
+
+// The PPU stops reading in vblank or with rendering off, and the MMC5 decides
+// the frame is over after this many CPU cycles without a read
+const IDLE_CYCLES: u8 = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Fetch {
+    // Not rendering, so the CPU is reaching the PPU through $2007
+    None,
+    Background,
+    Sprite,
+}
+
+// Where the background tile being fetched comes from
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Tile {
+    Normal,
+    // Extended attribute mode, with the tile's ExRAM byte holding its
+    // palette and a 4KB CHR bank
+    Extended(u8),
+    // Inside the split screen, which draws from ExRAM with its own scroll
+    Split { row: usize, column: usize },
+}
+
+pub struct Mapper5 {
+    data: CartridgeData,
+    exram: Vec<u8>,
+    prg_mode: u8,
+    chr_mode: u8,
+    ram_protect: [u8; 2],
+    exram_mode: u8,
+    // Two bits for each nametable: CIRAM page 0 or 1, ExRAM or fill mode
+    nametables: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+    // $5113-$5117
+    prg_banks: [u8; 5],
+    // $5120-$5127 for sprites, $5128-$512B for the background
+    chr_banks: [usize; 12],
+    chr_upper: usize,
+    background_banks_written: bool,
+    split: u8,
+    split_scroll: u8,
+    split_bank: u8,
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: Cell<bool>,
+    in_frame: Cell<bool>,
+    scanline: u8,
+    multiplicand: u8,
+    multiplier: u8,
+    // Snooped from PPUCTRL
+    large_sprites: bool,
+    // Following the PPU's reads
+    last_fetch: u16,
+    matching_fetches: u8,
+    next_fetch: usize,
+    idle: u8,
+    fetch: Fetch,
+    tile: Tile,
+    audio: Mmc5Audio,
+    pcm_read_mode: bool,
+    pcm_irq_enabled: bool,
+    pcm_irq: Cell<bool>,
+    // A byte read from $8000-$BFFF in PCM read mode, played on the next tick
+    pcm_read: Cell<Option<u8>>,
+}
+
+impl Mapper5 {
+    pub fn new(mut data: CartridgeData) -> Self {
+        if !data.header.nes2 {
+            data.prg_ram = Pager::new(vec![0; DEFAULT_PRG_RAM_SIZE]);
+        }
+        Mapper5 {
+            data,
+            exram: vec![0; EXRAM_SIZE],
+            prg_mode: 3,
+            chr_mode: 0,
+            ram_protect: [0; 2],
+            exram_mode: 0,
+            nametables: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            prg_banks: [0, 0, 0, 0, 0xFF],
+            chr_banks: [0; 12],
+            chr_upper: 0,
+            background_banks_written: false,
+            split: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: Cell::new(false),
+            in_frame: Cell::new(false),
+            scanline: 0,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+            large_sprites: false,
+            last_fetch: 0,
+            matching_fetches: 0,
+            next_fetch: 0,
+            idle: 0,
+            fetch: Fetch::None,
+            tile: Tile::Normal,
+            audio: Mmc5Audio::new(),
+            pcm_read_mode: false,
+            pcm_irq_enabled: false,
+            pcm_irq: Cell::new(false),
+            pcm_read: Cell::new(None),
+        }
+    }
+
+    // The 8KB bank behind a CPU address in $6000-$FFFF, and whether it is ROM
+    fn prg_bank(&self, address: u16) -> (usize, bool) {
+        if address < 0x8000 {
+            return (self.prg_banks[0] as usize & 0x0F, false);
+        }
+        let slot = (address as usize - 0x8000) / 0x2000;
+        // Which of $5114-$5117 maps the slot, and how many 8KB banks it spans
+        let (register, banks) = match (self.prg_mode, slot) {
+            (0, _) => (4, 4),
+            (1, 0..=1) | (2, 0..=1) => (2, 2),
+            (1, _) => (4, 2),
+            (2, 2) => (3, 1),
+            (2, _) => (4, 1),
+            (_, slot) => (1 + slot, 1),
+        };
+        let value = self.prg_banks[register] as usize;
+        // $5117 can only map ROM
+        let rom = register == 4 || value & 0x80 != 0;
+        let mask = if rom { 0x7F } else { 0x0F };
+        ((value & mask & !(banks - 1)) | (slot & (banks - 1)), rom)
+    }
+
+    // What $5105 maps at a nametable address: 0 and 1 are CIRAM pages, 2 is
+    // ExRAM and 3 is fill mode
+    fn nametable_source(&self, address: u16) -> u8 {
+        (self.nametables >> (((address >> 10) & 3) * 2)) & 3
+    }
+
+    fn ram_writable(&self) -> bool {
+        self.ram_protect == [0b10, 0b01]
+    }
+
+    fn product(&self) -> u16 {
+        self.multiplicand as u16 * self.multiplier as u16
+    }
+
+    fn chr(&self) -> &Pager {
+        if self.data.header.chr_rom_bytes() == 0 {
+            &self.data.chr_ram
+        } else {
+            &self.data.chr_rom
+        }
+    }
+
+    fn chr_page(&self, address: u16) -> (Page, u16) {
+        if self.fetch == Fetch::Background {
+            match self.tile {
+                Tile::Split { row, .. } => {
+                    let page = page(self.chr(), self.split_bank as usize, PageSize::FourKb);
+                    return (page, (address & 0xFF8) | (row as u16 & 7));
+                }
+                Tile::Extended(ex) => {
+                    let bank = (ex as usize & 0x3F) | self.chr_upper << 6;
+                    return (page(self.chr(), bank, PageSize::FourKb), address & 0xFFF);
+                }
+                Tile::Normal => (),
+            }
+        }
+        // With 8x8 sprites, and whenever the PPU isn't rendering, whichever set
+        // of banks was written last is used for everything
+        let sprites = match self.fetch {
+            Fetch::Sprite if self.large_sprites => true,
+            Fetch::Background if self.large_sprites => false,
+            _ => !self.background_banks_written,
+        };
+        let banks = if sprites {
+            [0, 1, 2, 3, 4, 5, 6, 7]
+        } else {
+            [8, 9, 10, 11, 8, 9, 10, 11]
+        };
+        let address = address as usize & 0x1FFF;
+        let (size, slot) = match self.chr_mode {
+            0 => (PageSize::EightKb, 7),
+            1 => (PageSize::FourKb, address / 0x1000 * 4 + 3),
+            2 => (PageSize::TwoKb, address / 0x800 * 2 + 1),
+            _ => (PageSize::OneKb, address / 0x400),
+        };
+        let bank = self.chr_banks[banks[slot]];
+        let offset = (address % size as usize) as u16;
+        (page(self.chr(), bank, size), offset)
+    }
+
+    // Works out which tile a background nametable read belongs to
+    fn background_tile(&self, address: u16, fetch: usize) -> Tile {
+        // The last two tiles fetched on a scanline are the first two of the next
+        let (tile, next_line) = if fetch < SPRITE_FETCHES_START {
+            (2 + fetch / 4, false)
+        } else {
+            ((fetch - SPRITE_FETCHES_END) / 4, true)
+        };
+        if self.split & 0x80 != 0 && self.exram_mode <= 1 {
+            let threshold = (self.split & 0x1F) as usize;
+            let inside = if self.split & 0x40 != 0 {
+                tile >= threshold
+            } else {
+                tile < threshold
+            };
+            if inside {
+                let line = if self.in_frame.get() {
+                    self.scanline as usize + next_line as usize
+                } else {
+                    0
+                };
+                return Tile::Split {
+                    row: (self.split_scroll as usize + line) % 240,
+                    column: tile % 32,
+                };
+            }
+        }
+        if self.exram_mode == 1 {
+            Tile::Extended(self.exram[address as usize % EXRAM_SIZE])
+        } else {
+            Tile::Normal
+        }
+    }
+
+    fn start_scanline(&mut self) {
+        if self.in_frame.get() {
+            self.scanline = self.scanline.wrapping_add(1);
+            if self.scanline == self.irq_compare {
+                self.irq_pending.set(true);
+            }
+        } else {
+            self.in_frame.set(true);
+            self.scanline = 0;
+            self.irq_pending.set(false);
+        }
+    }
+
+    fn leave_frame(&mut self) {
+        self.in_frame.set(false);
+        self.last_fetch = 0;
+        self.matching_fetches = 0;
+        self.next_fetch = 0;
+        self.fetch = Fetch::None;
+        self.tile = Tile::Normal;
+    }
+}
+
+// Bank numbers past the end of the memory wrap around
+fn page(pager: &Pager, bank: usize, size: PageSize) -> Page {
+    let banks = (pager.data.len() / size as usize).max(1);
+    Page::Number(bank % banks, size)
+}
+
+// An attribute byte with the same palette for all four of its tiles
+fn attribute(palette: u8) -> u8 {
+    (palette & 3) * 0x55
+}
+
+impl Mapper for Mapper5 {
+    fn read_prg_byte(&self, address: u16) -> u8 {
+        let value = self.peek_prg_byte(address);
+        match address {
+            0x5010 => self.pcm_irq.set(false),
+            0x5204 => self.irq_pending.set(false),
+            0x8000..=0xBFFF if self.pcm_read_mode => self.pcm_read.set(Some(value)),
+            // Fetching the NMI vector means vblank has started
+            0xFFFA | 0xFFFB => self.in_frame.set(false),
+            _ => (),
+        }
+        value
+    }
+
+    fn peek_prg_byte(&self, address: u16) -> u8 {
+        match address {
+            0x5010 => (self.pcm_irq.get() as u8) << 7,
+            0x5015 => self.audio.status(),
+            0x5204 => (self.irq_pending.get() as u8) << 7 | (self.in_frame.get() as u8) << 6,
+            0x5205 => self.product() as u8,
+            0x5206 => (self.product() >> 8) as u8,
+            0x5C00..=0x5FFF if self.exram_mode >= 2 => self.exram[address as usize % EXRAM_SIZE],
+            0x6000..=0xFFFF => {
+                let (bank, rom) = self.prg_bank(address);
+                let offset = address & 0x1FFF;
+                if rom {
+                    let page = page(&self.data.prg_rom, bank, PageSize::EightKb);
+                    self.data.prg_rom.read(page, offset)
+                } else {
+                    let page = page(&self.data.prg_ram, bank, PageSize::EightKb);
+                    self.data.prg_ram.read(page, offset)
+                }
+            }
+            a => (a >> 8) as u8,
+        }
+    }
+
+    fn write_prg_byte(&mut self, address: u16, value: u8) {
+        match address {
+            0x5000..=0x5007 | 0x5015 => self.audio.write_register(address, value),
+            0x5010 => {
+                self.pcm_read_mode = value & 1 != 0;
+                self.pcm_irq_enabled = value & 0x80 != 0;
+            }
+            // Writing 0 is ignored
+            0x5011 if !self.pcm_read_mode && value != 0 => self.audio.pcm = value,
+            0x5100 => self.prg_mode = value & 3,
+            0x5101 => self.chr_mode = value & 3,
+            0x5102 | 0x5103 => self.ram_protect[address as usize - 0x5102] = value & 3,
+            0x5104 => self.exram_mode = value & 3,
+            0x5105 => self.nametables = value,
+            0x5106 => self.fill_tile = value,
+            0x5107 => self.fill_attribute = value & 3,
+            0x5113..=0x5117 => self.prg_banks[address as usize - 0x5113] = value,
+            0x5120..=0x512B => {
+                self.chr_banks[address as usize - 0x5120] = value as usize | self.chr_upper << 8;
+                self.background_banks_written = address >= 0x5128;
+            }
+            0x5130 => self.chr_upper = value as usize & 3,
+            0x5200 => self.split = value,
+            0x5201 => self.split_scroll = value,
+            0x5202 => self.split_bank = value,
+            0x5203 => self.irq_compare = value,
+            0x5204 => self.irq_enabled = value & 0x80 != 0,
+            0x5205 => self.multiplicand = value,
+            0x5206 => self.multiplier = value,
+            0x5C00..=0x5FFF => {
+                let i = address as usize % EXRAM_SIZE;
+                match self.exram_mode {
+                    // While it is a nametable, ExRAM can only be written during rendering
+                    0 | 1 => self.exram[i] = if self.in_frame.get() { value } else { 0 },
+                    2 => self.exram[i] = value,
+                    _ => (),
+                }
+            }
+            0x6000..=0xFFFF => {
+                let (bank, rom) = self.prg_bank(address);
+                if !rom && self.ram_writable() {
+                    let page = page(&self.data.prg_ram, bank, PageSize::EightKb);
+                    self.data.prg_ram.write(page, address & 0x1FFF, value);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn read_chr_byte(&self, address: u16) -> u8 {
+        let (page, offset) = self.chr_page(address);
+        self.chr().read(page, offset)
+    }
+
+    fn write_chr_byte(&mut self, address: u16, value: u8) {
+        if self.data.header.chr_rom_bytes() == 0 {
+            let (page, offset) = self.chr_page(address);
+            self.data.chr_ram.write(page, offset, value);
+        }
+    }
+
+    fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x6000..=0xFFFF => match self.prg_bank(address) {
+                (bank, true) => {
+                    let page = page(&self.data.prg_rom, bank, PageSize::EightKb);
+                    self.data.prg_rom.offset(page, address & 0x1FFF)
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn chr_rom_offset(&self, address: u16) -> Option<usize> {
+        let (page, offset) = self.chr_page(address);
+        self.data.chr_rom.offset(page, offset)
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.data.prg_ram.data
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.data.prg_ram.data
+    }
+
+    // Only a summary, read_nametable does the real work
+    fn mirroring(&self) -> Mirroring {
+        match self.nametables {
+            0x00 => Mirroring::SingleScreenA,
+            0x55 => Mirroring::SingleScreenB,
+            0x44 => Mirroring::Vertical,
+            0x50 => Mirroring::Horizontal,
+            _ => Mirroring::FourScreen,
+        }
+    }
+
+    fn irq_flag(&self) -> bool {
+        (self.irq_enabled && self.irq_pending.get()) || (self.pcm_irq_enabled && self.pcm_irq.get())
+    }
+
+    fn read_nametable(&self, address: u16, ciram: &[u8]) -> u8 {
+        let offset = address as usize % EXRAM_SIZE;
+        let is_attribute = offset >= 0x3C0;
+        if self.fetch == Fetch::Background {
+            match self.tile {
+                Tile::Split { row, column } if is_attribute => {
+                    let byte = self.exram[0x3C0 + row / 32 * 8 + column / 4];
+                    let shift = ((row / 16) & 1) * 4 + ((column / 2) & 1) * 2;
+                    return attribute(byte >> shift);
+                }
+                Tile::Split { row, column } => return self.exram[row / 8 * 32 + column],
+                Tile::Extended(ex) if is_attribute => return attribute(ex >> 6),
+                _ => (),
+            }
+        }
+        match self.nametable_source(address) {
+            0 => ciram[offset],
+            1 => ciram[EXRAM_SIZE + offset],
+            2 if self.exram_mode <= 1 => self.exram[offset],
+            2 => 0,
+            _ if is_attribute => attribute(self.fill_attribute),
+            _ => self.fill_tile,
+        }
+    }
+
+    fn write_nametable(&mut self, address: u16, value: u8, ciram: &mut [u8]) {
+        let offset = address as usize % EXRAM_SIZE;
+        match self.nametable_source(address) {
+            0 => ciram[offset] = value,
+            1 => ciram[EXRAM_SIZE + offset] = value,
+            2 if self.exram_mode <= 1 => self.exram[offset] = value,
+            _ => (),
+        }
+    }
+
+    fn ppu_fetch(&mut self, address: u16) {
+        self.idle = IDLE_CYCLES;
+        if (0x2000..=0x2FFF).contains(&address) && address == self.last_fetch {
+            self.matching_fetches += 1;
+        } else {
+            self.matching_fetches = 0;
+        }
+        self.last_fetch = address;
+
+        let fetch = if self.matching_fetches == 2 {
+            self.start_scanline();
+            0
+        } else {
+            self.next_fetch
+        };
+        self.next_fetch = fetch + 1;
+
+        self.fetch = if (SPRITE_FETCHES_START..SPRITE_FETCHES_END).contains(&fetch) {
+            Fetch::Sprite
+        } else {
+            Fetch::Background
+        };
+        let is_tile = (0x2000..=0x3EFF).contains(&address) && address as usize % EXRAM_SIZE < 0x3C0;
+        if self.fetch == Fetch::Background && is_tile {
+            self.tile = self.background_tile(address, fetch);
+        }
+    }
+
+    fn ppu_register_written(&mut self, address: u16, value: u8) {
+        if address & 7 == 0 {
+            self.large_sprites = value & 0x20 != 0;
+        }
+    }
+
+    fn tick(&mut self) {
+        self.audio.tick();
+        if let Some(value) = self.pcm_read.take() {
+            // A 0 in read mode is the end of a sample
+            if value == 0 {
+                self.pcm_irq.set(true);
+            } else {
+                self.audio.pcm = value;
+            }
+        }
+        if self.idle > 0 {
+            self.idle -= 1;
+            if self.idle == 0 {
+                self.leave_frame();
+            }
+        }
+    }
+
+    fn audio_output(&self) -> f64 {
+        self.audio.output()
+    }
+}
+
+fn save_tile(tile: Tile, state: &mut StateWriter) {
+    let (kind, a, b) = match tile {
+        Tile::Normal => (0, 0, 0),
+        Tile::Extended(ex) => (1, ex as usize, 0),
+        Tile::Split { row, column } => (2, row, column),
+    };
+    state.write_u8(kind);
+    state.write_usize(a);
+    state.write_usize(b);
+}
+
+fn load_tile(state: &mut StateReader) -> Result<Tile, StateError> {
+    let kind = state.read_u8()?;
+    let a = state.read_usize()?;
+    let b = state.read_usize()?;
+    match (kind, a, b) {
+        (0, _, _) => Ok(Tile::Normal),
+        (1, ex, _) => Ok(Tile::Extended(ex as u8)),
+        (2, row, column) if row < 240 && column < 32 => Ok(Tile::Split { row, column }),
+        _ => Err(StateError::Invalid("bad MMC5 tile")),
+    }
+}
+
+impl SaveState for Mapper5 {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.data.save_state(state);
+        state.write_bytes(&self.exram);
+        state.write_u8(self.prg_mode);
+        state.write_u8(self.chr_mode);
+        state.write_bytes(&self.ram_protect);
+        state.write_u8(self.exram_mode);
+        state.write_u8(self.nametables);
+        state.write_u8(self.fill_tile);
+        state.write_u8(self.fill_attribute);
+        state.write_bytes(&self.prg_banks);
+        for bank in self.chr_banks.iter() {
+            state.write_usize(*bank);
+        }
+        state.write_usize(self.chr_upper);
+        state.write_bool(self.background_banks_written);
+        state.write_u8(self.split);
+        state.write_u8(self.split_scroll);
+        state.write_u8(self.split_bank);
+        state.write_u8(self.irq_compare);
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.irq_pending.get());
+        state.write_bool(self.in_frame.get());
+        state.write_u8(self.scanline);
+        state.write_u8(self.multiplicand);
+        state.write_u8(self.multiplier);
+        state.write_bool(self.large_sprites);
+        state.write_u16(self.last_fetch);
+        state.write_u8(self.matching_fetches);
+        state.write_usize(self.next_fetch);
+        state.write_u8(self.idle);
+        state.write_u8(match self.fetch {
+            Fetch::None => 0,
+            Fetch::Background => 1,
+            Fetch::Sprite => 2,
+        });
+        save_tile(self.tile, state);
+        self.audio.save_state(state);
+        state.write_bool(self.pcm_read_mode);
+        state.write_bool(self.pcm_irq_enabled);
+        state.write_bool(self.pcm_irq.get());
+        state.write_bool(self.pcm_read.get().is_some());
+        state.write_u8(self.pcm_read.get().unwrap_or(0));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.data.load_state(state)?;
+        state.read_bytes_into(&mut self.exram)?;
+        self.prg_mode = state.read_u8()? & 3;
+        self.chr_mode = state.read_u8()? & 3;
+        state.read_bytes_into(&mut self.ram_protect)?;
+        self.exram_mode = state.read_u8()? & 3;
+        self.nametables = state.read_u8()?;
+        self.fill_tile = state.read_u8()?;
+        self.fill_attribute = state.read_u8()? & 3;
+        state.read_bytes_into(&mut self.prg_banks)?;
+        for bank in self.chr_banks.iter_mut() {
+            *bank = state.read_usize()?;
+        }
+        self.chr_upper = state.read_usize()? & 3;
+        self.background_banks_written = state.read_bool()?;
+        self.split = state.read_u8()?;
+        self.split_scroll = state.read_u8()?;
+        self.split_bank = state.read_u8()?;
+        self.irq_compare = state.read_u8()?;
+        self.irq_enabled = state.read_bool()?;
+        self.irq_pending.set(state.read_bool()?);
+        self.in_frame.set(state.read_bool()?);
+        self.scanline = state.read_u8()?;
+        self.multiplicand = state.read_u8()?;
+        self.multiplier = state.read_u8()?;
+        self.large_sprites = state.read_bool()?;
+        self.last_fetch = state.read_u16()?;
+        self.matching_fetches = state.read_u8()?;
+        self.next_fetch = state.read_usize()?;
+        self.idle = state.read_u8()?;
+        self.fetch = match state.read_u8()? {
+            0 => Fetch::None,
+            1 => Fetch::Background,
+            2 => Fetch::Sprite,
+            _ => return Err(StateError::Invalid("bad MMC5 fetch")),
+        };
+        self.tile = load_tile(state)?;
+        self.audio.load_state(state)?;
+        self.pcm_read_mode = state.read_bool()?;
+        self.pcm_irq_enabled = state.read_bool()?;
+        self.pcm_irq.set(state.read_bool()?);
+        let has_pcm_read = state.read_bool()?;
+        let pcm_read = state.read_u8()?;
+        self.pcm_read
+            .set(if has_pcm_read { Some(pcm_read) } else { None });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::build_test_rom;
+
+    fn build_cartridge_data() -> CartridgeData {
+        // Every byte of an 8kb PRG bank holds the bank number
+        let prg: Vec<u8> = (0..0x4000 * 8).map(|i| (i / 0x2000) as u8).collect();
+        // Every byte of a 1kb CHR bank holds the bank number
+        let chr: Vec<u8> = (0..0x2000 * 8).map(|i| (i / 0x400) as u8).collect();
+
+        CartridgeData::new(&build_test_rom(0x50, &prg, &chr)).unwrap()
+    }
+
+    // Feeds the mapper the reads the PPU makes over one scanline, starting
+    // from the third read of $2002 that begins it
+    fn fetch_scanline(mapper: &mut Mapper5) {
+        let fetch_tile = |mapper: &mut Mapper5, tile: u16| {
+            mapper.ppu_fetch(0x2000 + tile);
+            mapper.ppu_fetch(0x23C0 + tile / 4);
+            mapper.ppu_fetch(0x0000);
+            mapper.ppu_fetch(0x0008);
+        };
+        for tile in 2..34 {
+            fetch_tile(mapper, tile % 32);
+        }
+        for _ in 0..8 {
+            mapper.ppu_fetch(0x2000);
+            mapper.ppu_fetch(0x2000);
+            mapper.ppu_fetch(0x1FF0);
+            mapper.ppu_fetch(0x1FF8);
+        }
+        for tile in 0..2 {
+            fetch_tile(mapper, tile);
+        }
+        mapper.ppu_fetch(0x2002);
+        mapper.ppu_fetch(0x2002);
+    }
+
+    #[test]
+    fn test_prg_modes() {
+        let mut mapper = Mapper5::new(build_cartridge_data());
+        assert_eq!(mapper.read_prg_byte(0xE000), 15);
+        assert_eq!(mapper.prg_rom_offset(0xFFFF), Some(0x1FFFF));
+        mapper.write_prg_byte(0x5114, 0x82);
+        assert_eq!(mapper.read_prg_byte(0x8000), 2);
+
+        mapper.write_prg_byte(0x5100, 0);
+        mapper.write_prg_byte(0x5117, 0x07);
+        assert_eq!(mapper.read_prg_byte(0x8000), 4);
+        assert_eq!(mapper.read_prg_byte(0xE000), 7);
+
+        mapper.write_prg_byte(0x5100, 1);
+        mapper.write_prg_byte(0x5115, 0x85);
+        assert_eq!(mapper.read_prg_byte(0x8000), 4);
+        assert_eq!(mapper.read_prg_byte(0xA000), 5);
+        assert_eq!(mapper.read_prg_byte(0xC000), 6);
+
+        mapper.write_prg_byte(0x5100, 2);
+        mapper.write_prg_byte(0x5116, 0x89);
+        assert_eq!(mapper.read_prg_byte(0xA000), 5);
+        assert_eq!(mapper.read_prg_byte(0xC000), 9);
+        assert_eq!(mapper.read_prg_byte(0xE000), 7);
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut mapper = Mapper5::new(build_cartridge_data());
+        assert_eq!(mapper.prg_ram().len(), 0x10000);
+        mapper.write_prg_byte(0x5113, 1);
+        mapper.write_prg_byte(0x6000, 0x42);
+        assert_eq!(mapper.read_prg_byte(0x6000), 0);
+
+        mapper.write_prg_byte(0x5102, 2);
+        mapper.write_prg_byte(0x5103, 1);
+        mapper.write_prg_byte(0x6000, 0x42);
+        assert_eq!(mapper.read_prg_byte(0x6000), 0x42);
+        assert_eq!(mapper.prg_ram()[0x2000], 0x42);
+
+        // RAM can also be banked into $8000-$DFFF
+        mapper.write_prg_byte(0x5114, 0x01);
+        assert_eq!(mapper.read_prg_byte(0x8000), 0x42);
+        assert_eq!(mapper.prg_rom_offset(0x8000), None);
+    }
+
+    #[test]
+    fn test_chr_large_sprites() {
+        let mut mapper = Mapper5::new(build_cartridge_data());
+        mapper.write_prg_byte(0x5101, 3);
+        for i in 0..12 {
+            mapper.write_prg_byte(0x5120 + i, 20 + i as u8);
+        }
+        // Outside of rendering the last set written is used
+        assert_eq!(mapper.read_chr_byte(0x0000), 28);
+        assert_eq!(mapper.read_chr_byte(0x1C00), 31);
+
+        mapper.ppu_register_written(0x2000, 0x20);
+        mapper.fetch = Fetch::Sprite;
+        assert_eq!(mapper.read_chr_byte(0x0000), 20);
+        assert_eq!(mapper.read_chr_byte(0x1C00), 27);
+        mapper.fetch = Fetch::Background;
+        assert_eq!(mapper.read_chr_byte(0x1000), 28);
+
+        mapper.write_prg_byte(0x5101, 0);
+        mapper.fetch = Fetch::Sprite;
+        // 8kb bank 27 wraps to bank 3
+        assert_eq!(mapper.read_chr_byte(0x0400), 3 * 8 + 1);
+    }
+
+    #[test]
+    fn test_nametables() {
+        let mut mapper = Mapper5::new(build_cartridge_data());
+        let mut ciram = [0; 0x800];
+        mapper.write_prg_byte(0x5105, 0x44);
+        assert_eq!(mapper.mirroring(), Mirroring::Vertical);
+        mapper.write_nametable(0x2C05, 3, &mut ciram);
+        assert_eq!(ciram[0x405], 3);
+        assert_eq!(mapper.read_nametable(0x2405, &ciram), 3);
+
+        mapper.write_prg_byte(0x5105, 0xE4);
+        mapper.write_prg_byte(0x5106, 0x12);
+        mapper.write_prg_byte(0x5107, 2);
+        assert_eq!(mapper.read_nametable(0x2C00, &ciram), 0x12);
+        assert_eq!(mapper.read_nametable(0x2FC0, &ciram), 0xAA);
+
+        mapper.write_nametable(0x2805, 7, &mut ciram);
+        assert_eq!(mapper.read_nametable(0x2805, &ciram), 7);
+        assert_eq!(mapper.exram[5], 7);
+    }
+
+    #[test]
+    fn test_exram() {
+        let mut mapper = Mapper5::new(build_cartridge_data());
+        // ExRAM can't be written outside of rendering while it is a nametable
+        mapper.write_prg_byte(0x5C00, 5);
+        assert_eq!(mapper.exram[0], 0);
+        assert_eq!(mapper.read_prg_byte(0x5C00), 0x5C);
+
+        mapper.write_prg_byte(0x5104, 2);
+        mapper.write_prg_byte(0x5C00, 5);
+        assert_eq!(mapper.read_prg_byte(0x5C00), 5);
+        mapper.write_prg_byte(0x5104, 3);
+        mapper.write_prg_byte(0x5C00, 6);
+        assert_eq!(mapper.read_prg_byte(0x5C00), 5);
+    }
+
+    #[test]
+    fn test_extended_attributes() {
+        let mut mapper = Mapper5::new(build_cartridge_data());
+        let ciram = [0; 0x800];
+        mapper.write_prg_byte(0x5104, 1);
+        mapper.write_prg_byte(0x5130, 1);
+        mapper.exram[2] = 0xC3;
+
+        mapper.ppu_fetch(0x2002);
+        assert_eq!(mapper.read_nametable(0x23C0, &ciram), 0xFF);
+        // 4kb bank 0x43 wraps to bank 3, which is 1kb bank 12
+        assert_eq!(mapper.read_chr_byte(0x0010), 12);
+        assert_eq!(mapper.read_chr_byte(0x1010), 12);
+    }
+
+    #[test]
+    fn test_split() {
+        let mut mapper = Mapper5::new(build_cartridge_data());
+        let ciram = [0x33; 0x800];
+        mapper.write_prg_byte(0x5200, 0x84);
+        mapper.write_prg_byte(0x5202, 1);
+        mapper.exram[2] = 0x11;
+        mapper.exram[0x3C0] = 0b1100;
+
+        mapper.ppu_fetch(0x2002);
+        assert_eq!(mapper.read_nametable(0x2002, &ciram), 0x11);
+        assert_eq!(mapper.read_nametable(0x23C0, &ciram), 0xFF);
+        assert_eq!(mapper.read_chr_byte(0x0110), 4);
+
+        // Tile 6 is to the right of the split
+        mapper.next_fetch = 16;
+        mapper.ppu_fetch(0x2006);
+        assert_eq!(mapper.read_nametable(0x2006, &ciram), 0x33);
+        assert_eq!(mapper.read_chr_byte(0x0110), 0);
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mapper = Mapper5::new(build_cartridge_data());
+        mapper.write_prg_byte(0x5203, 2);
+        mapper.write_prg_byte(0x5204, 0x80);
+
+        // The pre-render scanline, then scanlines 0 and 1
+        mapper.ppu_fetch(0x2002);
+        for _ in 0..3 {
+            fetch_scanline(&mut mapper);
+        }
+        assert_eq!(mapper.peek_prg_byte(0x5204), 0x40);
+        assert!(!mapper.irq_flag());
+
+        fetch_scanline(&mut mapper);
+        assert!(mapper.irq_flag());
+        assert_eq!(mapper.read_prg_byte(0x5204), 0xC0);
+        assert!(!mapper.irq_flag());
+
+        // The frame ends once the PPU stops reading
+        for _ in 0..IDLE_CYCLES {
+            mapper.tick();
+        }
+        assert_eq!(mapper.peek_prg_byte(0x5204), 0x00);
+    }
+
+    #[test]
+    fn test_multiplier() {
+        let mut mapper = Mapper5::new(build_cartridge_data());
+        assert_eq!(mapper.read_prg_byte(0x5205), 0x01);
+        assert_eq!(mapper.read_prg_byte(0x5206), 0xFE);
+        mapper.write_prg_byte(0x5205, 200);
+        mapper.write_prg_byte(0x5206, 3);
+        assert_eq!(mapper.read_prg_byte(0x5205), 600u16 as u8);
+        assert_eq!(mapper.read_prg_byte(0x5206), 2);
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut mapper = Mapper5::new(build_cartridge_data());
+        mapper.write_prg_byte(0x5114, 0x83);
+        mapper.write_prg_byte(0x5104, 2);
+        mapper.write_prg_byte(0x5C10, 9);
+        mapper.ppu_fetch(0x2002);
+        let mut state = StateWriter::new();
+        mapper.save_state(&mut state);
+
+        let mut loaded = Mapper5::new(build_cartridge_data());
+        let data = state.data;
+        loaded.load_state(&mut StateReader::new(&data)).unwrap();
+        assert_eq!(loaded.read_prg_byte(0x8000), 3);
+        assert_eq!(loaded.read_prg_byte(0x5C10), 9);
+        assert_eq!(loaded.fetch, Fetch::Background);
+    }
+}
@@ -0,0 +1,202 @@
+// Mapper7 implements ines mapper 7 (AxROM)
+// https://wiki.nesdev.com/w/index.php/AxROM
+
+use super::pager::Page;
+use super::pager::PageSize;
+use super::CartridgeData;
+use super::Mapper;
+use super::Mirroring;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+pub struct Mapper7 {
+    data: CartridgeData,
+    prg_0: usize,
+    // Bit 4 of the bank register picks which 1KB of nametable RAM is shown
+    mirroring: Mirroring,
+}
+
+impl Mapper7 {
+    pub fn new(data: CartridgeData) -> Self {
+        Mapper7 {
+            data,
+            prg_0: 0,
+            mirroring: Mirroring::SingleScreenA,
+        }
+    }
+
+    fn prg_rom_page(&self) -> Page {
+        Page::Number(self.prg_0, PageSize::ThirtyTwoKb)
+    }
+}
+
+impl Mapper for Mapper7 {
+    fn read_prg_byte(&self, address: u16) -> u8 {
+        match address {
+            0x8000..=0xFFFF => self
+                .data
+                .prg_rom
+                .read(self.prg_rom_page(), address - 0x8000),
+            // There is no PRG-RAM, so nothing drives the bus
+            a => (a >> 8) as u8,
+        }
+    }
+
+    fn write_prg_byte(&mut self, address: u16, value: u8) {
+        if address < 0x8000 {
+            return;
+        }
+        let banks = (self.data.prg_rom.data.len() / PageSize::ThirtyTwoKb as usize).max(1);
+        self.prg_0 = (value as usize & 0x07) % banks;
+        self.mirroring = if value & 0x10 == 0 {
+            Mirroring::SingleScreenA
+        } else {
+            Mirroring::SingleScreenB
+        };
+    }
+
+    fn read_chr_byte(&self, address: u16) -> u8 {
+        if self.data.header.chr_rom_bytes() == 0 {
+            self.data
+                .chr_ram
+                .read(Page::First(PageSize::EightKb), address)
+        } else {
+            self.data
+                .chr_rom
+                .read(Page::First(PageSize::EightKb), address)
+        }
+    }
+
+    fn write_chr_byte(&mut self, address: u16, value: u8) {
+        if self.data.header.chr_rom_bytes() == 0 {
+            self.data
+                .chr_ram
+                .write(Page::First(PageSize::EightKb), address, value)
+        }
+    }
+
+    fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000..=0xFFFF => self
+                .data
+                .prg_rom
+                .offset(self.prg_rom_page(), address - 0x8000),
+            _ => None,
+        }
+    }
+
+    fn chr_rom_offset(&self, address: u16) -> Option<usize> {
+        self.data
+            .chr_rom
+            .offset(Page::First(PageSize::EightKb), address)
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.data.prg_ram.data
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.data.prg_ram.data
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+impl SaveState for Mapper7 {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.data.save_state(state);
+        state.write_usize(self.prg_0);
+        self.mirroring.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.data.load_state(state)?;
+        self.prg_0 = state.read_usize()?;
+        if !self.data.prg_rom.contains(self.prg_rom_page()) {
+            return Err(StateError::Invalid("AxROM PRG bank out of range"));
+        }
+        self.mirroring.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::{build_test_rom, Cartridge, RomError};
+
+    fn build_cartridge_data() -> CartridgeData {
+        // Four 32kb banks, each starting with its own number
+        let mut prg = vec![0xEA; 0x8000 * 4];
+        for bank in 0..4 {
+            prg[bank * 0x8000] = bank as u8;
+        }
+
+        CartridgeData::new(&build_test_rom(0x70, &prg, &[])).unwrap()
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        let mut mapper = Mapper7::new(build_cartridge_data());
+        assert_eq!(mapper.read_prg_byte(0x8000), 0);
+        mapper.write_prg_byte(0x8000, 2);
+        assert_eq!(mapper.read_prg_byte(0x8000), 2);
+        assert_eq!(mapper.prg_rom_offset(0xFFFF), Some(0x17FFF));
+        // Banks past the end of the ROM wrap around
+        mapper.write_prg_byte(0xFFFF, 7);
+        assert_eq!(mapper.read_prg_byte(0x8000), 3);
+    }
+
+    #[test]
+    fn test_partial_bank() {
+        // 48kb is one and a half 32kb banks
+        let data = build_test_rom(0x70, &[0xEA; 0x4000 * 3], &[]);
+        assert_eq!(
+            Some(RomError::InconsistentSize(
+                "PRG-ROM is not a whole number of the mapper's banks"
+            )),
+            Cartridge::new(&data).err()
+        );
+    }
+
+    #[test]
+    fn test_mirroring() {
+        let mut mapper = Mapper7::new(build_cartridge_data());
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenA);
+        mapper.write_prg_byte(0x8000, 0x11);
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenB);
+        assert_eq!(mapper.read_prg_byte(0x8000), 1);
+        mapper.write_prg_byte(0x8000, 0x01);
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenA);
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let mut mapper = Mapper7::new(build_cartridge_data());
+        mapper.write_chr_byte(0x1234, 0x56);
+        assert_eq!(mapper.read_chr_byte(0x1234), 0x56);
+        assert_eq!(mapper.chr_rom_offset(0x1234), None);
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut mapper = Mapper7::new(build_cartridge_data());
+        mapper.write_prg_byte(0x8000, 0x13);
+        let mut state = StateWriter::new();
+        mapper.save_state(&mut state);
+
+        let mut loaded = Mapper7::new(build_cartridge_data());
+        let data = state.data;
+        loaded.load_state(&mut StateReader::new(&data)).unwrap();
+        assert_eq!(loaded.read_prg_byte(0x8000), 3);
+        assert_eq!(loaded.mirroring(), Mirroring::SingleScreenB);
+
+        mapper.prg_0 = 4;
+        let mut state = StateWriter::new();
+        mapper.save_state(&mut state);
+        assert!(matches!(
+            loaded.load_state(&mut StateReader::new(&state.data)),
+            Err(StateError::Invalid(_))
+        ));
+    }
+}
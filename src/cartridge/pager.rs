@@ -1,9 +1,11 @@
 #[derive(Copy, Clone, Debug)]
 pub enum PageSize {
     OneKb = 0x400,
+    TwoKb = 0x800,
     FourKb = 0x1000,
     EightKb = 0x2000,
     SixteenKb = 0x4000,
+    ThirtyTwoKb = 0x8000,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -23,16 +25,43 @@ impl Pager {
         Pager { data }
     }
 
+    // An empty pager reads as zero and ignores writes, which is how
+    // carts without PRG-RAM behave closely enough.
     pub fn read(&self, page: Page, offset: u16) -> u8 {
+        if self.data.is_empty() {
+            return 0;
+        }
         let i = self.index(page, offset);
         self.data[i]
     }
 
     pub fn write(&mut self, page: Page, offset: u16, value: u8) {
+        if self.data.is_empty() {
+            return;
+        }
         let i = self.index(page, offset);
         self.data[i] = value;
     }
 
+    // Where a paged address lands in the underlying data, if there is any
+    pub fn offset(&self, page: Page, offset: u16) -> Option<usize> {
+        if self.data.is_empty() {
+            return None;
+        }
+        Some(self.index(page, offset))
+    }
+
+    // Whether reading or writing through a page stays inside the data
+    pub fn contains(&self, page: Page) -> bool {
+        if self.data.len() < size(page) {
+            return true;
+        }
+        match page {
+            Page::Number(n, size) | Page::FromEnd(n, size) => n < self.page_count(size),
+            Page::First(_) | Page::Last(_) => true,
+        }
+    }
+
     fn page_count(&self, size: PageSize) -> usize {
         if self.data.len() % (size as usize) != 0 {
             panic!("Page size must divide evenly into data length")
@@ -43,6 +72,13 @@ impl Pager {
 
     fn index(&self, page: Page, offset: u16) -> usize {
         match page {
+            // Memory smaller than a single page is mirrored across it
+            _ if self.data.len() < size(page) => {
+                if (offset as usize) >= size(page) {
+                    panic!("Offset cannot exceed page bounds")
+                }
+                offset as usize % self.data.len()
+            }
             Page::First(size) => self.index(Page::Number(0, size), offset),
             Page::Last(size) => {
                 let last_page = self.page_count(size) - 1;
@@ -66,6 +102,13 @@ impl Pager {
     }
 }
 
+fn size(page: Page) -> usize {
+    match page {
+        Page::First(size) | Page::Last(size) => size as usize,
+        Page::Number(_, size) | Page::FromEnd(_, size) => size as usize,
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -86,6 +129,17 @@ mod test {
         assert_eq!(16, pager.page_count(PageSize::FourKb));
     }
 
+    #[test]
+    fn test_contains() {
+        let pager = build_pager();
+        assert!(pager.contains(Page::Number(3, PageSize::SixteenKb)));
+        assert!(!pager.contains(Page::Number(4, PageSize::SixteenKb)));
+        assert!(!pager.contains(Page::FromEnd(4, PageSize::SixteenKb)));
+        // Memory smaller than a page mirrors whatever bank is asked for
+        let small = Pager::new(vec![0; 0x800]);
+        assert!(small.contains(Page::Number(5, PageSize::EightKb)));
+    }
+
     #[test]
     fn test_index_first() {
         let pager = build_pager();
@@ -138,4 +192,26 @@ mod test {
             pager.read(Page::Last(PageSize::SixteenKb), 0x1000 * 3 + 5)
         );
     }
+
+    #[test]
+    fn test_small_data_mirrors() {
+        let mut pager = Pager::new(vec![0; 0x800]);
+        pager.write(Page::First(PageSize::EightKb), 5, 0x66);
+        assert_eq!(0x66, pager.read(Page::First(PageSize::EightKb), 0x805));
+        assert_eq!(0x66, pager.read(Page::Last(PageSize::EightKb), 0x1805));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_small_data_overflow() {
+        let pager = Pager::new(vec![0; 0x800]);
+        pager.index(Page::First(PageSize::EightKb), PageSize::EightKb as u16);
+    }
+
+    #[test]
+    fn test_empty() {
+        let mut pager = Pager::new(vec![]);
+        pager.write(Page::First(PageSize::EightKb), 5, 0x66);
+        assert_eq!(0, pager.read(Page::First(PageSize::EightKb), 5));
+    }
 }
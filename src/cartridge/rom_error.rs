@@ -0,0 +1,43 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RomError {
+    TruncatedHeader(usize),
+    BadMagic([u8; 4]),
+    TruncatedTrainer,
+    TruncatedPrg { expected: usize, actual: usize },
+    TruncatedChr { expected: usize, actual: usize },
+    UnsupportedMapper(u16),
+    InconsistentSize(&'static str),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::TruncatedHeader(n) => {
+                write!(f, "file is {} bytes, too short for a 16 byte iNES header", n)
+            }
+            RomError::BadMagic(m) => write!(
+                f,
+                "not an iNES file (expected magic 4E 45 53 1A, found {:02X} {:02X} {:02X} {:02X})",
+                m[0], m[1], m[2], m[3]
+            ),
+            RomError::TruncatedTrainer => write!(f, "trainer is truncated"),
+            RomError::TruncatedPrg { expected, actual } => write!(
+                f,
+                "PRG-ROM is truncated (expected {} bytes, found {})",
+                expected, actual
+            ),
+            RomError::TruncatedChr { expected, actual } => write!(
+                f,
+                "CHR-ROM is truncated (expected {} bytes, found {})",
+                expected, actual
+            ),
+            RomError::UnsupportedMapper(n) => write!(f, "mapper {} is not supported", n),
+            RomError::InconsistentSize(reason) => write!(f, "inconsistent ROM sizes: {}", reason),
+        }
+    }
+}
+
+impl Error for RomError {}
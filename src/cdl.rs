@@ -0,0 +1,136 @@
+// Code/Data Logger: one byte of flags for every byte of PRG and CHR ROM,
+// recording how the game used it. The file format is FCEUX's .cdl, which is
+// the PRG flags followed by the CHR flags, so logs can be shared with its
+// tools and merged across play sessions.
+
+use std::error::Error;
+use std::fmt;
+
+// PRG-ROM flags
+pub const CODE: u8 = 0x01;
+pub const DATA: u8 = 0x02;
+pub const PCM: u8 = 0x40;
+
+// CHR-ROM flags
+pub const RENDERED: u8 = 0x01;
+pub const READ: u8 = 0x02;
+
+#[derive(Debug, PartialEq)]
+pub enum CdlError {
+    WrongSize { expected: usize, actual: usize },
+}
+
+impl fmt::Display for CdlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CdlError::WrongSize { expected, actual } => write!(
+                f,
+                "code/data log is {} bytes but this ROM needs {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl Error for CdlError {}
+
+pub struct CodeDataLog {
+    pub prg: Vec<u8>,
+    pub chr: Vec<u8>,
+}
+
+impl CodeDataLog {
+    pub fn new(prg_rom_bytes: usize, chr_rom_bytes: usize) -> Self {
+        CodeDataLog {
+            prg: vec![0; prg_rom_bytes],
+            chr: vec![0; chr_rom_bytes],
+        }
+    }
+
+    // Code and data reads also record which 8KB window of $8000-$FFFF the
+    // byte was seen through, in bits 2-3, like FCEUX does
+    pub fn log_prg(&mut self, offset: usize, address: u16, flags: u8) {
+        let window = if flags & (CODE | DATA) != 0 {
+            ((address >> 13) & 3) as u8
+        } else {
+            0
+        };
+        if let Some(f) = self.prg.get_mut(offset) {
+            *f |= flags | window << 2;
+        }
+    }
+
+    pub fn log_chr(&mut self, offset: usize, flags: u8) {
+        if let Some(f) = self.chr.get_mut(offset) {
+            *f |= flags;
+        }
+    }
+
+    // Adds the flags from a previously saved log to this one
+    pub fn merge(&mut self, data: &[u8]) -> Result<(), CdlError> {
+        let expected = self.prg.len() + self.chr.len();
+        if data.len() != expected {
+            return Err(CdlError::WrongSize {
+                expected,
+                actual: data.len(),
+            });
+        }
+        let (prg, chr) = data.split_at(self.prg.len());
+        for (f, saved) in self.prg.iter_mut().zip(prg) {
+            *f |= saved;
+        }
+        for (f, saved) in self.chr.iter_mut().zip(chr) {
+            *f |= saved;
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.prg.clone();
+        data.extend_from_slice(&self.chr);
+        data
+    }
+
+    // How many PRG bytes have any of the given flags
+    pub fn prg_count(&self, flags: u8) -> usize {
+        self.prg.iter().filter(|&&f| f & flags != 0).count()
+    }
+
+    pub fn chr_count(&self, flags: u8) -> usize {
+        self.chr.iter().filter(|&&f| f & flags != 0).count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_log_prg() {
+        let mut cdl = CodeDataLog::new(0x8000, 0);
+        cdl.log_prg(0x10, 0xE010, CODE);
+        cdl.log_prg(0x10, 0xE010, DATA);
+        cdl.log_prg(0x20, 0x8020, PCM);
+        cdl.log_prg(0x8000, 0x8000, CODE);
+        assert_eq!(cdl.prg[0x10], 0b0000_1111);
+        assert_eq!(cdl.prg[0x20], PCM);
+        assert_eq!(cdl.prg_count(CODE), 1);
+        assert_eq!(cdl.prg_count(CODE | PCM), 2);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut cdl = CodeDataLog::new(4, 2);
+        cdl.log_prg(0, 0x8000, CODE);
+        cdl.log_chr(1, RENDERED);
+        cdl.merge(&[DATA, 0, 0, 0, READ, 0]).unwrap();
+        assert_eq!(cdl.to_bytes(), vec![CODE | DATA, 0, 0, 0, READ, RENDERED]);
+        assert_eq!(
+            cdl.merge(&[0; 4]),
+            Err(CdlError::WrongSize {
+                expected: 6,
+                actual: 4
+            })
+        );
+    }
+}
@@ -0,0 +1,286 @@
+// Cheat codes, applied by the Bus. Game Genie codes patch what the CPU reads
+// from PRG-ROM, and raw RAM codes (like a Pro Action Replay) keep writing a
+// value to RAM once per frame.
+//
+// A cheat file has one code per line followed by an optional name. Lines
+// starting with # are comments, and a code starting with - is loaded but
+// switched off:
+//
+//     SXIOPO Infinite lives
+//     -0075:09 Always big
+
+use std::error::Error;
+use std::fmt;
+
+const GAME_GENIE_LETTERS: &str = "APZLGITYEOXUKSVN";
+
+#[derive(Debug, PartialEq)]
+pub enum CheatError {
+    BadCode(String),
+    BadLine(usize),
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheatError::BadCode(code) => write!(f, "'{}' is not a cheat code", code),
+            CheatError::BadLine(n) => write!(f, "bad cheat on line {}", n),
+        }
+    }
+}
+
+impl Error for CheatError {}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Effect {
+    // Reads of `address` return `value`, but only while the ROM there holds
+    // `compare` when the code has one
+    Rom {
+        address: u16,
+        value: u8,
+        compare: Option<u8>,
+    },
+    // `value` is written to `address` every frame
+    Ram {
+        address: u16,
+        value: u8,
+    },
+}
+
+impl Effect {
+    pub fn decode(code: &str) -> Result<Self, CheatError> {
+        let bad_code = || CheatError::BadCode(code.to_string());
+        let code = code.to_uppercase();
+        if let Some((address, value)) = code.split_once(':') {
+            let address = u16::from_str_radix(address, 16).map_err(|_| bad_code())?;
+            let value = u8::from_str_radix(value, 16).map_err(|_| bad_code())?;
+            return match address {
+                0x0000..=0x1FFF | 0x6000..=0x7FFF => Ok(Effect::Ram { address, value }),
+                _ => Err(bad_code()),
+            };
+        }
+
+        let n: Vec<u16> = code
+            .chars()
+            .map(|c| GAME_GENIE_LETTERS.find(c).map(|i| i as u16))
+            .collect::<Option<_>>()
+            .ok_or_else(bad_code)?;
+        if n.len() != 6 && n.len() != 8 {
+            return Err(bad_code());
+        }
+        let address = 0x8000
+            | (n[3] & 7) << 12
+            | (n[5] & 7) << 8
+            | (n[4] & 8) << 8
+            | (n[2] & 7) << 4
+            | (n[1] & 8) << 4
+            | (n[4] & 7)
+            | (n[3] & 8);
+        let value = |last: u16| ((n[1] & 7) << 4 | (n[0] & 8) << 4 | (n[0] & 7) | (last & 8)) as u8;
+        if n.len() == 6 {
+            return Ok(Effect::Rom {
+                address,
+                value: value(n[5]),
+                compare: None,
+            });
+        }
+        let compare = (n[7] & 7) << 4 | (n[6] & 8) << 4 | (n[6] & 7) | (n[5] & 8);
+        Ok(Effect::Rom {
+            address,
+            value: value(n[7]),
+            compare: Some(compare as u8),
+        })
+    }
+}
+
+pub struct Cheat {
+    pub code: String,
+    pub name: String,
+    pub effect: Effect,
+    pub enabled: bool,
+}
+
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+    active: bool,
+    // The effects of enabled cheats, kept apart so reads stay quick
+    rom: Vec<(u16, u8, Option<u8>)>,
+    ram: Vec<(u16, u8)>,
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Cheats {
+            cheats: Vec::new(),
+            active: true,
+            rom: Vec::new(),
+            ram: Vec::new(),
+        }
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty()
+    }
+
+    pub fn add(&mut self, code: &str, name: &str) -> Result<(), CheatError> {
+        self.cheats.push(Cheat {
+            code: code.to_uppercase(),
+            name: name.to_string(),
+            effect: Effect::decode(code)?,
+            enabled: true,
+        });
+        self.update();
+        Ok(())
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Cheat> {
+        if index >= self.cheats.len() {
+            return None;
+        }
+        let cheat = self.cheats.remove(index);
+        self.update();
+        Some(cheat)
+    }
+
+    // Returns false if there is no cheat at `index`
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        match self.cheats.get_mut(index) {
+            Some(cheat) => cheat.enabled = enabled,
+            None => return false,
+        }
+        self.update();
+        true
+    }
+
+    // Switches all cheats off or back on, leaving each one's own setting alone
+    pub fn set_active(&mut self, active: bool) {
+        self.active = active;
+        self.update();
+    }
+
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    pub fn parse(&mut self, text: &str) -> Result<(), CheatError> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (code, name) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let (code, enabled) = match code.strip_prefix('-') {
+                Some(code) => (code, false),
+                None => (code, true),
+            };
+            self.add(code, name.trim())
+                .map_err(|_| CheatError::BadLine(i + 1))?;
+            self.cheats.last_mut().unwrap().enabled = enabled;
+        }
+        self.update();
+        Ok(())
+    }
+
+    // What a CPU read of PRG-ROM returns once Game Genie codes are applied
+    pub fn read_prg_byte(&self, address: u16, value: u8) -> u8 {
+        for &(a, v, compare) in &self.rom {
+            if a == address && compare.is_none_or(|c| c == value) {
+                return v;
+            }
+        }
+        value
+    }
+
+    // The RAM writes to make each frame
+    pub fn ram_writes(&self) -> &[(u16, u8)] {
+        &self.ram
+    }
+
+    fn update(&mut self) {
+        self.rom.clear();
+        self.ram.clear();
+        if !self.active {
+            return;
+        }
+        for cheat in self.cheats.iter().filter(|c| c.enabled) {
+            match cheat.effect {
+                Effect::Rom {
+                    address,
+                    value,
+                    compare,
+                } => self.rom.push((address, value, compare)),
+                Effect::Ram { address, value } => self.ram.push((address, value)),
+            }
+        }
+    }
+}
+
+impl Default for Cheats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            Effect::decode("GOSSIP"),
+            Ok(Effect::Rom {
+                address: 0xD1DD,
+                value: 0x14,
+                compare: None
+            })
+        );
+        assert_eq!(
+            Effect::decode("zexpyguo"),
+            Ok(Effect::Rom {
+                address: 0x94A7,
+                value: 0x0A,
+                compare: Some(0x93)
+            })
+        );
+        assert_eq!(
+            Effect::decode("0075:09"),
+            Ok(Effect::Ram {
+                address: 0x0075,
+                value: 0x09
+            })
+        );
+        assert!(Effect::decode("GOSSI").is_err());
+        assert!(Effect::decode("8000:01").is_err());
+        assert!(Effect::decode("0075:100").is_err());
+    }
+
+    #[test]
+    fn test_parse_and_toggle() {
+        let mut cheats = Cheats::new();
+        cheats
+            .parse("# Lives\nGOSSIP Infinite lives\n-0075:09 Always big\n\nZEXPYGUO\n")
+            .unwrap();
+        assert_eq!(cheats.cheats().len(), 3);
+        assert_eq!(cheats.cheats()[0].name, "Infinite lives");
+        assert_eq!(cheats.read_prg_byte(0xD1DD, 0x00), 0x14);
+        assert_eq!(cheats.read_prg_byte(0x94A7, 0x93), 0x0A);
+        assert_eq!(cheats.read_prg_byte(0x94A7, 0x94), 0x94);
+        assert!(cheats.ram_writes().is_empty());
+
+        cheats.set_enabled(1, true);
+        assert_eq!(cheats.ram_writes(), &[(0x0075, 0x09)]);
+        cheats.set_active(false);
+        assert_eq!(cheats.read_prg_byte(0xD1DD, 0x00), 0x00);
+        assert!(cheats.ram_writes().is_empty());
+
+        assert_eq!(
+            cheats.parse("GOSSIP\nNOTACODE"),
+            Err(CheatError::BadLine(2))
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Button {
     A = 0b0000_0001,
@@ -16,6 +18,12 @@ pub struct Controller {
     cursor: usize,
 }
 
+impl Default for Controller {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Controller {
     pub fn new() -> Self {
         Controller {
@@ -33,16 +41,20 @@ impl Controller {
     }
 
     pub fn read_register(&mut self) -> u8 {
+        let result = self.peek_register();
+        if !self.strobe && self.cursor < 8 {
+            self.cursor += 1;
+        }
+        result
+    }
+
+    // What the next read would return, without shifting to the next button
+    pub fn peek_register(&self) -> u8 {
         let v = if self.cursor < 8 {
             self.button_states >> self.cursor & 1
         } else {
             1
         };
-
-        if !self.strobe {
-            self.cursor += 1;
-        }
-
         0x40 | v
     }
 
@@ -52,4 +64,30 @@ impl Controller {
             self.button_states |= button as u8;
         }
     }
+
+    pub fn set_button_states(&mut self, buttons: u8) {
+        self.button_states = buttons;
+    }
+
+    pub fn button_states(&self) -> u8 {
+        self.button_states
+    }
+}
+
+impl SaveState for Controller {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.button_states);
+        state.write_bool(self.strobe);
+        state.write_usize(self.cursor);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.button_states = state.read_u8()?;
+        self.strobe = state.read_bool()?;
+        self.cursor = state.read_usize()?;
+        if self.cursor > 8 {
+            return Err(StateError::Invalid("controller cursor out of range"));
+        }
+        Ok(())
+    }
 }
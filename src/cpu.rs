@@ -1,7 +1,7 @@
 use crate::bus::Bus;
-use std::fmt::Write;
 
-use crate::cpu_debug::{INSTRUCTION_NAMES, INSTRUCTION_SIZES};
+use crate::disassembler::{self, Instruction, Mode as OperandMode};
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 #[cfg_attr(rustfmt, rustfmt_skip)]
 enum Flag {
@@ -42,6 +42,17 @@ enum Interrupt {
     Break,
 }
 
+// A copy of the programmer-visible registers, for debuggers
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CpuRegisters {
+    pub pc: u16,
+    pub sp: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+}
+
 pub struct Cpu {
     pub bus: Bus,
     pc: u16,
@@ -65,6 +76,26 @@ impl Cpu {
         }
     }
 
+    pub fn registers(&self) -> CpuRegisters {
+        CpuRegisters {
+            pc: self.pc,
+            sp: self.sp,
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            p: self.p,
+        }
+    }
+
+    pub fn set_registers(&mut self, registers: CpuRegisters) {
+        self.pc = registers.pc;
+        self.sp = registers.sp;
+        self.a = registers.a;
+        self.x = registers.x;
+        self.y = registers.y;
+        self.p = registers.p;
+    }
+
     pub fn reset(&mut self) {
         self.sp = 0xFF;
         self.p = 0x34;
@@ -100,14 +131,14 @@ impl Cpu {
     fn next_byte(&mut self) -> u8 {
         let original_pc = self.pc;
         self.increment_pc();
-        self.bus.read_byte(original_pc)
+        self.bus.read_code_byte(original_pc)
     }
 
     fn next_word(&mut self) -> u16 {
         let original_pc = self.pc;
         self.increment_pc();
         self.increment_pc();
-        self.bus.read_word(original_pc)
+        self.bus.read_code_word(original_pc)
     }
 
     // Flags
@@ -210,7 +241,11 @@ impl Cpu {
 
     fn read_operand(&mut self, mode: Mode) -> u8 {
         let address = self.operand_address(mode);
-        self.bus.read_byte(address)
+        if let Mode::Immediate = mode {
+            self.bus.read_code_byte(address)
+        } else {
+            self.bus.read_byte(address)
+        }
     }
 
     fn interrupt(&mut self, kind: Interrupt) {
@@ -246,26 +281,79 @@ impl Cpu {
 
     #[allow(dead_code)]
     pub fn log_next_instruction(&mut self) {
+        println!("{}", self.trace());
+    }
+
+    // The next instruction and the machine state before it runs, in the
+    // Nintendulator format used by nestest.log:
+    // C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+    // With symbols loaded, operands use labels and a label at PC gets a line of its own.
+    pub fn trace(&mut self) -> String {
         let pc = self.pc;
-        let rom_offset = 15 + (self.pc % 0x4000);
-        let opcode = self.bus.unclocked_read_byte(pc) as usize;
-        let mut args = String::new();
-        for i in 1..INSTRUCTION_SIZES[opcode] {
-            write!(&mut args, "{:02X} ", self.bus.unclocked_read_byte(pc + i)).expect("it to work");
-        }
-        println!(
-            "OFFSET:{:06x}\tPC:{:04x}\tA:{:02x}\tX:{:02x}\tY:{:02x}\tP:{:08b}\tTEST:{:02x}\t[{:02x}] {}\t{}",
-            rom_offset,
+        let bus = &self.bus;
+        let mut instruction = disassembler::decode(pc, |a| trace_read(bus, a));
+        instruction.resolve(self.x, self.y, |a| trace_read(bus, a));
+        let bytes: Vec<String> = instruction.bytes().iter().map(|b| format!("{:02X}", b)).collect();
+
+        // nestest spells ISC as ISB
+        let mnemonic = match instruction.mnemonic {
+            "ISC" => "ISB",
+            m => m,
+        };
+        let unofficial = if instruction.unofficial { '*' } else { ' ' };
+        let operand = self.trace_operand(&instruction);
+        let text = if operand.is_empty() {
+            mnemonic.to_string()
+        } else {
+            format!("{} {}", mnemonic, operand)
+        };
+
+        let heading = self.bus.symbol(pc).map(|s| s.heading()).unwrap_or_default();
+        let renderer = &self.bus.ppu.renderer;
+        format!(
+            "{}{:04X}  {:<8} {}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+            heading,
             pc,
+            bytes.join(" "),
+            unofficial,
+            text,
             self.a,
             self.x,
             self.y,
             self.p,
-            self.bus.unclocked_read_byte(0x6000),
-            opcode,
-            INSTRUCTION_NAMES[opcode as usize],
-            args,
-        );
+            self.sp,
+            renderer.scanline,
+            renderer.dot,
+            self.bus.cycles,
+        )
+    }
+
+    // The operand plus the addresses and values it touches, as nestest.log shows them
+    fn trace_operand(&mut self, instruction: &Instruction) -> String {
+        let operand = instruction.operand_text(|a| self.bus.label(a));
+        let address = instruction.effective_address.unwrap_or(0);
+        let value = trace_read(&self.bus, address);
+        match instruction.mode {
+            OperandMode::ZeroPage => format!("{} = {:02X}", operand, value),
+            OperandMode::ZeroPageX | OperandMode::ZeroPageY => {
+                format!("{} @ {:02X} = {:02X}", operand, address, value)
+            }
+            OperandMode::Absolute if instruction.branch_target.is_some() => operand,
+            OperandMode::Absolute => format!("{} = {:02X}", operand, value),
+            OperandMode::AbsoluteX | OperandMode::AbsoluteY => {
+                format!("{} @ {:04X} = {:02X}", operand, address, value)
+            }
+            OperandMode::Indirect => format!("{} = {:04X}", operand, address),
+            OperandMode::IndirectX => {
+                let pointer = (instruction.operand as u8).wrapping_add(self.x);
+                format!("{} @ {:02X} = {:04X} = {:02X}", operand, pointer, address, value)
+            }
+            OperandMode::IndirectY => {
+                let base = address.wrapping_sub(self.y as u16);
+                format!("{} = {:04X} @ {:04X} = {:02X}", operand, base, address, value)
+            }
+            _ => operand,
+        }
     }
 
     pub fn execute_next_instruction(&mut self) {
@@ -1178,6 +1266,15 @@ impl Cpu {
     }
 }
 
+// nestest.log shows the PPU, APU and controller registers as $FF, whatever
+// peeking at them would find
+fn trace_read(bus: &Bus, address: u16) -> u8 {
+    match address {
+        0x2000..=0x401F => 0xFF,
+        _ => bus.peek(address),
+    }
+}
+
 fn cross(base: u16, offset: u8) -> bool {
     high_byte(base + offset as u16) != high_byte(base)
 }
@@ -1198,5 +1295,27 @@ fn high_byte(value: u16) -> u16 {
 //     (low as u16) | (high as u16) << 8
 // }
 
+impl SaveState for Cpu {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.pc);
+        state.write_u8(self.sp);
+        state.write_u8(self.a);
+        state.write_u8(self.x);
+        state.write_u8(self.y);
+        state.write_u8(self.p);
+        self.bus.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.pc = state.read_u16()?;
+        self.sp = state.read_u8()?;
+        self.a = state.read_u8()?;
+        self.x = state.read_u8()?;
+        self.y = state.read_u8()?;
+        self.p = state.read_u8()?;
+        self.bus.load_state(state)
+    }
+}
+
 #[cfg(test)]
 mod test;
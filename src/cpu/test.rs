@@ -2,6 +2,7 @@ use super::Mode::*;
 use super::*;
 use crate::apu::Apu;
 
+use crate::cartridge::build_test_rom;
 use crate::cpu::Cpu;
 use crate::ppu::Ppu;
 
@@ -18,7 +19,7 @@ macro_rules! build_cpu {
         // add the PRG-ROM
         rom.extend_from_slice(&[0u8; 2 * 0x4000]);
         let mut bus = Bus::new();
-        bus.load_rom_from_memory(&rom);
+        bus.load_rom_from_memory(&rom).unwrap();
         let mut cpu = Cpu::new(bus);
         cpu.pc = 0;
         let bytes = $bytes;
@@ -550,6 +551,108 @@ fn test_php_plp() {
     assert_eq!(cpu.bus.cycles, 3); // Really 4 once you add an opcode read.
 }
 
+#[test]
+fn test_trace() {
+    let mut cpu = build_cpu!([0x4c, 0xf5, 0xc5]);
+    assert_eq!(
+        cpu.trace(),
+        "0000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:00 SP:00 PPU:  0,  0 CYC:0"
+    );
+
+    let trace = |bytes: Vec<u8>, x: u8, y: u8| {
+        let mut cpu = build_cpu!(bytes);
+        cpu.x = x;
+        cpu.y = y;
+        cpu.trace()[..48].trim_end().to_string()
+    };
+    assert_eq!(trace(vec![0x4a], 0, 0), "0000  4A        LSR A");
+    assert_eq!(trace(vec![0xa9, 0x7f], 0, 0), "0000  A9 7F     LDA #$7F");
+    assert_eq!(trace(vec![0xb5, 0x10], 5, 0), "0000  B5 10     LDA $10,X @ 15 = 00");
+    assert_eq!(trace(vec![0x04, 0x01], 0, 0), "0000  04 01    *NOP $01 = 01");
+    assert_eq!(
+        trace(vec![0xb1, 0x02], 0, 1),
+        "0000  B1 02     LDA ($02),Y = 0000 @ 0001 = 02"
+    );
+    assert_eq!(
+        trace(vec![0xa1, 0x01], 1, 0),
+        "0000  A1 01     LDA ($01,X) @ 02 = 0000 = A1"
+    );
+    assert_eq!(trace(vec![0xf0, 0xfe], 0, 0), "0000  F0 FE     BEQ $0000");
+    assert_eq!(trace(vec![0xe7, 0x00], 0, 0), "0000  E7 00    *ISB $00 = E7");
+    assert_eq!(trace(vec![0xad, 0x02, 0x20], 0, 0), "0000  AD 02 20  LDA $2002 = FF");
+}
+
+#[test]
+fn test_trace_symbols() {
+    let mut cpu = build_cpu!([0xa5, 0x10]);
+    cpu.bus
+        .symbols
+        .parse_nl("$0000#start#Entry point\n$0010#counter#\n", None)
+        .unwrap();
+    assert_eq!(
+        cpu.trace()[..53].to_string(),
+        "; Entry point\nstart:\n0000  A5 10     LDA counter = 00"
+    );
+}
+
+// Puts the CPU in the state nestest.log starts from
+fn nestest_cpu(rom: &[u8]) -> Cpu {
+    let mut bus = Bus::new();
+    bus.load_rom_from_memory(rom).unwrap();
+    let mut cpu = Cpu::new(bus);
+    cpu.reset();
+    // Automated mode starts at $C000 instead of the reset vector, and the log
+    // begins 7 cycles in, once the reset sequence has run
+    cpu.pc = 0xC000;
+    cpu.sp = 0xFD;
+    cpu.p = 0x24;
+    cpu.bus.cycles = 7;
+    cpu.bus.ppu.renderer.scanline = 0;
+    cpu.bus.ppu.renderer.dot = 21;
+    cpu
+}
+
+#[test]
+fn test_nestest_start() {
+    let mut prg = vec![0u8; 0x4000];
+    prg[0..3].copy_from_slice(&[0x4c, 0xf5, 0xc5]);
+    let mut cpu = nestest_cpu(&build_test_rom(0, &prg, &[0; 0x2000]));
+    assert_eq!(
+        cpu.trace(),
+        "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7"
+    );
+}
+
+// nestest.nes and its golden log are checked against whenever they are in
+// tests/nestest/, the test passes with a note otherwise
+#[test]
+fn test_nestest_log() {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/nestest");
+    let (rom, log) = match (
+        std::fs::read(dir.join("nestest.nes")),
+        std::fs::read_to_string(dir.join("nestest.log")),
+    ) {
+        (Ok(rom), Ok(log)) => (rom, log),
+        _ => {
+            eprintln!("skipping, nestest.nes and nestest.log aren't in {}", dir.display());
+            return;
+        }
+    };
+
+    let mut cpu = nestest_cpu(&rom);
+    for (n, expected) in log.lines().enumerate() {
+        let actual = cpu.trace();
+        assert!(
+            actual == expected.trim_end(),
+            "nestest diverges on line {}\nexpected: {}\nactual:   {}",
+            n + 1,
+            expected.trim_end(),
+            actual
+        );
+        cpu.execute_next_instruction();
+    }
+}
+
 #[derive(Debug)]
 struct Op {
     code: u8,
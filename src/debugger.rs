@@ -0,0 +1,568 @@
+// A command-driven CPU debugger. The frontend feeds it lines from stdin while
+// it is paused and lets it run frames otherwise. It stops on execution
+// breakpoints, read and write watchpoints on the Bus, or when a step finishes.
+
+use crate::bus::Access;
+use crate::cheats::Cheats;
+use crate::cpu::CpuRegisters;
+use crate::disassembler;
+use crate::nes::Nes;
+use crate::ram_search::{Condition, RamSearch, View};
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt::Write;
+
+const HISTORY_LENGTH: usize = 8;
+
+pub const HELP: &str = "Commands (numbers are hex, with an optional $ or 0x):
+    c, continue           Run until a breakpoint
+    s, step [N]           Run N instructions (default: 1)
+    sl, scanline          Run to the start of the next scanline
+    f, frame              Run to the end of the frame
+    b, break <ADDR>       Break when ADDR is executed
+    rb <ADDR>             Break when ADDR is read
+    wb <ADDR>             Break when ADDR is written
+    bl                    List breakpoints
+    bd <ADDR|all>         Delete breakpoints at ADDR, or all of them
+    r, regs [REG VALUE]   Show registers, or set one of a, x, y, p, sp, pc
+    m, mem <ADDR> [LEN]   Dump memory (default: 64 bytes)
+    d, disasm [ADDR] [N]  Disassemble N instructions from ADDR (default: around PC)
+    ch, cheat             List cheats
+    ch add <CODE> [NAME]  Add a Game Genie or AAAA:VV RAM cheat
+    ch on|off <N|all>     Switch cheat N, or all cheats, on or off
+    ch del <N>            Delete cheat N
+    rs new [VIEW]         Start a RAM search as u8 (default), s8, u16 or s16
+    rs same|changed|up|down
+                          Keep RAM search candidates that changed that way
+    rs = <N>              Keep candidates equal to N, in decimal
+    rs                    List RAM search candidates
+    q, quit               Exit the emulator";
+
+pub struct Debugger {
+    pub paused: bool,
+    breakpoints: BTreeSet<u16>,
+    // Recently executed addresses, oldest first, so disassembly can show what led to PC
+    history: VecDeque<u16>,
+    // Set by continue, so that we don't stop on the breakpoint we are sitting on
+    resume: bool,
+    search: Option<RamSearch>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            paused: true,
+            breakpoints: BTreeSet::new(),
+            history: VecDeque::with_capacity(HISTORY_LENGTH),
+            resume: false,
+            search: None,
+        }
+    }
+
+    // Runs the rest of the frame unless a breakpoint is hit, in which case it
+    // pauses and returns why
+    pub fn run_frame(&mut self, nes: &mut Nes) -> Option<String> {
+        loop {
+            let pc = nes.cpu.registers().pc;
+            if !self.resume && self.breakpoints.contains(&pc) {
+                self.paused = true;
+                return Some(format!("Breakpoint at ${:04X}", pc));
+            }
+            self.resume = false;
+
+            let frame_done = self.step(nes);
+            if let Some(reason) = self.watchpoint_hit(nes) {
+                self.paused = true;
+                return Some(reason);
+            }
+            if frame_done {
+                return None;
+            }
+        }
+    }
+
+    fn step(&mut self, nes: &mut Nes) -> bool {
+        if self.history.len() == HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+        self.history.push_back(nes.cpu.registers().pc);
+        nes.step_instruction()
+    }
+
+    fn watchpoint_hit(&mut self, nes: &mut Nes) -> Option<String> {
+        nes.cpu
+            .bus
+            .watchpoints
+            .hit
+            .take()
+            .map(|(access, address, value)| {
+                let verb = match access {
+                    Access::Read => "Read",
+                    Access::Write => "Write",
+                };
+                format!("{} of ${:02X} at ${:04X}", verb, value, address)
+            })
+    }
+
+    // Runs one command and returns what to print. Quitting is up to the caller.
+    pub fn command(&mut self, nes: &mut Nes, line: &str) -> String {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match words.as_slice() {
+            [] => Ok(String::new()),
+            ["h"] | ["help"] => Ok(HELP.to_string()),
+            ["c"] | ["continue"] => {
+                self.paused = false;
+                self.resume = true;
+                Ok(String::new())
+            }
+            ["s"] | ["step"] => Ok(self.step_until(nes, 1, |_| false)),
+            ["s", n] | ["step", n] => {
+                parse_number(n).map(|n| self.step_until(nes, n as usize, |_| false))
+            }
+            ["sl"] | ["scanline"] => {
+                let scanline = nes.cpu.bus.ppu.renderer.scanline;
+                Ok(self.step_until(nes, usize::MAX, |nes| {
+                    nes.cpu.bus.ppu.renderer.scanline != scanline
+                }))
+            }
+            // Stepping always stops at the end of a frame
+            ["f"] | ["frame"] => Ok(self.step_until(nes, usize::MAX, |_| false)),
+            ["b", a] | ["break", a] => parse_address(a).map(|a| {
+                self.breakpoints.insert(a);
+                format!("Breakpoint at ${:04X}", a)
+            }),
+            ["rb", a] => parse_address(a).map(|a| {
+                nes.cpu.bus.watchpoints.reads.insert(a);
+                format!("Read breakpoint at ${:04X}", a)
+            }),
+            ["wb", a] => parse_address(a).map(|a| {
+                nes.cpu.bus.watchpoints.writes.insert(a);
+                format!("Write breakpoint at ${:04X}", a)
+            }),
+            ["bl"] => Ok(self.list_breakpoints(nes)),
+            ["bd", "all"] => {
+                self.breakpoints.clear();
+                nes.cpu.bus.watchpoints.reads.clear();
+                nes.cpu.bus.watchpoints.writes.clear();
+                Ok("Deleted all breakpoints".to_string())
+            }
+            ["bd", a] => parse_address(a).map(|a| {
+                self.breakpoints.remove(&a);
+                nes.cpu.bus.watchpoints.reads.remove(&a);
+                nes.cpu.bus.watchpoints.writes.remove(&a);
+                format!("Deleted breakpoints at ${:04X}", a)
+            }),
+            ["r"] | ["regs"] => Ok(format_registers(nes.cpu.registers())),
+            ["r", reg, value] | ["regs", reg, value] => {
+                set_register(nes, reg, value).map(|_| format_registers(nes.cpu.registers()))
+            }
+            ["m", a] | ["mem", a] => parse_address(a).map(|a| dump_memory(nes, a, 64)),
+            ["m", a, n] | ["mem", a, n] => parse_address(a)
+                .and_then(|a| parse_number(n).map(|n| dump_memory(nes, a, n as usize))),
+            ["d"] | ["disasm"] => Ok(self.disassemble_around_pc(nes)),
+            ["d", a] | ["disasm", a] => parse_address(a).map(|a| disassemble(nes, a, 16, None)),
+            ["d", a, n] | ["disasm", a, n] => parse_address(a)
+                .and_then(|a| parse_number(n).map(|n| disassemble(nes, a, n as usize, None))),
+            ["ch", args @ ..] | ["cheat", args @ ..] => cheat_command(nes, args),
+            ["rs", args @ ..] => self.search_command(nes, args),
+            _ => Err(format!("Unknown command '{}', try help", line.trim())),
+        };
+        result.unwrap_or_else(|e| e)
+    }
+
+    // Steps up to `count` instructions, stopping early at the end of a frame,
+    // on a watchpoint or when `done` says so
+    fn step_until<F: FnMut(&Nes) -> bool>(
+        &mut self,
+        nes: &mut Nes,
+        count: usize,
+        mut done: F,
+    ) -> String {
+        let mut stop = String::new();
+        for _ in 0..count {
+            let frame_done = self.step(nes);
+            if let Some(reason) = self.watchpoint_hit(nes) {
+                stop = reason + "\n";
+                break;
+            }
+            if frame_done || done(nes) {
+                break;
+            }
+        }
+        stop + disassemble(nes, nes.cpu.registers().pc, 1, None).as_str()
+            + "\n"
+            + format_registers(nes.cpu.registers()).as_str()
+    }
+
+    fn search_command(&mut self, nes: &Nes, args: &[&str]) -> Result<String, String> {
+        let bus = &nes.cpu.bus;
+        if let ["new", view @ ..] = args {
+            let view = match view {
+                [] => View::U8,
+                [v] => View::parse(v).ok_or_else(|| format!("Unknown view '{}'", v))?,
+                _ => return Err("Usage: rs new [u8|s8|u16|s16]".to_string()),
+            };
+            self.search = Some(RamSearch::new(bus, view));
+        }
+        let search = self
+            .search
+            .as_mut()
+            .ok_or("No RAM search, start one with rs new")?;
+        let condition = match args {
+            [] | ["new", ..] => None,
+            ["same"] => Some(Condition::Same),
+            ["changed"] => Some(Condition::Changed),
+            ["up"] => Some(Condition::Increased),
+            ["down"] => Some(Condition::Decreased),
+            ["=", n] => Some(Condition::Equals(
+                n.parse().map_err(|_| format!("Bad number '{}'", n))?,
+            )),
+            _ => return Err(format!("Unknown search '{}'", args.join(" "))),
+        };
+        if let Some(condition) = condition {
+            search.filter(bus, condition);
+        }
+        Ok(list_candidates(search))
+    }
+
+    fn list_breakpoints(&self, nes: &Nes) -> String {
+        let mut out = String::new();
+        let watchpoints = &nes.cpu.bus.watchpoints;
+        for (kind, set) in [
+            ("exec", &self.breakpoints),
+            ("read", &watchpoints.reads),
+            ("write", &watchpoints.writes),
+        ] {
+            for a in set.iter() {
+                writeln!(out, "{:<5} ${:04X}", kind, a).unwrap();
+            }
+        }
+        if out.is_empty() {
+            out.push_str("No breakpoints");
+        }
+        out.trim_end().to_string()
+    }
+
+    fn disassemble_around_pc(&self, nes: &mut Nes) -> String {
+        let pc = nes.cpu.registers().pc;
+        let mut out = String::new();
+        for &a in self.history.iter() {
+            out.push_str(&disassemble(nes, a, 1, None));
+            out.push('\n');
+        }
+        out + disassemble(nes, pc, 8, Some(pc)).as_str()
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn parse_number(text: &str) -> Result<u32, String> {
+    let digits = text
+        .strip_prefix('$')
+        .or_else(|| text.strip_prefix("0x"))
+        .unwrap_or(text);
+    u32::from_str_radix(digits, 16).map_err(|_| format!("Bad number '{}'", text))
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    match parse_number(text)? {
+        n if n <= 0xFFFF => Ok(n as u16),
+        _ => Err(format!("Address '{}' is out of range", text)),
+    }
+}
+
+fn cheat_command(nes: &mut Nes, args: &[&str]) -> Result<String, String> {
+    let cheats = &mut nes.cpu.bus.cheats;
+    match args {
+        [] => {}
+        ["add", code, name @ ..] => cheats.add(code, &name.join(" ")).map_err(|e| e.to_string())?,
+        [on @ ("on" | "off"), "all"] => cheats.set_active(*on == "on"),
+        [on @ ("on" | "off"), n] => {
+            let n = parse_number(n)? as usize;
+            if !cheats.set_enabled(n, *on == "on") {
+                return Err(format!("No cheat {:X}", n));
+            }
+        }
+        ["del", n] => {
+            let n = parse_number(n)? as usize;
+            cheats.remove(n).ok_or_else(|| format!("No cheat {:X}", n))?;
+        }
+        _ => return Err("Usage: ch [add CODE NAME | on N | off N | del N]".to_string()),
+    }
+    Ok(list_cheats(cheats))
+}
+
+fn list_cheats(cheats: &Cheats) -> String {
+    let mut out = String::new();
+    if !cheats.active() {
+        out.push_str("All cheats are off\n");
+    }
+    for (i, cheat) in cheats.cheats().iter().enumerate() {
+        let on = if cheat.enabled { "on" } else { "off" };
+        writeln!(out, "{:>2X} {:<3} {:<8}  {}", i, on, cheat.code, cheat.name).unwrap();
+    }
+    if cheats.is_empty() {
+        out.push_str("No cheats");
+    }
+    out.trim_end().to_string()
+}
+
+fn list_candidates(search: &RamSearch) -> String {
+    const SHOWN: usize = 16;
+    let candidates = search.candidates();
+    let mut out = format!("{} candidates ({})", candidates.len(), search.view);
+    for &a in candidates.iter().take(SHOWN) {
+        write!(out, "\n${:04X}  {}", a, search.value(a).unwrap()).unwrap();
+    }
+    if candidates.len() > SHOWN {
+        out.push_str("\n...");
+    }
+    out
+}
+
+fn format_registers(r: CpuRegisters) -> String {
+    let flags: String = "NV-BDIZC"
+        .chars()
+        .enumerate()
+        .map(|(i, c)| if r.p & (0x80 >> i) != 0 { c } else { '.' })
+        .collect();
+    format!(
+        "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} SP:{:02X} P:{:02X} [{}]",
+        r.pc, r.a, r.x, r.y, r.sp, r.p, flags
+    )
+}
+
+fn set_register(nes: &mut Nes, name: &str, value: &str) -> Result<(), String> {
+    let value = parse_number(value)?;
+    let mut r = nes.cpu.registers();
+    let byte = || match value {
+        v if v <= 0xFF => Ok(v as u8),
+        _ => Err(format!("${:X} does not fit in {}", value, name)),
+    };
+    match name.to_lowercase().as_str() {
+        "a" => r.a = byte()?,
+        "x" => r.x = byte()?,
+        "y" => r.y = byte()?,
+        "p" => r.p = byte()?,
+        "sp" => r.sp = byte()?,
+        "pc" if value <= 0xFFFF => r.pc = value as u16,
+        "pc" => return Err(format!("${:X} does not fit in pc", value)),
+        _ => return Err(format!("Unknown register '{}'", name)),
+    }
+    nes.cpu.set_registers(r);
+    Ok(())
+}
+
+fn dump_memory(nes: &mut Nes, start: u16, len: usize) -> String {
+    let mut out = String::new();
+    let mut address = start as usize;
+    let end = (start as usize + len).min(0x10000);
+    while address < end {
+        let row: Vec<u8> = (address..end.min(address + 16))
+            .map(|a| nes.cpu.bus.peek(a as u16))
+            .collect();
+        let hex: Vec<String> = row.iter().map(|b| format!("{:02X}", b)).collect();
+        let text: String = row
+            .iter()
+            .map(|&b| {
+                if (0x20..0x7F).contains(&b) {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        writeln!(out, "{:04X}  {:<47}  {}", address, hex.join(" "), text).unwrap();
+        address += 16;
+    }
+    out.trim_end().to_string()
+}
+
+// One instruction per line, marking `current` with an arrow. Indexed operands
+// of the current instruction also show the address they resolve to.
+fn disassemble(nes: &mut Nes, start: u16, count: usize, current: Option<u16>) -> String {
+    let registers = nes.cpu.registers();
+    let bus = &mut nes.cpu.bus;
+    let mut out = String::new();
+    let mut address = start;
+    for _ in 0..count {
+        let mut instruction = disassembler::decode(address, |a| bus.peek(a));
+        let hex: Vec<String> = instruction
+            .bytes()
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        let marker = if Some(address) == current { "->" } else { "  " };
+        let mut text = instruction.text(|a| {
+            bus.label(a)
+                .or_else(|| disassembler::register_label(a).map(String::from))
+        });
+        if Some(address) == current && instruction.mode.is_indexed() {
+            instruction.resolve(registers.x, registers.y, |a| bus.peek(a));
+            write!(text, " @ ${:04X}", instruction.effective_address.unwrap()).unwrap();
+        }
+        if let Some(symbol) = bus.symbol(address) {
+            out.push_str(&symbol.heading());
+        }
+        writeln!(
+            out,
+            "{} {:04X}  {:<8}  {}",
+            marker,
+            address,
+            hex.join(" "),
+            text
+        )
+        .unwrap();
+        address = address.wrapping_add(instruction.size);
+    }
+    out.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nes::build_counter_nes as build_nes;
+
+    #[test]
+    fn test_step_and_registers() {
+        let mut nes = build_nes();
+        let mut debugger = Debugger::new();
+        let out = debugger.command(&mut nes, "s 2");
+        assert!(out.contains("8003  86 10     STX $10"), "{}", out);
+        assert_eq!(1, nes.cpu.registers().x);
+
+        debugger.command(&mut nes, "r x 7f");
+        assert_eq!(0x7f, nes.cpu.registers().x);
+        assert_eq!("Bad number 'zz'", debugger.command(&mut nes, "r a zz"));
+        assert!(debugger
+            .command(&mut nes, "r q 1")
+            .starts_with("Unknown register"));
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut nes = build_nes();
+        let mut debugger = Debugger::new();
+        debugger.command(&mut nes, "b $8007");
+        debugger.command(&mut nes, "c");
+        assert!(!debugger.paused);
+        assert_eq!(
+            Some("Breakpoint at $8007".to_string()),
+            debugger.run_frame(&mut nes)
+        );
+        assert!(debugger.paused);
+        assert_eq!(0x8007, nes.cpu.registers().pc);
+
+        // Continuing does not stop on the same breakpoint straight away
+        debugger.command(&mut nes, "c");
+        assert_eq!(
+            Some("Breakpoint at $8007".to_string()),
+            debugger.run_frame(&mut nes)
+        );
+        assert_eq!(2, nes.cpu.registers().x);
+
+        debugger.command(&mut nes, "bd all");
+        debugger.command(&mut nes, "wb 10");
+        debugger.command(&mut nes, "c");
+        assert_eq!(
+            Some("Write of $03 at $0010".to_string()),
+            debugger.run_frame(&mut nes)
+        );
+
+        debugger.command(&mut nes, "bd 10");
+        debugger.command(&mut nes, "rb 0x0010");
+        assert_eq!("read  $0010", debugger.command(&mut nes, "bl"));
+        debugger.command(&mut nes, "c");
+        assert_eq!(
+            Some("Read of $03 at $0010".to_string()),
+            debugger.run_frame(&mut nes)
+        );
+    }
+
+    #[test]
+    fn test_cheats() {
+        let mut nes = build_nes();
+        let mut debugger = Debugger::new();
+        assert_eq!("No cheats", debugger.command(&mut nes, "ch"));
+        assert_eq!(
+            " 0 on  GOSSIP    Lives",
+            debugger.command(&mut nes, "ch add gossip Lives")
+        );
+        debugger.command(&mut nes, "ch add 0010:FF");
+        assert_eq!(
+            " 0 on  GOSSIP    Lives\n 1 off 0010:FF",
+            debugger.command(&mut nes, "ch off 1")
+        );
+        assert!(debugger
+            .command(&mut nes, "ch off all")
+            .starts_with("All cheats are off"));
+        assert_eq!("No cheat 5", debugger.command(&mut nes, "ch del 5"));
+        assert!(debugger
+            .command(&mut nes, "ch add NOPE")
+            .contains("not a cheat code"));
+    }
+
+    #[test]
+    fn test_ram_search() {
+        let mut nes = build_nes();
+        let mut debugger = Debugger::new();
+        assert_eq!(
+            "No RAM search, start one with rs new",
+            debugger.command(&mut nes, "rs up")
+        );
+        assert!(debugger
+            .command(&mut nes, "rs new")
+            .starts_with("10240 candidates (u8)"));
+        // The program counts up in X and stores it at $10
+        debugger.command(&mut nes, "s 3");
+        debugger.command(&mut nes, "rs up");
+        debugger.command(&mut nes, "s 5");
+        assert_eq!(
+            "1 candidates (u8)\n$0010  2",
+            debugger.command(&mut nes, "rs up")
+        );
+        assert_eq!("0 candidates (u8)", debugger.command(&mut nes, "rs = 7"));
+        assert_eq!("Unknown view 'u32'", debugger.command(&mut nes, "rs new u32"));
+    }
+
+    #[test]
+    fn test_memory_and_disassembly() {
+        let mut nes = build_nes();
+        let mut debugger = Debugger::new();
+        assert_eq!(
+            format!("8000  {:<47}  ....", "A2 00 E8 86"),
+            debugger.command(&mut nes, "m 8000 4")
+        );
+        assert_eq!(
+            "   8002  E8        INX\n   8003  86 10     STX $10",
+            debugger.command(&mut nes, "d 8002 2")
+        );
+
+        debugger.command(&mut nes, "s 3");
+        let out = debugger.command(&mut nes, "d");
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!("   8000  A2 00     LDX #$00", lines[0]);
+        assert_eq!("-> 8005  A5 10     LDA $10", lines[3]);
+        assert_eq!("   8007  4C 02 80  JMP $8002", lines[4]);
+
+        debugger.command(&mut nes, "r pc 0");
+        nes.cpu.bus.ram[0..3].copy_from_slice(&[0x9d, 0x00, 0x20]);
+        assert_eq!(
+            "-> 0000  9D 00 20  STA PPUCTRL,X @ $2001",
+            debugger.command(&mut nes, "d").lines().nth(3).unwrap_or("")
+        );
+    }
+
+    #[test]
+    fn test_step_frame() {
+        let mut nes = build_nes();
+        let mut debugger = Debugger::new();
+        debugger.command(&mut nes, "sl");
+        assert_eq!(1, nes.cpu.bus.ppu.renderer.scanline);
+        debugger.command(&mut nes, "f");
+        assert_eq!(None, debugger.run_frame(&mut nes));
+    }
+}
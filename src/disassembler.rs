@@ -0,0 +1,352 @@
+// Decodes 6502 machine code into structured instructions, built on the
+// mnemonic and size tables in cpu_debug. Memory is read through a closure so
+// callers can decide what is safe to touch; the debugger and the CPU trace
+// both render from here.
+
+use crate::cpu_debug::{INSTRUCTION_NAMES, INSTRUCTION_SIZES};
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Mode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    Relative,
+}
+
+impl Mode {
+    fn from_name(opcode: u8, name: &str) -> Self {
+        match name {
+            "imm" => Mode::Immediate,
+            "zp" => Mode::ZeroPage,
+            "zpx" => Mode::ZeroPageX,
+            "zpy" => Mode::ZeroPageY,
+            "abs" => Mode::Absolute,
+            "abx" => Mode::AbsoluteX,
+            "aby" => Mode::AbsoluteY,
+            "ind" => Mode::Indirect,
+            "izx" => Mode::IndirectX,
+            "izy" => Mode::IndirectY,
+            "rel" => Mode::Relative,
+            _ => match opcode {
+                0x0a | 0x2a | 0x4a | 0x6a => Mode::Accumulator,
+                _ => Mode::Implied,
+            },
+        }
+    }
+
+    // Whether the address depends on X, Y or a pointer in memory
+    pub fn is_indexed(self) -> bool {
+        matches!(
+            self,
+            Mode::ZeroPageX
+                | Mode::ZeroPageY
+                | Mode::AbsoluteX
+                | Mode::AbsoluteY
+                | Mode::IndirectX
+                | Mode::IndirectY
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub address: u16,
+    pub opcode: u8,
+    // Without the leading * that INSTRUCTION_NAMES puts on unofficial opcodes
+    pub mnemonic: &'static str,
+    pub unofficial: bool,
+    pub mode: Mode,
+    // The byte or word after the opcode, zero when there isn't one
+    pub operand: u16,
+    pub size: u16,
+    bytes: [u8; 3],
+    // The address the instruction reads or writes. Modes that depend on
+    // registers only have one after resolve().
+    pub effective_address: Option<u16>,
+    // Where control goes for branches, JMP and JSR
+    pub branch_target: Option<u16>,
+}
+
+impl Instruction {
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.size as usize]
+    }
+
+    // Fills in the effective address of indexed and indirect modes for the
+    // given index registers
+    pub fn resolve<F: FnMut(u16) -> u8>(&mut self, x: u8, y: u8, mut read: F) {
+        let b = self.operand as u8;
+        let mut zero_page_word = |pointer: u8| {
+            read(pointer as u16) as u16 | (read(pointer.wrapping_add(1) as u16) as u16) << 8
+        };
+        let address = match self.mode {
+            Mode::ZeroPageX => b.wrapping_add(x) as u16,
+            Mode::ZeroPageY => b.wrapping_add(y) as u16,
+            Mode::AbsoluteX => self.operand.wrapping_add(x as u16),
+            Mode::AbsoluteY => self.operand.wrapping_add(y as u16),
+            Mode::IndirectX => zero_page_word(b.wrapping_add(x)),
+            Mode::IndirectY => zero_page_word(b).wrapping_add(y as u16),
+            _ => return,
+        };
+        self.effective_address = Some(address);
+    }
+
+    // The operand in assembler syntax, e.g. ($10),Y. Addresses that `label`
+    // has a name for are written by name.
+    pub fn operand_text<L: Fn(u16) -> Option<String>>(&self, label: L) -> String {
+        let zero_page = |address: u8| label(address as u16).unwrap_or(format!("${:02X}", address));
+        let absolute = |address: u16| label(address).unwrap_or(format!("${:04X}", address));
+        let b = self.operand as u8;
+        let w = self.operand;
+        match self.mode {
+            Mode::Implied => String::new(),
+            Mode::Accumulator => "A".to_string(),
+            Mode::Immediate => format!("#${:02X}", b),
+            Mode::ZeroPage => zero_page(b),
+            Mode::ZeroPageX => format!("{},X", zero_page(b)),
+            Mode::ZeroPageY => format!("{},Y", zero_page(b)),
+            Mode::Absolute => absolute(w),
+            Mode::AbsoluteX => format!("{},X", absolute(w)),
+            Mode::AbsoluteY => format!("{},Y", absolute(w)),
+            Mode::Indirect => format!("({})", absolute(w)),
+            Mode::IndirectX => format!("({},X)", zero_page(b)),
+            Mode::IndirectY => format!("({}),Y", zero_page(b)),
+            Mode::Relative => absolute(self.branch_target.unwrap_or(0)),
+        }
+    }
+
+    pub fn text<L: Fn(u16) -> Option<String>>(&self, label: L) -> String {
+        let operand = self.operand_text(label);
+        if operand.is_empty() {
+            self.mnemonic.to_string()
+        } else {
+            format!("{} {}", self.mnemonic, operand)
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text(no_labels))
+    }
+}
+
+// Decodes the instruction at `address`
+pub fn decode<F: FnMut(u16) -> u8>(address: u16, mut read: F) -> Instruction {
+    let opcode = read(address);
+    let size = INSTRUCTION_SIZES[opcode as usize];
+    let mut bytes = [opcode, 0, 0];
+    for i in 1..size {
+        bytes[i as usize] = read(address.wrapping_add(i));
+    }
+    let operand = match size {
+        2 => bytes[1] as u16,
+        3 => bytes[1] as u16 | (bytes[2] as u16) << 8,
+        _ => 0,
+    };
+
+    let name = INSTRUCTION_NAMES[opcode as usize];
+    let (mnemonic, mode) = name.split_once(' ').unwrap_or((name, ""));
+    let (unofficial, mnemonic) = match mnemonic.strip_prefix('*') {
+        Some(m) => (true, m),
+        None => (false, mnemonic),
+    };
+    let mode = Mode::from_name(opcode, mode);
+
+    let effective_address = match mode {
+        Mode::ZeroPage | Mode::Absolute => Some(operand),
+        Mode::Indirect => {
+            // JMP ($xxFF) fetches the high byte from the start of the same page
+            let high = (operand & 0xFF00) | (operand.wrapping_add(1) & 0x00FF);
+            Some(read(operand) as u16 | (read(high) as u16) << 8)
+        }
+        _ => None,
+    };
+    let branch_target = match (mode, mnemonic) {
+        (Mode::Relative, _) => Some(
+            address
+                .wrapping_add(2)
+                .wrapping_add(operand as u8 as i8 as u16),
+        ),
+        (Mode::Absolute, "JMP") | (Mode::Absolute, "JSR") => Some(operand),
+        (Mode::Indirect, _) => effective_address,
+        _ => None,
+    };
+
+    Instruction {
+        address,
+        opcode,
+        mnemonic,
+        unofficial,
+        mode,
+        operand,
+        size,
+        bytes,
+        effective_address,
+        branch_target,
+    }
+}
+
+// Decodes every instruction that starts in start..=end, assuming the range
+// starts on an instruction boundary
+pub fn disassemble<F: FnMut(u16) -> u8>(start: u16, end: u16, mut read: F) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut address = start as u32;
+    while address <= end as u32 {
+        let instruction = decode(address as u16, &mut read);
+        address += instruction.size as u32;
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+pub fn no_labels(_address: u16) -> Option<String> {
+    None
+}
+
+// Names for the memory-mapped PPU, APU and I/O registers
+pub fn register_label(address: u16) -> Option<&'static str> {
+    let name = match address {
+        0x2000 => "PPUCTRL",
+        0x2001 => "PPUMASK",
+        0x2002 => "PPUSTATUS",
+        0x2003 => "OAMADDR",
+        0x2004 => "OAMDATA",
+        0x2005 => "PPUSCROLL",
+        0x2006 => "PPUADDR",
+        0x2007 => "PPUDATA",
+        0x4000 => "SQ1_VOL",
+        0x4001 => "SQ1_SWEEP",
+        0x4002 => "SQ1_LO",
+        0x4003 => "SQ1_HI",
+        0x4004 => "SQ2_VOL",
+        0x4005 => "SQ2_SWEEP",
+        0x4006 => "SQ2_LO",
+        0x4007 => "SQ2_HI",
+        0x4008 => "TRI_LINEAR",
+        0x400A => "TRI_LO",
+        0x400B => "TRI_HI",
+        0x400C => "NOISE_VOL",
+        0x400E => "NOISE_LO",
+        0x400F => "NOISE_HI",
+        0x4010 => "DMC_FREQ",
+        0x4011 => "DMC_RAW",
+        0x4012 => "DMC_START",
+        0x4013 => "DMC_LEN",
+        0x4014 => "OAMDMA",
+        0x4015 => "SND_CHN",
+        0x4016 => "JOY1",
+        0x4017 => "JOY2",
+        _ => return None,
+    };
+    Some(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode_bytes(bytes: &[u8]) -> Instruction {
+        let mut memory = vec![0u8; 0x10000];
+        memory[0x8000..0x8000 + bytes.len()].copy_from_slice(bytes);
+        memory[0x10] = 0x34;
+        memory[0x11] = 0x12;
+        memory[0x30FF] = 0xCD;
+        memory[0x3000] = 0xAB;
+        let mut instruction = decode(0x8000, |a| memory[a as usize]);
+        instruction.resolve(1, 2, |a| memory[a as usize]);
+        instruction
+    }
+
+    #[test]
+    fn test_decode() {
+        let i = decode_bytes(&[0xb1, 0x10]);
+        assert_eq!(Mode::IndirectY, i.mode);
+        assert_eq!("LDA", i.mnemonic);
+        assert_eq!(0x10, i.operand);
+        assert_eq!(&[0xb1, 0x10], i.bytes());
+        assert_eq!(Some(0x1236), i.effective_address);
+        assert_eq!("LDA ($10),Y", i.to_string());
+
+        let i = decode_bytes(&[0xa1, 0x0f]);
+        assert_eq!(Some(0x1234), i.effective_address);
+        assert_eq!("LDA ($0F,X)", i.to_string());
+
+        let i = decode_bytes(&[0xb6, 0xff]);
+        assert_eq!(Some(0x0001), i.effective_address);
+        assert_eq!("LDX $FF,Y", i.to_string());
+
+        let i = decode_bytes(&[0x0a]);
+        assert_eq!(Mode::Accumulator, i.mode);
+        assert_eq!("ASL A", i.to_string());
+
+        let i = decode_bytes(&[0xc7, 0x10]);
+        assert!(i.unofficial);
+        assert_eq!("DCP $10", i.to_string());
+    }
+
+    #[test]
+    fn test_branch_targets() {
+        assert_eq!(Some(0x7FF2), decode_bytes(&[0xd0, 0xf0]).branch_target);
+        assert_eq!("BNE $8012", decode_bytes(&[0xd0, 0x10]).to_string());
+        assert_eq!(
+            Some(0x1234),
+            decode_bytes(&[0x20, 0x34, 0x12]).branch_target
+        );
+        assert_eq!(None, decode_bytes(&[0xad, 0x34, 0x12]).branch_target);
+
+        // The indirect JMP page wrapping bug
+        let i = decode_bytes(&[0x6c, 0xff, 0x30]);
+        assert_eq!(Some(0xABCD), i.branch_target);
+        assert_eq!("JMP ($30FF)", i.to_string());
+    }
+
+    #[test]
+    fn test_labels() {
+        let i = decode_bytes(&[0x8d, 0x14, 0x40]);
+        let registers = |a| register_label(a).map(String::from);
+        assert_eq!("STA $4014", i.text(no_labels));
+        assert_eq!("STA OAMDMA", i.text(registers));
+        assert_eq!(
+            "LDA PPUSTATUS,X",
+            decode_bytes(&[0xbd, 0x02, 0x20]).text(registers)
+        );
+        assert_eq!(
+            "LDA $0200",
+            decode_bytes(&[0xad, 0x00, 0x02]).text(registers)
+        );
+
+        let symbols = |a| match a {
+            0x10 => Some("pointer".to_string()),
+            0x8012 => Some("loop".to_string()),
+            _ => None,
+        };
+        assert_eq!("LDA (pointer),Y", decode_bytes(&[0xb1, 0x10]).text(symbols));
+        assert_eq!("BNE loop", decode_bytes(&[0xd0, 0x10]).text(symbols));
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let program = [0xa9, 0x00, 0x8d, 0x00, 0x20, 0xe8, 0x4c, 0x00, 0x80];
+        let instructions = disassemble(0, 8, |a| program.get(a as usize).copied().unwrap_or(0));
+        let text: Vec<String> = instructions
+            .iter()
+            .map(|i| i.text(|a| register_label(a).map(String::from)))
+            .collect();
+        assert_eq!(vec!["LDA #$00", "STA PPUCTRL", "INX", "JMP $8000"], text);
+        assert_eq!(6, instructions[3].address);
+
+        // Running off the end of memory stops instead of wrapping around
+        assert_eq!(1, disassemble(0xFFFF, 0xFFFF, |_| 0x4c).len());
+    }
+}
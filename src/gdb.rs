@@ -0,0 +1,668 @@
+// A GDB remote serial protocol stub, so GDB and front-ends built on it can
+// debug a running game over TCP. It never blocks: the frontend calls poll()
+// every frame to handle packets, and run_frame() while the client has the
+// machine running.
+//
+// GDB has no 6502 target, so the register layout is our own and described
+// in target.xml: A, X, Y, P and SP are one byte each, then PC as two bytes,
+// all little-endian.
+
+use crate::bus::Access;
+use crate::nes::Nes;
+use std::collections::BTreeSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.nes_emu.6502">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="x" bitsize="8"/>
+    <reg name="y" bitsize="8"/>
+    <reg name="p" bitsize="8"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>"#;
+
+// The largest packet we tell the client it may send or expect back
+const PACKET_SIZE: usize = 0x1000;
+
+// Signals for stop replies
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+pub struct GdbStub {
+    listener: TcpListener,
+    connection: Option<TcpStream>,
+    input: Vec<u8>,
+    // Resent when the client asks for a retransmit
+    last_reply: Vec<u8>,
+    no_ack: bool,
+    breakpoints: BTreeSet<u16>,
+    // The watchpoints the client added to the bus, which come out again when
+    // it detaches. Any the debugger console set are left alone.
+    watched_reads: BTreeSet<u16>,
+    watched_writes: BTreeSet<u16>,
+    halted: bool,
+    // Set by detach and kill, the connection closes once the reply is out
+    closing: bool,
+}
+
+impl GdbStub {
+    pub fn bind<A: ToSocketAddrs>(address: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(GdbStub {
+            listener,
+            connection: None,
+            input: Vec::new(),
+            last_reply: Vec::new(),
+            no_ack: false,
+            breakpoints: BTreeSet::new(),
+            watched_reads: BTreeSet::new(),
+            watched_writes: BTreeSet::new(),
+            halted: false,
+            closing: false,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    // Whether the client is holding the machine still
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    // Accepts a client and handles whatever it has sent. A client that
+    // misbehaves or goes away is dropped and the game carries on.
+    pub fn poll(&mut self, nes: &mut Nes) -> io::Result<()> {
+        if self.connection.is_none() {
+            match self.listener.accept() {
+                Ok((stream, _)) => self.attach(stream)?,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let result = self.read_packets(nes);
+        if result.is_err() || self.closing {
+            self.disconnect(nes);
+        }
+        result
+    }
+
+    // Runs until the end of the frame, stopping early on a breakpoint
+    pub fn run_frame(&mut self, nes: &mut Nes) {
+        if self.halted {
+            return;
+        }
+        loop {
+            let frame_done = nes.step_instruction();
+            if let Some(reply) = self.stop_reply(nes, true) {
+                self.halted = true;
+                if self.send(&reply).is_err() {
+                    self.disconnect(nes);
+                }
+                return;
+            }
+            if frame_done {
+                return;
+            }
+        }
+    }
+
+    fn attach(&mut self, stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        self.connection = Some(stream);
+        self.input.clear();
+        self.no_ack = false;
+        self.closing = false;
+        // GDB expects to find the target stopped when it attaches
+        self.halted = true;
+        Ok(())
+    }
+
+    // Detaching leaves the game running without breakpoints or watchpoints
+    fn disconnect(&mut self, nes: &mut Nes) {
+        self.connection = None;
+        self.closing = false;
+        self.halted = false;
+        self.breakpoints.clear();
+        let watchpoints = &mut nes.cpu.bus.watchpoints;
+        for address in std::mem::take(&mut self.watched_reads) {
+            watchpoints.reads.remove(&address);
+        }
+        for address in std::mem::take(&mut self.watched_writes) {
+            watchpoints.writes.remove(&address);
+        }
+        watchpoints.hit = None;
+    }
+
+    fn read_packets(&mut self, nes: &mut Nes) -> io::Result<()> {
+        let mut buffer = [0u8; 4096];
+        loop {
+            let stream = self.connection.as_mut().unwrap();
+            match stream.read(&mut buffer) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.input.extend_from_slice(&buffer[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        while !self.input.is_empty() && !self.closing {
+            match self.input[0] {
+                b'$' => {
+                    let end = match self.input.iter().position(|&b| b == b'#') {
+                        Some(end) if self.input.len() >= end + 3 => end,
+                        // Wait for the rest of the packet
+                        _ => return Ok(()),
+                    };
+                    let packet: Vec<u8> = self.input.drain(..end + 3).collect();
+                    let data = &packet[1..end];
+                    let checksum = std::str::from_utf8(&packet[end + 1..])
+                        .ok()
+                        .and_then(|c| u8::from_str_radix(c, 16).ok());
+                    if !self.no_ack {
+                        let ack = if checksum == Some(checksum_of(data)) {
+                            b"+"
+                        } else {
+                            b"-"
+                        };
+                        self.write(ack)?;
+                        if ack == b"-" {
+                            continue;
+                        }
+                    }
+                    let data = String::from_utf8_lossy(data).into_owned();
+                    if let Some(reply) = self.handle(nes, &data) {
+                        self.send(&reply)?;
+                    }
+                    if data == "QStartNoAckMode" {
+                        self.no_ack = true;
+                    }
+                }
+                b'-' => {
+                    self.input.remove(0);
+                    let reply = self.last_reply.clone();
+                    self.write(&reply)?;
+                }
+                // Ctrl-C from the client
+                0x03 => {
+                    self.input.remove(0);
+                    if !self.halted {
+                        self.halted = true;
+                        self.send(&format!("S{:02x}", SIGINT))?;
+                    }
+                }
+                // Acks and line noise
+                _ => {
+                    self.input.remove(0);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the reply, or None when the reply comes later (continue) or never (kill)
+    fn handle(&mut self, nes: &mut Nes, packet: &str) -> Option<String> {
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => {
+                let r = nes.cpu.registers();
+                hex(&[r.a, r.x, r.y, r.p, r.sp, r.pc as u8, (r.pc >> 8) as u8])
+            }
+            "G" => match parse_hex(args) {
+                Some(bytes) if bytes.len() == 7 => {
+                    for (i, &b) in bytes.iter().enumerate().take(5) {
+                        set_register(nes, i, b as u16);
+                    }
+                    set_register(nes, 5, bytes[5] as u16 | (bytes[6] as u16) << 8);
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(n @ 0..=4) => hex(&[register(nes, n) as u8]),
+                Ok(5) => {
+                    let pc = register(nes, 5);
+                    hex(&[pc as u8, (pc >> 8) as u8])
+                }
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(n, value)| {
+                    let n = usize::from_str_radix(n, 16).ok()?;
+                    let bytes = parse_hex(value)?;
+                    let value = bytes.iter().rev().fold(0u16, |v, &b| v << 8 | b as u16);
+                    Some((n, value))
+                });
+                match parsed {
+                    Some((n, value)) if n <= 5 => {
+                        set_register(nes, n, value);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match parse_range(args) {
+                // Each byte takes two hex digits in the reply
+                Some((address, len)) if len <= PACKET_SIZE / 2 => {
+                    let bytes: Vec<u8> = (0..len)
+                        .map(|i| {
+                            nes.cpu
+                                .bus
+                                .peek(address.wrapping_add(i as u16))
+                        })
+                        .collect();
+                    hex(&bytes)
+                }
+                _ => "E01".to_string(),
+            },
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(range, data)| {
+                    let (address, len) = parse_range(range)?;
+                    let bytes = parse_hex(data)?;
+                    if bytes.len() == len {
+                        Some((address, bytes))
+                    } else {
+                        None
+                    }
+                });
+                // ROM and I/O registers can't be written without side effects
+                let written = parsed.is_some_and(|(address, bytes)| {
+                    bytes
+                        .iter()
+                        .enumerate()
+                        .all(|(i, &b)| nes.cpu.bus.poke(address.wrapping_add(i as u16), b))
+                });
+                if written {
+                    "OK".to_string()
+                } else {
+                    "E01".to_string()
+                }
+            }
+            "Z" | "z" => self.breakpoint(nes, command == "Z", args),
+            "s" => {
+                if let Some(address) = parse_address(args) {
+                    set_register(nes, 5, address);
+                }
+                nes.step_instruction();
+                self.stop_reply(nes, false)
+                    .unwrap_or_else(|| format!("S{:02x}", SIGTRAP))
+            }
+            "c" => {
+                if let Some(address) = parse_address(args) {
+                    set_register(nes, 5, address);
+                }
+                self.halted = false;
+                return None;
+            }
+            "D" => {
+                self.closing = true;
+                "OK".to_string()
+            }
+            "k" => {
+                self.closing = true;
+                return None;
+            }
+            "H" | "T" => "OK".to_string(),
+            "q" => self.query(args),
+            "Q" if args == "StartNoAckMode" => "OK".to_string(),
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn query(&self, query: &str) -> String {
+        if let Some(args) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let (offset, len) = match args.split_once(',').and_then(|(o, l)| {
+                Some((
+                    usize::from_str_radix(o, 16).ok()?,
+                    usize::from_str_radix(l, 16).ok()?,
+                ))
+            }) {
+                Some(pair) => pair,
+                None => return "E01".to_string(),
+            };
+            let start = offset.min(TARGET_XML.len());
+            let end = (offset + len).min(TARGET_XML.len());
+            let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+            return format!("{}{}", more, &TARGET_XML[start..end]);
+        }
+        match query.split(':').next().unwrap_or("") {
+            "Supported" => format!(
+                "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+",
+                PACKET_SIZE
+            ),
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    // Z0/Z1 are execution breakpoints, Z2/Z3/Z4 watch writes, reads or both
+    fn breakpoint(&mut self, nes: &mut Nes, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let kind = fields.next();
+        let address = fields.next().and_then(parse_address);
+        let address = match address {
+            Some(a) => a,
+            None => return "E01".to_string(),
+        };
+        if let Some("0") | Some("1") = kind {
+            if insert {
+                self.breakpoints.insert(address);
+            } else {
+                self.breakpoints.remove(&address);
+            }
+            return "OK".to_string();
+        }
+
+        // Each bus set goes with the stub's record of what it added there
+        let watchpoints = &mut nes.cpu.bus.watchpoints;
+        let sets: Vec<(&mut BTreeSet<u16>, &mut BTreeSet<u16>)> = match kind {
+            Some("2") => vec![(&mut watchpoints.writes, &mut self.watched_writes)],
+            Some("3") => vec![(&mut watchpoints.reads, &mut self.watched_reads)],
+            Some("4") => vec![
+                (&mut watchpoints.reads, &mut self.watched_reads),
+                (&mut watchpoints.writes, &mut self.watched_writes),
+            ],
+            _ => return String::new(),
+        };
+        for (set, added) in sets {
+            if insert {
+                set.insert(address);
+                added.insert(address);
+            } else if added.remove(&address) {
+                set.remove(&address);
+            }
+        }
+        if !insert && matches!(watchpoints.hit, Some((_, a, _)) if a == address) {
+            watchpoints.hit = None;
+        }
+        "OK".to_string()
+    }
+
+    // Why the machine should stop after an instruction, if it should.
+    // Single steps ignore execution breakpoints.
+    fn stop_reply(&self, nes: &mut Nes, check_breakpoints: bool) -> Option<String> {
+        if let Some((access, address, _)) = nes.cpu.bus.watchpoints.hit.take() {
+            let kind = match access {
+                Access::Read => "rwatch",
+                Access::Write => "watch",
+            };
+            return Some(format!("T{:02x}{}:{:04x};", SIGTRAP, kind, address));
+        }
+        let pc = nes.cpu.registers().pc;
+        if check_breakpoints && self.breakpoints.contains(&pc) {
+            return Some(format!("T{:02x}swbreak:;", SIGTRAP));
+        }
+        None
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.last_reply = packet.into_bytes();
+        let packet = self.last_reply.clone();
+        self.write(&packet)
+    }
+
+    // Replies are small, so block until they are out rather than queueing them
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let stream = match self.connection.as_mut() {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        stream.set_nonblocking(false)?;
+        let result = stream.write_all(bytes);
+        stream.set_nonblocking(true)?;
+        result
+    }
+}
+
+fn register(nes: &Nes, n: usize) -> u16 {
+    let r = nes.cpu.registers();
+    match n {
+        0 => r.a as u16,
+        1 => r.x as u16,
+        2 => r.y as u16,
+        3 => r.p as u16,
+        4 => r.sp as u16,
+        _ => r.pc,
+    }
+}
+
+fn set_register(nes: &mut Nes, n: usize, value: u16) {
+    let mut r = nes.cpu.registers();
+    match n {
+        0 => r.a = value as u8,
+        1 => r.x = value as u8,
+        2 => r.y = value as u8,
+        3 => r.p = value as u8,
+        4 => r.sp = value as u8,
+        _ => r.pc = value,
+    }
+    nes.cpu.set_registers(r);
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+    u16::from_str_radix(text, 16).ok()
+}
+
+// addr,length
+fn parse_range(text: &str) -> Option<(u16, usize)> {
+    let (address, len) = text.split_once(',')?;
+    Some((
+        parse_address(address)?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Watchpoints;
+    use crate::nes::build_counter_nes as build_nes;
+    use std::io::BufReader;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    // The machine isn't Send, so it lives and dies on the server thread
+    // The server hands back the bus's watchpoints once the client has gone
+    fn start_server() -> (SocketAddr, thread::JoinHandle<Watchpoints>) {
+        let (sender, receiver) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let mut nes = build_nes();
+            let mut stub = GdbStub::bind("127.0.0.1:0").unwrap();
+            sender.send(stub.local_addr().unwrap()).unwrap();
+            let mut attached = false;
+            while !attached || stub.connected() {
+                let _ = stub.poll(&mut nes);
+                attached |= stub.connected();
+                // Keep the machine where reset left it until the client is in
+                if stub.connected() && !stub.halted() {
+                    stub.run_frame(&mut nes);
+                } else {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+            std::mem::take(&mut nes.cpu.bus.watchpoints)
+        });
+        (receiver.recv().unwrap(), server)
+    }
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn connect(address: SocketAddr) -> Self {
+            let stream = TcpStream::connect(address).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            Client {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                writer: stream,
+            }
+        }
+
+        fn byte(&mut self) -> u8 {
+            let mut b = [0u8];
+            self.reader.read_exact(&mut b).unwrap();
+            b[0]
+        }
+
+        fn receive(&mut self) -> String {
+            while self.byte() != b'$' {}
+            let mut data = Vec::new();
+            loop {
+                match self.byte() {
+                    b'#' => break,
+                    b => data.push(b),
+                }
+            }
+            let checksum = [self.byte(), self.byte()];
+            let checksum = u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16);
+            assert_eq!(Ok(checksum_of(&data)), checksum);
+            self.writer.write_all(b"+").unwrap();
+            String::from_utf8(data).unwrap()
+        }
+
+        fn send(&mut self, data: &str) {
+            let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+            self.writer.write_all(packet.as_bytes()).unwrap();
+            assert_eq!(b'+', self.byte());
+        }
+
+        fn request(&mut self, data: &str) -> String {
+            self.send(data);
+            self.receive()
+        }
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let (address, server) = start_server();
+        let mut client = Client::connect(address);
+
+        assert_eq!("S05", client.request("?"));
+        assert!(client
+            .request("qSupported:swbreak+")
+            .contains("qXfer:features:read+"));
+        let xml = client.request("qXfer:features:read:target.xml:0,1000");
+        assert!(xml.starts_with("l<?xml"));
+
+        // PC is $8000 after reset
+        assert_eq!("0080", client.request("p5"));
+        assert_eq!("S05", client.request("s"));
+        assert_eq!("S05", client.request("s"));
+        let registers = client.request("g");
+        assert_eq!(14, registers.len());
+        assert_eq!("01", &registers[2..4]);
+        assert_eq!("0380", &registers[10..14]);
+
+        assert_eq!("OK", client.request("P1=7f"));
+        assert_eq!("7f", client.request("p1"));
+        assert_eq!("OK", client.request("Gaabbccddee0080"));
+        assert_eq!("aabbccddee0080", client.request("g"));
+
+        assert_eq!("OK", client.request("M10,3:123456"));
+        assert_eq!("123456", client.request("m10,3"));
+        assert_eq!("OK", client.request("M6000,2:beef"));
+        assert_eq!("beef", client.request("m6000,2"));
+        assert_eq!("E01", client.request("M8000,1:00"));
+        assert_eq!("E01", client.request("M2000,1:80"));
+        assert_eq!("a200e8", client.request("m8000,3"));
+        assert_eq!("E01", client.request("mzz"));
+        assert_eq!(0x1000, client.request("m0,800").len());
+        assert_eq!("E01", client.request("m0,801"));
+        assert_eq!("", client.request("vMustReplyEmpty"));
+
+        assert_eq!("OK", client.request("D"));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_breakpoints_and_continue() {
+        let (address, server) = start_server();
+        let mut client = Client::connect(address);
+
+        assert_eq!("OK", client.request("Z0,8007,1"));
+        client.send("c");
+        assert_eq!("T05swbreak:;", client.receive());
+        assert_eq!("0780", client.request("p5"));
+
+        // Continuing from a breakpoint runs the loop once more
+        client.send("c");
+        assert_eq!("T05swbreak:;", client.receive());
+        assert_eq!("02", client.request("p1"));
+
+        assert_eq!("OK", client.request("z0,8007,1"));
+        assert_eq!("OK", client.request("Z2,10,1"));
+        client.send("c");
+        assert_eq!("T05watch:0010;", client.receive());
+        assert_eq!("OK", client.request("z2,10,1"));
+        assert_eq!("OK", client.request("Z3,10,1"));
+        client.send("c");
+        assert_eq!("T05rwatch:0010;", client.receive());
+        assert_eq!("OK", client.request("z3,10,1"));
+
+        // With nothing to stop it, only Ctrl-C brings it back
+        client.send("c");
+        client.writer.write_all(&[0x03]).unwrap();
+        assert_eq!("S02", client.receive());
+
+        client.send("k");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_detach_removes_watchpoints() {
+        let (address, server) = start_server();
+        let mut client = Client::connect(address);
+
+        assert_eq!("OK", client.request("Z4,10,1"));
+        assert_eq!("OK", client.request("Z2,20,1"));
+        assert_eq!("OK", client.request("z3,10,1"));
+
+        assert_eq!("OK", client.request("D"));
+        let watchpoints = server.join().unwrap();
+        assert!(watchpoints.reads.is_empty());
+        assert!(watchpoints.writes.is_empty());
+        assert!(watchpoints.hit.is_none());
+    }
+}
@@ -0,0 +1,32 @@
+#[macro_use]
+extern crate bitfield;
+
+pub mod apu;
+pub mod bus;
+pub mod cartridge;
+pub mod cdl;
+pub mod cheats;
+pub mod controller;
+pub mod cpu;
+pub mod cpu_debug;
+pub mod debugger;
+pub mod disassembler;
+pub mod gdb;
+pub mod movie;
+pub mod nes;
+pub mod overlay;
+pub mod ram_search;
+pub mod ppu;
+pub mod region;
+pub mod rng;
+pub mod rewind;
+pub mod savestate;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod symbols;
+pub mod test_rom;
+
+pub use cartridge::RomError;
+pub use controller::Button;
+pub use nes::Nes;
+pub use savestate::StateError;
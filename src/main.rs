@@ -1,72 +1,90 @@
-#[macro_use]
-extern crate bitfield;
-
-extern crate rand;
-extern crate time;
+extern crate nes_emu;
 
 use std::env;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Instant;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
-use sdl2::audio;
-use sdl2::audio::AudioCallback;
 use sdl2::audio::AudioQueue;
 use sdl2::audio::AudioSpecDesired;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
-use sdl2::libc::SEEK_CUR;
-use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::render::Canvas;
 use sdl2::render::Texture;
 use sdl2::EventPump;
 
-mod apu;
-mod bus;
-mod cartridge;
-mod controller;
-mod cpu;
-mod cpu_debug;
-mod ppu;
-
-use bus::Bus;
-use controller::Button;
-use cpu::Cpu;
-use sdl2::render::TextureCreator;
+use nes_emu::debugger::Debugger;
+use nes_emu::gdb::GdbStub;
+use nes_emu::movie::{Movie, MovieStart};
+use nes_emu::nes::{MovieMode, Port, Region, SCREEN_HEIGHT, SCREEN_WIDTH};
+use nes_emu::rewind::{Rewind, RewindConfig};
+#[cfg(feature = "scripting")]
+use nes_emu::script::Script;
+use nes_emu::Button;
+use nes_emu::Nes;
 use sdl2::video::Window;
-use sdl2::video::WindowContext;
 
 struct NesCore<'a> {
-    cpu: Cpu,
+    nes: Nes,
+    rom_path: PathBuf,
+    persist_battery: bool,
+    saved_battery_ram: Option<Vec<u8>>,
+    frames_since_flush: u32,
+    record_path: Option<PathBuf>,
+    cdl_path: Option<PathBuf>,
+    buttons: u8,
+    save_slot: u8,
+    rewind: Option<Rewind>,
+    rewinding: bool,
+    debugger: Option<Debugger>,
+    gdb: Option<GdbStub>,
+    #[cfg(feature = "scripting")]
+    script: Option<Script>,
+    frame_start: Instant,
     frame_count: u64,
     frame_second: u64,
     event_pump: EventPump,
     canvas: Canvas<Window>,
     texture: Texture<'a>,
-    audio_device: AudioQueue<i16>
+    audio_device: Option<AudioQueue<i16>>,
 }
 
 
 
-impl<'a> NesCore<'_> {
-    fn new(event_pump: EventPump, canvas: Canvas<Window>, texture: Texture<'_>, audio_device: AudioQueue<i16>) -> NesCore {
+impl NesCore<'_> {
+    fn new(nes: Nes, rom_path: PathBuf, event_pump: EventPump, canvas: Canvas<Window>, texture: Texture<'_>, audio_device: Option<AudioQueue<i16>>) -> NesCore<'_> {
         NesCore {
-            cpu: Cpu::new(Bus::new()),
+            nes,
+            rom_path,
+            persist_battery: true,
+            saved_battery_ram: None,
+            frames_since_flush: 0,
+            record_path: None,
+            cdl_path: None,
+            buttons: 0,
+            save_slot: 0,
+            rewind: None,
+            rewinding: false,
+            debugger: None,
+            gdb: None,
+            #[cfg(feature = "scripting")]
+            script: None,
+            frame_start: Instant::now(),
             frame_count: 0,
             frame_second: 0,
             event_pump,
             canvas,
             texture,
-            audio_device
+            audio_device,
         }
     }
 
-    fn load_game(&mut self, game_data: &[u8]) {
-        self.cpu.bus.load_rom_from_memory(game_data);
-        self.cpu.reset();
-        self.cpu.bus.reset();
-    }
-
     fn run(&mut self) {
         self.handle_user_input();
 
@@ -79,146 +97,446 @@ impl<'a> NesCore<'_> {
             self.frame_second = second;
         }
 
-        while !self.cpu.bus.draw {
-            self.cpu.execute_next_instruction();
-            let stall_cycles = self.cpu.bus.reset_cpu_stall_cycles();
-            for _ in 0..stall_cycles {
-                self.cpu.bus.tick()
+        if let Some(ref mut gdb) = self.gdb {
+            if let Err(e) = gdb.poll(&mut self.nes) {
+                eprintln!("gdb connection dropped: {}", e);
             }
         }
 
-        self.cpu.bus.draw = false;
+        let playing = self.nes.movie_mode().is_some();
+        if self.gdb.as_ref().is_some_and(|g| g.halted()) {
+            // The GDB client has the machine stopped
+        } else if self.debugger.as_ref().is_some_and(|d| d.paused) {
+            self.debug_prompt();
+        } else if self.rewinding && !playing {
+            if let Some(ref mut rewind) = self.rewind {
+                rewind.step_back(&mut self.nes);
+            }
+        } else {
+            // A rewind leaves the recorded buttons in the controller
+            self.nes.set_buttons(Port::One, self.buttons);
+            // Movies override the buttons inside the frame, so rewind would replay the wrong input
+            if !playing {
+                if let Some(ref mut rewind) = self.rewind {
+                    rewind.record(&self.nes);
+                }
+            }
+            self.step_frame();
+            if playing && self.nes.movie_mode().is_none() {
+                println!("Movie finished");
+            }
+        }
 
-        let mut video_frame = [0; 256 * 240 * 4];
+        let mut video_frame = [0; SCREEN_WIDTH * SCREEN_HEIGHT * 4];
 
+        let pixels = self.nes.frame_buffer();
         for i in 0..video_frame.len() {
-            let pixel = self.cpu.bus.ppu.renderer.pixels[i / 4];
+            let pixel = pixels[i / 4];
             video_frame[i] = (pixel >> (i % 4 * 8)) as u8;
         }
 
-        let _ = self.texture.update(None, &video_frame, 256 * 4).unwrap();
+        let _ = self.texture.update(None, &video_frame, SCREEN_WIDTH * 4).unwrap();
         self.canvas.copy(&self.texture, None, None).unwrap();
         self.canvas.present();
 
-        let audio_buffer_size = self.cpu.bus.apu.buffer.len();
-        if audio_buffer_size < 1470 {
-            for _ in 0..1470 - audio_buffer_size {
-                self.cpu.bus.apu.buffer.push(0);
+        let mut audio_buffer = self.nes.drain_audio();
+        if let Some(ref audio_device) = self.audio_device {
+            if audio_buffer.len() < 1470 {
+                audio_buffer.resize(1470, 0);
             }
+            audio_device.queue(&audio_buffer[..]);
+        }
+
+        // vsync alone paces us at the monitor's refresh rate, which is too fast for PAL
+        let elapsed = self.frame_start.elapsed();
+        let frame_duration = self.nes.region().frame_duration();
+        if elapsed < frame_duration {
+            thread::sleep(frame_duration - elapsed);
         }
-        self.audio_device.queue(&self.cpu.bus.apu.buffer[..]);
-        // self.audio_device.resume();
-        self.cpu.bus.apu.buffer.clear();
+        self.frame_start = Instant::now();
 
         self.frame_count += 1;
+
+        self.frames_since_flush += 1;
+        if self.frames_since_flush >= BATTERY_FLUSH_FRAMES {
+            self.flush_battery_ram();
+        }
+    }
+
+    // The debugger and GDB step the machine themselves, so a script only runs
+    // while neither of them is in charge
+    fn step_frame(&mut self) {
+        if let Some(ref mut gdb) = self.gdb {
+            if gdb.connected() {
+                gdb.run_frame(&mut self.nes);
+                return;
+            }
+        }
+        match self.debugger {
+            Some(ref mut debugger) => {
+                if let Some(reason) = debugger.run_frame(&mut self.nes) {
+                    println!("{}", reason);
+                    println!("{}", debugger.command(&mut self.nes, "d"));
+                }
+            }
+            None => self.step_script_frame(),
+        }
+    }
+
+    fn step_script_frame(&mut self) {
+        #[cfg(feature = "scripting")]
+        if let Some(ref mut script) = self.script {
+            if let Err(e) = script.run_frame(&mut self.nes) {
+                eprintln!("{}, stopping the script", e);
+                self.script = None;
+            }
+            return;
+        }
+        self.nes.step_frame();
+    }
+
+    fn load_script(&mut self, path: &Path) -> Result<(), String> {
+        #[cfg(feature = "scripting")]
+        {
+            let source = std::fs::read_to_string(path)
+                .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+            let script = Script::load(&mut self.nes, &source)
+                .map_err(|e| format!("could not load {}: {}", path.display(), e))?;
+            self.script = Some(script);
+            Ok(())
+        }
+        #[cfg(not(feature = "scripting"))]
+        Err(format!(
+            "cannot run {}, this build has no scripting (build with --features scripting)",
+            path.display()
+        ))
+    }
+
+    // Blocks on stdin for one command, the window stays frozen meanwhile
+    fn debug_prompt(&mut self) {
+        print!("> ");
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => self.quit(),
+            Ok(_) => (),
+        }
+        if let "q" | "quit" = line.trim() {
+            self.quit();
+        }
+        if let Some(ref mut debugger) = self.debugger {
+            let output = debugger.command(&mut self.nes, &line);
+            if !output.is_empty() {
+                println!("{}", output);
+            }
+        }
+    }
+
+    fn break_into_debugger(&mut self) {
+        #[cfg(feature = "scripting")]
+        if self.script.is_some() && self.debugger.is_none() {
+            println!("The script stops running now that the debugger is in charge");
+        }
+        let debugger = self.debugger.get_or_insert_with(Debugger::new);
+        if !debugger.paused {
+            debugger.paused = true;
+            println!("Paused, type help for debugger commands");
+        }
+    }
+
+    fn quit(&mut self) -> ! {
+        self.flush_battery_ram();
+        self.finish_movie();
+        self.save_code_data_log();
+        std::process::exit(0);
+    }
+
+    fn battery_path(&self) -> PathBuf {
+        self.rom_path.with_extension("sav")
+    }
+
+    fn load_battery_ram(&mut self) -> Result<(), String> {
+        if !self.nes.has_battery() {
+            return Ok(());
+        }
+        let path = self.battery_path();
+        match std::fs::read(&path) {
+            Ok(data) => self.nes.load_battery_ram(&data),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(format!("could not read {}: {}", path.display(), e)),
+        }
+        self.saved_battery_ram = self.nes.battery_ram();
+        Ok(())
+    }
+
+    // Only touches the disk when the game has actually written to its save RAM
+    fn flush_battery_ram(&mut self) {
+        self.frames_since_flush = 0;
+        if !self.persist_battery {
+            return;
+        }
+        let ram = self.nes.battery_ram();
+        if ram.is_none() || ram == self.saved_battery_ram {
+            return;
+        }
+        let path = self.battery_path();
+        match std::fs::write(&path, ram.as_ref().unwrap()) {
+            Ok(()) => self.saved_battery_ram = ram,
+            Err(e) => eprintln!("could not write {}: {}", path.display(), e),
+        }
+    }
+
+    // Movies need a known starting point, so they leave the .sav file alone
+    fn start_movie(&mut self, play: Option<PathBuf>, record: Option<PathBuf>) -> Result<(), String> {
+        if let Some(path) = play {
+            let movie = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| Movie::parse_fm2(&text).map_err(|e| e.to_string()))
+                .map_err(|e| format!("could not read movie {}: {}", path.display(), e))?;
+            self.nes
+                .play_movie(movie)
+                .map_err(|e| format!("could not play movie {}: {}", path.display(), e))?;
+            self.persist_battery = false;
+        }
+        if let Some(path) = record {
+            self.nes.record_movie(MovieStart::PowerOn);
+            self.record_path = Some(path);
+            self.persist_battery = false;
+        }
+        Ok(())
+    }
+
+    fn finish_movie(&mut self) {
+        let path = match self.record_path.take() {
+            Some(p) => p,
+            None => return,
+        };
+        if self.nes.movie_mode() != Some(MovieMode::Recording) {
+            return;
+        }
+        let mut movie = self.nes.stop_movie().unwrap();
+        if let Some(name) = self.rom_path.file_stem() {
+            movie.rom_filename = name.to_string_lossy().into_owned();
+        }
+        match std::fs::write(&path, movie.to_fm2()) {
+            Ok(()) => println!("Saved movie to {}", path.display()),
+            Err(e) => eprintln!("could not save movie to {}: {}", path.display(), e),
+        }
+    }
+
+    // An existing log is added to, so coverage builds up over several sessions
+    fn start_code_data_log(&mut self, path: PathBuf) -> Result<(), String> {
+        self.nes.start_code_data_log();
+        match std::fs::read(&path) {
+            Ok(data) => self
+                .nes
+                .load_code_data_log(&data)
+                .map_err(|e| format!("could not load {}: {}", path.display(), e))?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(format!("could not read {}: {}", path.display(), e)),
+        }
+        self.cdl_path = Some(path);
+        Ok(())
+    }
+
+    fn save_code_data_log(&mut self) {
+        let (path, data) = match (self.cdl_path.take(), self.nes.code_data_log()) {
+            (Some(path), Some(data)) => (path, data),
+            _ => return,
+        };
+        match std::fs::write(&path, data) {
+            Ok(()) => println!("Saved code/data log to {}", path.display()),
+            Err(e) => eprintln!("could not save code/data log to {}: {}", path.display(), e),
+        }
+    }
+
+    fn toggle_cheats(&mut self) {
+        let cheats = &mut self.nes.cpu.bus.cheats;
+        if cheats.is_empty() {
+            return;
+        }
+        let active = !cheats.active();
+        cheats.set_active(active);
+        println!("Cheats {}", if active { "on" } else { "off" });
+    }
+
+    fn set_save_slot(&mut self, slot: u8) {
+        self.save_slot = slot;
+        let title = format!("NES Emulator [slot {}]", slot);
+        let _ = self.canvas.window_mut().set_title(&title);
+    }
+
+    fn save_state_path(&self) -> PathBuf {
+        self.rom_path.with_extension(format!("ss{}", self.save_slot))
+    }
+
+    fn save_state(&mut self) {
+        let path = self.save_state_path();
+        match std::fs::write(&path, self.nes.save_state()) {
+            Ok(()) => println!("Saved state to {}", path.display()),
+            Err(e) => eprintln!("could not save state to {}: {}", path.display(), e),
+        }
+    }
+
+    fn load_state(&mut self) {
+        let path = self.save_state_path();
+        let result = std::fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| self.nes.load_state(&data).map_err(|e| e.to_string()));
+        match result {
+            Ok(()) => {
+                if let Some(ref mut rewind) = self.rewind {
+                    rewind.snapshot(&self.nes);
+                }
+                println!("Loaded state from {}", path.display())
+            }
+            Err(e) => eprintln!("could not load state from {}: {}", path.display(), e),
+        }
+    }
+
+    fn set_button(&mut self, button: Button, pressed: bool) {
+        self.buttons &= !(button as u8);
+        if pressed {
+            self.buttons |= button as u8;
+        }
+        self.nes.set_buttons(Port::One, self.buttons);
     }
 
     fn handle_user_input(&mut self) {
         use Button::*;
-        for event in self.event_pump.poll_iter() {
+        let events: Vec<Event> = self.event_pump.poll_iter().collect();
+        for event in events {
             match event {
                 Event::Quit { .. }
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => {
-                    std::process::exit(0);
+                } => self.quit(),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    ..
+                } => self.break_into_debugger(),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    ..
+                } => self.save_state(),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F8),
+                    ..
+                } => self.toggle_cheats(),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F7),
+                    ..
+                } => self.load_state(),
+                Event::KeyDown {
+                    keycode: Some(k),
+                    ..
+                } if (Keycode::Num0 as i32..=Keycode::Num9 as i32).contains(&(k as i32)) => {
+                    self.set_save_slot((k as i32 - Keycode::Num0 as i32) as u8);
                 }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => self.rewinding = self.rewind.is_some(),
+                Event::KeyUp {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => self.rewinding = false,
                 Event::KeyDown {
                     keycode: Some(Keycode::W),
                     ..
                 } => {
-                    self.cpu.bus.controller_0.set_button_state(UP, true);
+                    self.set_button(UP, true);
                 }
                 Event::KeyDown {
                     keycode: Some(Keycode::S),
                     ..
                 } => {
-                    self.cpu.bus.controller_0.set_button_state(DOWN, true);
+                    self.set_button(DOWN, true);
                 }
                 Event::KeyDown {
                     keycode: Some(Keycode::A),
                     ..
                 } => {
-                    self.cpu.bus.controller_0.set_button_state(LEFT, true);
+                    self.set_button(LEFT, true);
                 }
                 Event::KeyDown {
                     keycode: Some(Keycode::D),
                     ..
                 } => {
-                    self.cpu.bus.controller_0.set_button_state(RIGHT, true);
+                    self.set_button(RIGHT, true);
                 }
                 Event::KeyDown {
                     keycode: Some(Keycode::K),
                     ..
                 } => {
-                    self.cpu.bus.controller_0.set_button_state(A, true);
+                    self.set_button(A, true);
                 }
                 Event::KeyDown {
                     keycode: Some(Keycode::L),
                     ..
                 } => {
-                    self.cpu.bus.controller_0.set_button_state(B, true);
+                    self.set_button(B, true);
                 }
                 Event::KeyDown {
                     keycode: Some(Keycode::N),
                     ..
                 } => {
-                    self.cpu.bus.controller_0.set_button_state(START, true);
+                    self.set_button(START, true);
                 }
                 Event::KeyDown {
                     keycode: Some(Keycode::M),
                     ..
                 } => {
-                    self.cpu.bus.controller_0.set_button_state(SELECT, true);
+                    self.set_button(SELECT, true);
                 }
                 Event::KeyUp {
                     keycode: Some(Keycode::W),
                     ..
                 } => {
-                    self.cpu.bus.controller_0.set_button_state(UP, false);
+                    self.set_button(UP, false);
                 }
                 Event::KeyUp {
                     keycode: Some(Keycode::S),
                     ..
                 } => {
-                    self.cpu.bus.controller_0.set_button_state(DOWN, false);
+                    self.set_button(DOWN, false);
                 }
                 Event::KeyUp {
                     keycode: Some(Keycode::A),
                     ..
                 } => {
-                    self.cpu.bus.controller_0.set_button_state(LEFT, false);
+                    self.set_button(LEFT, false);
                 }
                 Event::KeyUp {
                     keycode: Some(Keycode::D),
                     ..
                 } => {
-                    self.cpu.bus.controller_0.set_button_state(RIGHT, false);
+                    self.set_button(RIGHT, false);
                 }
                 Event::KeyUp {
                     keycode: Some(Keycode::K),
                     ..
                 } => {
-                    self.cpu.bus.controller_0.set_button_state(A, false);
+                    self.set_button(A, false);
                 }
                 Event::KeyUp {
                     keycode: Some(Keycode::L),
                     ..
                 } => {
-                    self.cpu.bus.controller_0.set_button_state(B, false);
+                    self.set_button(B, false);
                 }
                 Event::KeyUp {
                     keycode: Some(Keycode::N),
                     ..
                 } => {
-                    self.cpu.bus.controller_0.set_button_state(START, false);
+                    self.set_button(START, false);
                 }
                 Event::KeyUp {
                     keycode: Some(Keycode::M),
                     ..
                 } => {
-                    self.cpu.bus.controller_0.set_button_state(SELECT, false);
+                    self.set_button(SELECT, false);
                 }
                 _ => {}
             }
@@ -226,37 +544,304 @@ impl<'a> NesCore<'_> {
     }
 }
 
-fn main() {
-    env::set_var("RUST_BACKTRACE", "1");
-    let sdl_context = sdl2::init().unwrap();
-    let video_subsystem = sdl_context.video().unwrap();
+// Roughly every five seconds
+const BATTERY_FLUSH_FRAMES: u32 = 300;
+
+const USAGE: &str = "Usage: nes_emu [OPTIONS] <ROM>
+
+Options:
+    --scale <N>       Window scale factor (default: 2)
+    --no-audio        Disable audio output
+    --region <NAME>   Override the console region: ntsc, pal or dendy
+    --slot <N>        Starting save state slot, 0-9 (default: 0)
+    --rewind <SECS>   Length of the rewind buffer, 0 disables it (default: 30)
+    --rewind-memory <MB>
+                      Memory limit for the rewind buffer (default: 64)
+    --seed <N>        Seed for console-to-console variation (default: fixed)
+    --play <FILE>     Play back an .fm2 input movie
+    --record <FILE>   Record an .fm2 input movie from power-on, saved on exit
+    --debug           Start paused in the stdin debugger (F12 breaks in later)
+    --gdb <PORT>      Accept a GDB remote debugger on localhost:PORT
+    --symbols <FILE>  Load labels from a ca65 .dbg or FCEUX .nl file, can be repeated
+    --cdl <FILE>      Log which ROM bytes are code or data to an FCEUX .cdl file on exit
+    --script <FILE>   Run a Rhai script alongside the game (needs --features scripting)
+    -h, --help        Print this message";
+
+struct Options {
+    rom_path: PathBuf,
+    scale: u32,
+    audio: bool,
+    region: Option<Region>,
+    save_slot: u8,
+    rewind: Option<RewindConfig>,
+    seed: Option<u64>,
+    play_movie: Option<PathBuf>,
+    record_movie: Option<PathBuf>,
+    debug: bool,
+    gdb_port: Option<u16>,
+    symbols: Vec<PathBuf>,
+    cdl: Option<PathBuf>,
+    script: Option<PathBuf>,
+}
+
+impl Options {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Self>, String> {
+        let mut rom_path = None;
+        let mut options = Options {
+            rom_path: PathBuf::new(),
+            scale: 2,
+            audio: true,
+            region: None,
+            save_slot: 0,
+            rewind: None,
+            seed: None,
+            play_movie: None,
+            record_movie: None,
+            debug: false,
+            gdb_port: None,
+            symbols: Vec::new(),
+            cdl: None,
+            script: None,
+        };
+        let mut rewind = RewindConfig::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--no-audio" => options.audio = false,
+                "--debug" => options.debug = true,
+                "--scale" => {
+                    let value = option_value(&mut args, &arg)?;
+                    options.scale = match value.parse() {
+                        Ok(n) if n > 0 && n <= 8 => n,
+                        _ => return Err(format!("invalid scale '{}', expected 1-8", value)),
+                    };
+                }
+                "--region" => {
+                    let value = option_value(&mut args, &arg)?;
+                    options.region = Some(match value.to_lowercase().as_str() {
+                        "ntsc" => Region::Ntsc,
+                        "pal" => Region::Pal,
+                        "dendy" => Region::Dendy,
+                        _ => return Err(format!("unknown region '{}'", value)),
+                    });
+                }
+                "--slot" => {
+                    let value = option_value(&mut args, &arg)?;
+                    options.save_slot = match value.parse() {
+                        Ok(n) if n <= 9 => n,
+                        _ => return Err(format!("invalid save slot '{}', expected 0-9", value)),
+                    };
+                }
+                "--rewind" => {
+                    let value = option_value(&mut args, &arg)?;
+                    rewind.seconds = value
+                        .parse()
+                        .map_err(|_| format!("invalid rewind length '{}'", value))?;
+                }
+                "--rewind-memory" => {
+                    let value = option_value(&mut args, &arg)?;
+                    rewind.max_bytes = match value.parse::<usize>() {
+                        Ok(n) if n > 0 => n * 1024 * 1024,
+                        _ => return Err(format!("invalid rewind memory '{}'", value)),
+                    };
+                }
+                "--seed" => {
+                    let value = option_value(&mut args, &arg)?;
+                    options.seed = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("invalid seed '{}'", value))?,
+                    );
+                }
+                "--gdb" => {
+                    let value = option_value(&mut args, &arg)?;
+                    options.gdb_port = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("invalid port '{}'", value))?,
+                    );
+                }
+                "--symbols" => options.symbols.push(PathBuf::from(option_value(&mut args, &arg)?)),
+                "--script" => options.script = Some(PathBuf::from(option_value(&mut args, &arg)?)),
+                "--cdl" => options.cdl = Some(PathBuf::from(option_value(&mut args, &arg)?)),
+                "--play" => options.play_movie = Some(PathBuf::from(option_value(&mut args, &arg)?)),
+                "--record" => options.record_movie = Some(PathBuf::from(option_value(&mut args, &arg)?)),
+                a if a.starts_with('-') => return Err(format!("unknown option '{}'", a)),
+                a => {
+                    if rom_path.is_some() {
+                        return Err(format!("unexpected argument '{}'", a));
+                    }
+                    rom_path = Some(PathBuf::from(a));
+                }
+            }
+        }
+
+        if options.play_movie.is_some() && options.record_movie.is_some() {
+            return Err("--play and --record cannot be used together".to_string());
+        }
+
+        match rom_path {
+            Some(p) => {
+                options.rom_path = p;
+                options.rewind = if rewind.seconds > 0 { Some(rewind) } else { None };
+                Ok(Some(options))
+            }
+            None => Err("no ROM file given".to_string()),
+        }
+    }
+}
+
+fn option_value<I: Iterator<Item = String>>(args: &mut I, name: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("missing value for '{}'", name))
+}
+
+// Loads the given symbol files, plus any next to the ROM that follow the ld65
+// (game.dbg) or FCEUX (game.nes.ram.nl, game.nes.0.nl...) naming
+fn load_symbols(nes: &mut Nes, rom_path: &Path, extra: &[PathBuf]) -> Result<(), String> {
+    let mut paths = vec![rom_path.with_extension("dbg")];
+    let rom_name = rom_path.to_string_lossy();
+    paths.push(PathBuf::from(format!("{}.ram.nl", rom_name)));
+    for bank in 0..256 {
+        paths.push(PathBuf::from(format!("{}.{:X}.nl", rom_name, bank)));
+    }
+    let found = paths.into_iter().filter(|p| p.is_file());
+
+    let symbols = &mut nes.cpu.bus.symbols;
+    for path in found.chain(extra.iter().cloned()) {
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let result = match file_name.rsplit('.').collect::<Vec<_>>().as_slice() {
+            ["dbg", ..] => symbols.parse_dbg(&text),
+            ["nl", "ram", ..] => symbols.parse_nl(&text, None),
+            ["nl", bank, ..] => match usize::from_str_radix(bank, 16) {
+                Ok(bank) => symbols.parse_nl(&text, Some(bank)),
+                Err(_) => return Err(format!("no bank number in {}", path.display())),
+            },
+            _ => return Err(format!("unknown symbol file type {}", path.display())),
+        };
+        result.map_err(|e| format!("could not load {}: {}", path.display(), e))?;
+    }
+    if !symbols.is_empty() {
+        println!("Loaded {} symbols", symbols.len());
+    }
+    Ok(())
+}
+
+// Cheats for a game live next to it in game.cht, see the cheats module
+fn load_cheats(nes: &mut Nes, rom_path: &Path) -> Result<(), String> {
+    let path = rom_path.with_extension("cht");
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("could not read {}: {}", path.display(), e)),
+    };
+    let cheats = &mut nes.cpu.bus.cheats;
+    cheats
+        .parse(&text)
+        .map_err(|e| format!("could not load {}: {}", path.display(), e))?;
+    println!("Loaded {} cheats, F8 switches them on and off", cheats.cheats().len());
+    Ok(())
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let bytes = std::fs::read(&options.rom_path)
+        .map_err(|e| format!("could not read {}: {}", options.rom_path.display(), e))?;
+    let mut nes = Nes::new();
+    if let Some(seed) = options.seed {
+        nes.set_seed(seed);
+    }
+    nes.load_rom(&bytes)
+        .map_err(|e| format!("could not load {}: {}", options.rom_path.display(), e))?;
+    if let Some(region) = options.region {
+        nes.set_region(region);
+    }
+    load_symbols(&mut nes, &options.rom_path, &options.symbols)?;
+    load_cheats(&mut nes, &options.rom_path)?;
+
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
     let window = video_subsystem
-        .window("NES Emulator", (32 * 20) as u32, (32 * 20) as u32)
+        .window(
+            "NES Emulator",
+            SCREEN_WIDTH as u32 * options.scale,
+            SCREEN_HEIGHT as u32 * options.scale,
+        )
         .position_centered()
         .build()
-        .unwrap();
+        .map_err(|e| e.to_string())?;
 
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
-    canvas.set_scale(2.0, 2.0).unwrap();
+    let canvas = window
+        .into_canvas()
+        .present_vsync()
+        .build()
+        .map_err(|e| e.to_string())?;
 
     let binding = canvas.texture_creator();
-    let texture = binding.create_texture_target(PixelFormatEnum::ARGB8888, 256, 240).unwrap();
-    let audio_subsystem = sdl_context.audio().unwrap();
-    let desired_audio_spec = AudioSpecDesired {
-        freq: Some(44100),
-        channels: Some(1),
-        samples: None
-    };
-    let audio_device = audio_subsystem.open_queue::<i16, _>(None, &desired_audio_spec).unwrap();
-    audio_device.resume();
-
-    let mut nes_core = NesCore::new(sdl_context.event_pump().unwrap(), canvas, texture, audio_device);
+    let texture = binding
+        .create_texture_target(PixelFormatEnum::ARGB8888, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
+        .map_err(|e| e.to_string())?;
 
-    let bytes: Vec<u8> = std::fs::read("games/Super Mario Bros. (World).nes").unwrap();
+    let audio_device = if options.audio {
+        let audio_subsystem = sdl_context.audio()?;
+        let desired_audio_spec = AudioSpecDesired {
+            freq: Some(44100),
+            channels: Some(1),
+            samples: None
+        };
+        let audio_device = audio_subsystem.open_queue::<i16, _>(None, &desired_audio_spec)?;
+        audio_device.resume();
+        Some(audio_device)
+    } else {
+        None
+    };
 
-    nes_core.load_game(&bytes);
+    let mut nes_core = NesCore::new(nes, options.rom_path, sdl_context.event_pump()?, canvas, texture, audio_device);
+    nes_core.set_save_slot(options.save_slot);
+    nes_core.start_movie(options.play_movie, options.record_movie)?;
+    if nes_core.persist_battery {
+        nes_core.load_battery_ram()?;
+    }
+    if let Some(path) = options.cdl {
+        nes_core.start_code_data_log(path)?;
+    }
+    if let Some(path) = options.script {
+        nes_core.load_script(&path)?;
+    }
+    let frame_rate = nes_core.nes.region().frame_rate();
+    nes_core.rewind = options.rewind.map(|config| Rewind::new(config, frame_rate));
+    if options.debug {
+        nes_core.break_into_debugger();
+    }
+    if let Some(port) = options.gdb_port {
+        let gdb = GdbStub::bind(("127.0.0.1", port))
+            .map_err(|e| format!("could not listen on port {}: {}", port, e))?;
+        println!("Waiting for GDB on localhost:{}", port);
+        nes_core.gdb = Some(gdb);
+    }
 
     loop {
         nes_core.run();
     }
 }
+
+fn main() {
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(options) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,298 @@
+// Input movies in the FCEUX .fm2 text format. A movie is a starting point
+// (power-on or a save state) followed by the controller state for every frame.
+//
+// Only text input logs with standard gamepads are supported. Save state
+// starts hold one of our own save states, so those movies only play back here.
+
+use std::error::Error;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::savestate::hash;
+
+// Commands that run at the start of a frame, before its input
+pub const COMMAND_RESET: u8 = 0b01;
+pub const COMMAND_POWER: u8 = 0b10;
+
+// The order buttons appear in on an input line, from bit 7 down to bit 0
+const BUTTON_CHARS: &[u8; 8] = b"RLDUTSBA";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MovieError {
+    UnsupportedVersion(String),
+    BinaryInput,
+    BadSaveState,
+    BadInput(usize),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::UnsupportedVersion(v) => write!(f, "movie version {} is not supported", v),
+            MovieError::BinaryInput => write!(f, "binary movie input is not supported"),
+            MovieError::BadSaveState => write!(f, "movie save state is not valid base64"),
+            MovieError::BadInput(line) => write!(f, "bad input on line {}", line),
+        }
+    }
+}
+
+impl Error for MovieError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MovieStart {
+    PowerOn,
+    SaveState(Vec<u8>),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct MovieFrame {
+    pub commands: u8,
+    // `Button` bitmasks for ports 0 and 1
+    pub buttons: [u8; 2],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movie {
+    pub start: MovieStart,
+    pub frames: Vec<MovieFrame>,
+    pub pal: bool,
+    pub rerecord_count: u32,
+    pub rom_filename: String,
+    // Kept as found so that FCEUX movies survive a round trip. We do not compute it.
+    pub rom_checksum: String,
+    pub guid: String,
+    pub comments: Vec<String>,
+}
+
+impl Movie {
+    pub fn new(start: MovieStart) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        let a = hash(&now.to_le_bytes());
+        let b = hash(&a.to_le_bytes());
+        let guid = format!(
+            "{:08X}-{:04X}-{:04X}-{:04X}-{:012X}",
+            a >> 32,
+            (a >> 16) & 0xFFFF,
+            a & 0xFFFF,
+            b >> 48,
+            b & 0xFFFF_FFFF_FFFF
+        );
+
+        Movie {
+            start,
+            frames: Vec::new(),
+            pal: false,
+            rerecord_count: 0,
+            rom_filename: String::new(),
+            rom_checksum: String::new(),
+            guid,
+            comments: Vec::new(),
+        }
+    }
+
+    pub fn parse_fm2(text: &str) -> Result<Self, MovieError> {
+        let mut movie = Movie::new(MovieStart::PowerOn);
+        movie.guid.clear();
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.starts_with('|') {
+                movie
+                    .frames
+                    .push(parse_input(line).ok_or(MovieError::BadInput(n + 1))?);
+                continue;
+            }
+
+            let (key, value) = match line.split_once(' ') {
+                Some((k, v)) => (k, v),
+                None => (line, ""),
+            };
+            match key {
+                "version" if value != "3" => {
+                    return Err(MovieError::UnsupportedVersion(value.to_string()))
+                }
+                "binary" if value != "0" => return Err(MovieError::BinaryInput),
+                "palFlag" => movie.pal = value == "1",
+                "rerecordCount" => movie.rerecord_count = value.parse().unwrap_or(0),
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "romChecksum" => movie.rom_checksum = value.to_string(),
+                "guid" => movie.guid = value.to_string(),
+                "comment" => movie.comments.push(value.to_string()),
+                "savestate" => {
+                    let data = value.strip_prefix("base64:").unwrap_or(value);
+                    let state = base64_decode(data).ok_or(MovieError::BadSaveState)?;
+                    movie.start = MovieStart::SaveState(state);
+                }
+                _ => (),
+            }
+        }
+        Ok(movie)
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let mut out = String::new();
+        out.push_str("version 3\n");
+        out.push_str("emuVersion 0\n");
+        out.push_str(&format!("rerecordCount {}\n", self.rerecord_count));
+        out.push_str(&format!("palFlag {}\n", self.pal as u8));
+        out.push_str(&format!("romFilename {}\n", self.rom_filename));
+        out.push_str(&format!("romChecksum {}\n", self.rom_checksum));
+        out.push_str(&format!("guid {}\n", self.guid));
+        out.push_str("fourscore 0\nmicrophone 0\nport0 1\nport1 1\nport2 0\nFDS 0\nNewPPU 0\n");
+        for comment in &self.comments {
+            out.push_str(&format!("comment {}\n", comment));
+        }
+        if let MovieStart::SaveState(ref state) = self.start {
+            out.push_str(&format!("savestate base64:{}\n", base64_encode(state)));
+        }
+
+        for frame in &self.frames {
+            out.push_str(&format!("|{}|", frame.commands));
+            for &buttons in &frame.buttons {
+                for (i, &c) in BUTTON_CHARS.iter().enumerate() {
+                    let pressed = buttons & (0x80 >> i) != 0;
+                    out.push(if pressed { c as char } else { '.' });
+                }
+                out.push('|');
+            }
+            out.push_str("|\n");
+        }
+        out
+    }
+}
+
+// |commands|port0|port1|port2|, where an empty port field means nothing is plugged in
+fn parse_input(line: &str) -> Option<MovieFrame> {
+    let mut fields = line.split('|').skip(1);
+    let mut frame = MovieFrame {
+        commands: fields.next()?.trim().parse().ok()?,
+        buttons: [0; 2],
+    };
+    for buttons in frame.buttons.iter_mut() {
+        let field = fields.next()?.as_bytes();
+        if field.is_empty() {
+            continue;
+        }
+        if field.len() != 8 {
+            return None;
+        }
+        for (i, &c) in field.iter().enumerate() {
+            if c != b'.' && c != b' ' {
+                *buttons |= 0x80 >> i;
+            }
+        }
+    }
+    Some(frame)
+}
+
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_CHARS[(n >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut n = 0u32;
+    let mut bits = 0;
+    for c in text.trim_end_matches('=').bytes() {
+        let v = BASE64_CHARS.iter().position(|&b| b == c)? as u32;
+        n = n << 6 | v;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::Button;
+
+    #[test]
+    fn test_parse_fm2() {
+        let text = "version 3\n\
+                    emuVersion 22020\n\
+                    rerecordCount 12\n\
+                    palFlag 0\n\
+                    romFilename Super Mario Bros.\n\
+                    romChecksum base64:jjYwGG411HcjG/j9UOVM3Q==\n\
+                    guid 452DE2C3-EF43-2FA9-77AC-0677FC51543B\n\
+                    comment author someone\n\
+                    |1|........|||\n\
+                    |0|R..U...A|.L......||\n\
+                    |0|    T   |        ||\n";
+        let movie = Movie::parse_fm2(text).unwrap();
+        assert_eq!(MovieStart::PowerOn, movie.start);
+        assert_eq!(12, movie.rerecord_count);
+        assert_eq!("Super Mario Bros.", movie.rom_filename);
+        assert_eq!(vec!["author someone".to_string()], movie.comments);
+        assert_eq!(
+            vec![
+                MovieFrame {
+                    commands: COMMAND_RESET,
+                    buttons: [0, 0]
+                },
+                MovieFrame {
+                    commands: 0,
+                    buttons: [
+                        Button::RIGHT as u8 | Button::UP as u8 | Button::A as u8,
+                        Button::LEFT as u8
+                    ],
+                },
+                MovieFrame {
+                    commands: 0,
+                    buttons: [Button::START as u8, 0]
+                },
+            ],
+            movie.frames
+        );
+
+        let round_trip = Movie::parse_fm2(&movie.to_fm2()).unwrap();
+        assert_eq!(movie, round_trip);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            Err(MovieError::UnsupportedVersion("2".to_string())),
+            Movie::parse_fm2("version 2\n")
+        );
+        assert_eq!(Err(MovieError::BinaryInput), Movie::parse_fm2("binary 1\n"));
+        assert_eq!(
+            Err(MovieError::BadInput(2)),
+            Movie::parse_fm2("version 3\n|0|RL|||\n")
+        );
+    }
+
+    #[test]
+    fn test_base64() {
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foob", b"fooba", b"foobar"] {
+            assert_eq!(Some(data.to_vec()), base64_decode(&base64_encode(data)));
+        }
+        assert_eq!("Zm9vYg==", base64_encode(b"foob"));
+        assert_eq!(None, base64_decode("Zm9v!"));
+    }
+}
@@ -0,0 +1,621 @@
+// Nes is the headless entry point into the emulator core. It owns the whole
+// machine and exposes only what a frontend needs to drive it frame by frame.
+
+use crate::bus::Bus;
+use crate::cartridge::cartridge_header::Timing;
+use crate::cartridge::RomError;
+use crate::cdl::CdlError;
+use crate::cpu::Cpu;
+pub use crate::region::Region;
+use crate::rng::DEFAULT_SEED;
+use crate::movie::{Movie, MovieFrame, MovieStart, COMMAND_POWER, COMMAND_RESET};
+use crate::savestate::{
+    SaveState, StateError, StateReader, StateWriter, STATE_MAGIC, STATE_VERSION,
+};
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+// The console's two controller ports
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Port {
+    One,
+    Two,
+}
+
+impl Port {
+    // Movies and scripts number the ports from 0
+    pub fn from_index(index: usize) -> Option<Self> {
+        match index {
+            0 => Some(Port::One),
+            1 => Some(Port::Two),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MovieMode {
+    Recording,
+    // Holds the index of the next frame to play
+    Playing(usize),
+}
+
+pub struct Nes {
+    pub cpu: Cpu,
+    region: Region,
+    rom: Vec<u8>,
+    seed: u64,
+    movie: Option<Movie>,
+    movie_mode: MovieMode,
+    movie_commands: u8,
+    mid_frame: bool,
+}
+
+impl Default for Nes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Nes {
+    pub fn new() -> Self {
+        Nes {
+            cpu: Cpu::new(Bus::new()),
+            region: Region::Ntsc,
+            rom: Vec::new(),
+            seed: DEFAULT_SEED,
+            movie: None,
+            movie_mode: MovieMode::Recording,
+            movie_commands: 0,
+            mid_frame: false,
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    // Overrides the region the ROM's header asked for, until the next load_rom
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.cpu.bus.set_region(region);
+    }
+
+    // Loading a ROM always starts from a freshly powered on machine
+    pub fn load_rom(&mut self, data: &[u8]) -> Result<(), RomError> {
+        let mut cpu = Cpu::new(Bus::new());
+        cpu.bus.seed(self.seed);
+        cpu.bus.load_rom_from_memory(data)?;
+        self.cpu = cpu;
+        self.rom = data.to_vec();
+        self.mid_frame = false;
+        if let Some(ref c) = self.cpu.bus.cartridge {
+            self.region = match c.borrow().header().timing {
+                Timing::Pal => Region::Pal,
+                Timing::Dendy => Region::Dendy,
+                Timing::Ntsc | Timing::MultiRegion => Region::Ntsc,
+            };
+        }
+        self.cpu.bus.set_region(self.region);
+        self.cpu.reset();
+        self.cpu.bus.reset();
+        Ok(())
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Two machines with the same seed and the same input run identically.
+    // The seed sticks across `load_rom` and `power_on`.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.cpu.bus.seed(seed);
+    }
+
+    // Like pressing the reset button
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.cpu.bus.reset();
+        self.movie_commands |= COMMAND_RESET;
+    }
+
+    // Like switching the console off and on again, which also clears PRG-RAM
+    pub fn power_on(&mut self) {
+        if self.rom.is_empty() {
+            return;
+        }
+        let region = self.region;
+        let symbols = std::mem::take(&mut self.cpu.bus.symbols);
+        let cheats = std::mem::take(&mut self.cpu.bus.cheats);
+        let watchpoints = std::mem::take(&mut self.cpu.bus.watchpoints);
+        let cdl = self.cpu.bus.cartridge.as_ref().and_then(|c| c.borrow_mut().cdl.take());
+        let rom = std::mem::take(&mut self.rom);
+        self.load_rom(&rom).expect("reloading a ROM that loaded before");
+        self.set_region(region);
+        self.cpu.bus.symbols = symbols;
+        self.cpu.bus.cheats = cheats;
+        self.cpu.bus.watchpoints = watchpoints;
+        if let Some(ref c) = self.cpu.bus.cartridge {
+            c.borrow_mut().cdl = cdl;
+        }
+        self.movie_commands |= COMMAND_POWER;
+    }
+
+    // Runs the machine until the PPU signals that a full frame is ready
+    pub fn step_frame(&mut self) {
+        while !self.step_instruction() {}
+    }
+
+    // Runs a single instruction, returning true if it finished a frame
+    pub fn step_instruction(&mut self) -> bool {
+        if !self.mid_frame {
+            self.movie_input();
+            self.cpu.bus.apply_ram_cheats();
+            self.mid_frame = true;
+        }
+
+        self.cpu.execute_next_instruction();
+        let stall_cycles = self.cpu.bus.reset_cpu_stall_cycles();
+        for _ in 0..stall_cycles {
+            self.cpu.bus.tick()
+        }
+
+        if self.cpu.bus.draw {
+            self.cpu.bus.draw = false;
+            self.mid_frame = false;
+            return true;
+        }
+        false
+    }
+
+    fn rom_hash(&self) -> Option<u64> {
+        self.cpu.bus.cartridge.as_ref().map(|c| c.borrow().rom_hash())
+    }
+
+    // Snapshots the whole machine into a versioned binary blob
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.data.extend_from_slice(&STATE_MAGIC);
+        state.write_u16(STATE_VERSION);
+        state.write_u64(self.rom_hash().unwrap_or(0));
+        self.cpu.save_state(&mut state);
+        state.data
+    }
+
+    // Restores a snapshot made by `save_state`. On error the machine is left untouched.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let rom_hash = self.rom_hash().ok_or(StateError::NoCartridge)?;
+        if data.len() < STATE_MAGIC.len() || data[0..4] != STATE_MAGIC {
+            return Err(StateError::BadMagic);
+        }
+
+        let mut state = StateReader::new(&data[4..]);
+        let version = state.read_u16()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        if state.read_u64()? != rom_hash {
+            return Err(StateError::RomMismatch);
+        }
+
+        let backup = self.save_state();
+        let result = self.cpu.load_state(&mut state).and_then(|_| {
+            if state.finished() {
+                Ok(())
+            } else {
+                Err(StateError::Invalid("trailing data"))
+            }
+        });
+        if result.is_err() {
+            let mut state = StateReader::new(&backup[4 + 2 + 8..]);
+            self.cpu
+                .load_state(&mut state)
+                .expect("restoring a fresh snapshot");
+        }
+        result
+    }
+
+    // Starts recording input from here on, either from a save state of the
+    // current machine or after powering it on
+    pub fn record_movie(&mut self, start: MovieStart) {
+        let start = match start {
+            MovieStart::PowerOn => {
+                self.power_on();
+                MovieStart::PowerOn
+            }
+            MovieStart::SaveState(_) => MovieStart::SaveState(self.save_state()),
+        };
+        let mut movie = Movie::new(start);
+        movie.pal = self.region != Region::Ntsc;
+        self.movie = Some(movie);
+        self.movie_mode = MovieMode::Recording;
+        self.movie_commands = 0;
+    }
+
+    // Puts the machine at the movie's starting point and replays its input,
+    // overriding `set_buttons`, until the movie runs out
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), StateError> {
+        match movie.start {
+            MovieStart::PowerOn => self.power_on(),
+            MovieStart::SaveState(ref state) => self.load_state(state)?,
+        }
+        self.movie = Some(movie);
+        self.movie_mode = MovieMode::Playing(0);
+        Ok(())
+    }
+
+    // Ends recording or playback and hands back the movie
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        self.movie.take()
+    }
+
+    pub fn movie_mode(&self) -> Option<MovieMode> {
+        self.movie.as_ref().map(|_| self.movie_mode)
+    }
+
+    // The frame-boundary input hook. Playback sets the controllers before the
+    // frame runs, recording logs what they were set to.
+    fn movie_input(&mut self) {
+        let movie = match self.movie {
+            Some(ref mut m) => m,
+            None => return,
+        };
+        match self.movie_mode {
+            MovieMode::Recording => {
+                movie.frames.push(MovieFrame {
+                    commands: self.movie_commands,
+                    buttons: [
+                        self.cpu.bus.controller_0.button_states(),
+                        self.cpu.bus.controller_1.button_states(),
+                    ],
+                });
+                self.movie_commands = 0;
+            }
+            MovieMode::Playing(i) => {
+                let frame = match movie.frames.get(i) {
+                    Some(&f) => f,
+                    None => {
+                        self.movie = None;
+                        return;
+                    }
+                };
+                self.movie_mode = MovieMode::Playing(i + 1);
+                if frame.commands & COMMAND_POWER != 0 {
+                    self.power_on();
+                } else if frame.commands & COMMAND_RESET != 0 {
+                    self.reset();
+                }
+                self.set_buttons(Port::One, frame.buttons[0]);
+                self.set_buttons(Port::Two, frame.buttons[1]);
+            }
+        }
+    }
+
+    pub fn has_battery(&self) -> bool {
+        match self.cpu.bus.cartridge {
+            Some(ref c) => c.borrow().header().battery,
+            None => false,
+        }
+    }
+
+    // The PRG-RAM contents a frontend should persist, or None if the cart has no battery
+    pub fn battery_ram(&self) -> Option<Vec<u8>> {
+        if !self.has_battery() {
+            return None;
+        }
+        self.cpu
+            .bus
+            .cartridge
+            .as_ref()
+            .map(|c| c.borrow().prg_ram().to_vec())
+    }
+
+    // Restores persisted PRG-RAM. Files of the wrong size are loaded as far
+    // as they fit, which is what other emulators do with foreign .sav files.
+    pub fn load_battery_ram(&mut self, data: &[u8]) {
+        if let Some(ref c) = self.cpu.bus.cartridge {
+            let mut c = c.borrow_mut();
+            let ram = c.prg_ram_mut();
+            let n = ram.len().min(data.len());
+            ram[..n].copy_from_slice(&data[..n]);
+        }
+    }
+
+    // Starts recording how each ROM byte gets used, see the cdl module
+    pub fn start_code_data_log(&mut self) {
+        if let Some(ref c) = self.cpu.bus.cartridge {
+            c.borrow_mut().start_code_data_log();
+        }
+    }
+
+    // Adds a previously saved .cdl file to the log, starting it if needed
+    pub fn load_code_data_log(&mut self, data: &[u8]) -> Result<(), CdlError> {
+        if let Some(ref c) = self.cpu.bus.cartridge {
+            let mut c = c.borrow_mut();
+            c.start_code_data_log();
+            if let Some(ref mut cdl) = c.cdl {
+                cdl.merge(data)?;
+            }
+        }
+        Ok(())
+    }
+
+    // The log as the contents of a .cdl file, or None if it isn't running
+    pub fn code_data_log(&self) -> Option<Vec<u8>> {
+        let c = self.cpu.bus.cartridge.as_ref()?.borrow();
+        c.cdl.as_ref().map(|cdl| cdl.to_bytes())
+    }
+
+    // `buttons` is a bitmask of `Button` values for the given controller port
+    pub fn set_buttons(&mut self, port: Port, buttons: u8) {
+        match port {
+            Port::One => self.cpu.bus.controller_0.set_button_states(buttons),
+            Port::Two => self.cpu.bus.controller_1.set_button_states(buttons),
+        }
+    }
+
+    pub fn buttons(&self, port: Port) -> u8 {
+        match port {
+            Port::One => self.cpu.bus.controller_0.button_states(),
+            Port::Two => self.cpu.bus.controller_1.button_states(),
+        }
+    }
+
+    // One ARGB8888 pixel per entry, SCREEN_WIDTH x SCREEN_HEIGHT
+    pub fn frame_buffer(&self) -> &[u32] {
+        &self.cpu.bus.ppu.renderer.pixels
+    }
+
+    // Takes all the audio samples produced since the last call
+    pub fn drain_audio(&mut self) -> Vec<i16> {
+        std::mem::take(&mut self.cpu.bus.apu.buffer)
+    }
+}
+
+// LDX #$00, loop: INX, STX $10, LDA $10, JMP loop, for the debugger tests to step through
+#[cfg(test)]
+pub(crate) fn build_counter_nes() -> Nes {
+    let program = [0xa2, 0x00, 0xe8, 0x86, 0x10, 0xa5, 0x10, 0x4c, 0x02, 0x80];
+    let mut nes = Nes::new();
+    let rom = crate::cartridge::build_test_rom(0, &crate::cartridge::build_test_prg(&program), &[]);
+    nes.load_rom(&rom).unwrap();
+    nes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::{build_test_prg, build_test_rom};
+    use crate::cdl;
+    use crate::controller::Button;
+    use crate::movie::Movie;
+
+    // An infinite loop at $8000: JMP $8000
+    fn build_rom() -> Vec<u8> {
+        build_test_rom(0, &build_test_prg(&[0x4c, 0x00, 0x80]), &[])
+    }
+
+    #[test]
+    fn test_step_frame() {
+        let mut nes = Nes::new();
+        nes.load_rom(&build_rom()).unwrap();
+        nes.step_frame();
+        let cycles = nes.cpu.bus.cycles;
+        nes.step_frame();
+        // A frame is 341 * 262 / 3 CPU cycles, give or take an instruction
+        let frame = nes.cpu.bus.cycles - cycles;
+        assert!((29_775..=29_785).contains(&frame), "frame took {} cycles", frame);
+        assert_eq!(nes.frame_buffer().len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+    }
+
+    #[test]
+    fn test_region_timing() {
+        // 341 * 312 dots at 3.2 dots per CPU cycle on PAL, and 3 on Dendy
+        for (region, cycles) in [(Region::Pal, 33_247), (Region::Dendy, 35_464)] {
+            let mut nes = Nes::new();
+            nes.load_rom(&build_rom()).unwrap();
+            nes.set_region(region);
+            nes.step_frame();
+            let start = nes.cpu.bus.cycles;
+            nes.step_frame();
+            let frame = nes.cpu.bus.cycles - start;
+            assert!(
+                (cycles - 5..=cycles + 5).contains(&frame),
+                "{:?} frame took {} cycles",
+                region,
+                frame
+            );
+        }
+    }
+
+    #[test]
+    fn test_code_data_log() {
+        let mut nes = Nes::new();
+        nes.load_rom(&build_rom()).unwrap();
+        assert_eq!(nes.code_data_log(), None);
+        nes.start_code_data_log();
+        nes.reset();
+        nes.step_frame();
+        let log = nes.code_data_log().unwrap();
+        assert_eq!(log.len(), 0x8000);
+        assert_eq!(&log[0..4], &[cdl::CODE, cdl::CODE, cdl::CODE, 0]);
+        // The reset vector is read as data through the $E000 window
+        assert_eq!(log[0x7FFC], cdl::DATA | 0b1100);
+
+        nes.cpu.bus.watchpoints.reads.insert(0x8000);
+        nes.cpu.bus.watchpoints.writes.insert(0x0200);
+        nes.power_on();
+        assert_eq!(nes.code_data_log(), Some(log));
+        assert!(nes.cpu.bus.watchpoints.reads.contains(&0x8000));
+        assert!(nes.cpu.bus.watchpoints.writes.contains(&0x0200));
+    }
+
+    #[test]
+    fn test_drain_audio() {
+        let mut nes = Nes::new();
+        nes.load_rom(&build_rom()).unwrap();
+        nes.step_frame();
+        assert!(!nes.drain_audio().is_empty());
+        assert!(nes.drain_audio().is_empty());
+    }
+
+    #[test]
+    fn test_set_buttons() {
+        let mut nes = Nes::new();
+        nes.set_buttons(Port::One, Button::A as u8 | Button::START as u8);
+        nes.cpu.bus.controller_0.write_register(1);
+        nes.cpu.bus.controller_0.write_register(0);
+        let bits: Vec<u8> = (0..8)
+            .map(|_| nes.cpu.bus.controller_0.read_register() & 1)
+            .collect();
+        assert_eq!(bits, vec![1, 0, 0, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_save_state_round_trip() {
+        let mut nes = Nes::new();
+        nes.load_rom(&build_rom()).unwrap();
+        for _ in 0..3 {
+            nes.step_frame();
+        }
+        nes.drain_audio();
+        let state = nes.save_state();
+
+        nes.step_frame();
+        let cycles = nes.cpu.bus.cycles;
+        let pixels = nes.frame_buffer().to_vec();
+        let audio = nes.drain_audio();
+
+        nes.cpu.bus.ram[0x10] = 0x55;
+        nes.load_state(&state).unwrap();
+        assert_eq!(nes.cpu.bus.ram[0x10], 0);
+
+        nes.step_frame();
+        assert_eq!(nes.cpu.bus.cycles, cycles);
+        assert_eq!(nes.frame_buffer(), &pixels[..]);
+        assert_eq!(nes.drain_audio(), audio);
+    }
+
+    #[test]
+    fn test_load_state_errors() {
+        let mut nes = Nes::new();
+        assert_eq!(Err(StateError::NoCartridge), nes.load_state(&[]));
+
+        nes.load_rom(&build_rom()).unwrap();
+        let mut state = nes.save_state();
+        assert_eq!(Err(StateError::BadMagic), nes.load_state(b"NES\x1a"));
+
+        state[4] = 0xff;
+        assert_eq!(
+            Err(StateError::UnsupportedVersion(0xff)),
+            nes.load_state(&state)
+        );
+        state[4] = STATE_VERSION as u8;
+
+        let truncated = &state[..state.len() - 1];
+        nes.cpu.bus.ram[0] = 0x42;
+        assert_eq!(Err(StateError::Truncated), nes.load_state(truncated));
+        assert_eq!(nes.cpu.bus.ram[0], 0x42);
+
+        let mut other = build_rom();
+        other[16] = 0xea;
+        let mut nes = Nes::new();
+        nes.load_rom(&other).unwrap();
+        assert_eq!(Err(StateError::RomMismatch), nes.load_state(&state));
+    }
+
+    #[test]
+    fn test_movie() {
+        let mut nes = Nes::new();
+        nes.load_rom(&build_rom()).unwrap();
+        nes.step_frame();
+
+        nes.record_movie(MovieStart::PowerOn);
+        assert!(nes.cpu.bus.cycles < 10);
+        for i in 0..5 {
+            nes.set_buttons(Port::One, i);
+            nes.step_frame();
+        }
+        nes.reset();
+        nes.step_frame();
+        let cycles = nes.cpu.bus.cycles;
+        let pixels = nes.frame_buffer().to_vec();
+
+        let movie = nes.stop_movie().unwrap();
+        assert_eq!(None, nes.movie_mode());
+        assert_eq!(6, movie.frames.len());
+        assert_eq!([3, 0], movie.frames[3].buttons);
+        assert_eq!(COMMAND_RESET, movie.frames[5].commands);
+
+        let movie = Movie::parse_fm2(&movie.to_fm2()).unwrap();
+        nes.play_movie(movie).unwrap();
+        for i in 0..5 {
+            nes.set_buttons(Port::One, 0xff);
+            nes.step_frame();
+            assert_eq!(i, nes.buttons(Port::One));
+        }
+        nes.step_frame();
+        assert_eq!(cycles, nes.cpu.bus.cycles);
+        assert_eq!(nes.frame_buffer(), &pixels[..]);
+        assert_eq!(Some(MovieMode::Playing(6)), nes.movie_mode());
+
+        nes.step_frame();
+        assert_eq!(None, nes.movie_mode());
+    }
+
+    #[test]
+    fn test_movie_from_save_state() {
+        let mut nes = Nes::new();
+        nes.load_rom(&build_rom()).unwrap();
+        nes.step_frame();
+        nes.drain_audio();
+        nes.record_movie(MovieStart::SaveState(Vec::new()));
+        for _ in 0..3 {
+            nes.step_frame();
+        }
+        let cycles = nes.cpu.bus.cycles;
+        let movie = nes.stop_movie().unwrap();
+
+        nes.step_frame();
+        nes.play_movie(movie).unwrap();
+        for _ in 0..3 {
+            nes.step_frame();
+        }
+        assert_eq!(cycles, nes.cpu.bus.cycles);
+    }
+
+    #[test]
+    fn test_seed() {
+        let run = |seed| {
+            let mut nes = Nes::new();
+            nes.set_seed(seed);
+            nes.load_rom(&build_rom()).unwrap();
+            for _ in 0..2 {
+                nes.step_frame();
+            }
+            nes.save_state()
+        };
+        assert_eq!(run(1), run(1));
+        assert_ne!(run(1), run(2));
+    }
+
+    #[test]
+    fn test_battery_ram() {
+        let mut nes = Nes::new();
+        nes.load_rom(&build_rom()).unwrap();
+        assert!(!nes.has_battery());
+        assert_eq!(None, nes.battery_ram());
+
+        let mut rom = build_rom();
+        rom[6] |= 0b10;
+        nes.load_rom(&rom).unwrap();
+        assert!(nes.has_battery());
+        nes.load_battery_ram(&[1, 2, 3]);
+        nes.cpu.bus.write_byte(0x6003u16, 4);
+        let ram = nes.battery_ram().unwrap();
+        assert_eq!(0x2000, ram.len());
+        assert_eq!(&[1, 2, 3, 4, 0], &ram[0..5]);
+    }
+}
@@ -0,0 +1,134 @@
+// Drawing on top of a finished frame, for scripts and other tools that want
+// to show something over the game. Colors are 0xRRGGBB like the palette, and
+// anything outside the screen is clipped.
+
+use crate::nes::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// Glyphs are 3x5 pixels, one row per byte with the leftmost pixel in bit 2
+const FONT: [(char, [u8; 5]); 56] = [
+    (' ', [0, 0, 0, 0, 0]),
+    ('0', [7, 5, 5, 5, 7]),
+    ('1', [2, 6, 2, 2, 7]),
+    ('2', [7, 1, 7, 4, 7]),
+    ('3', [7, 1, 3, 1, 7]),
+    ('4', [5, 5, 7, 1, 1]),
+    ('5', [7, 4, 7, 1, 7]),
+    ('6', [7, 4, 7, 5, 7]),
+    ('7', [7, 1, 1, 2, 2]),
+    ('8', [7, 5, 7, 5, 7]),
+    ('9', [7, 5, 7, 1, 7]),
+    ('A', [2, 5, 7, 5, 5]),
+    ('B', [6, 5, 6, 5, 6]),
+    ('C', [3, 4, 4, 4, 3]),
+    ('D', [6, 5, 5, 5, 6]),
+    ('E', [7, 4, 6, 4, 7]),
+    ('F', [7, 4, 6, 4, 4]),
+    ('G', [3, 4, 5, 5, 3]),
+    ('H', [5, 5, 7, 5, 5]),
+    ('I', [7, 2, 2, 2, 7]),
+    ('J', [1, 1, 1, 5, 2]),
+    ('K', [5, 5, 6, 5, 5]),
+    ('L', [4, 4, 4, 4, 7]),
+    ('M', [5, 7, 7, 5, 5]),
+    ('N', [6, 5, 5, 5, 5]),
+    ('O', [2, 5, 5, 5, 2]),
+    ('P', [6, 5, 6, 4, 4]),
+    ('Q', [2, 5, 5, 6, 3]),
+    ('R', [6, 5, 6, 5, 5]),
+    ('S', [3, 4, 2, 1, 6]),
+    ('T', [7, 2, 2, 2, 2]),
+    ('U', [5, 5, 5, 5, 7]),
+    ('V', [5, 5, 5, 5, 2]),
+    ('W', [5, 5, 7, 7, 5]),
+    ('X', [5, 5, 2, 5, 5]),
+    ('Y', [5, 5, 2, 2, 2]),
+    ('Z', [7, 1, 2, 4, 7]),
+    ('!', [2, 2, 2, 0, 2]),
+    ('"', [5, 5, 0, 0, 0]),
+    ('#', [5, 7, 5, 7, 5]),
+    ('$', [3, 6, 2, 3, 6]),
+    ('%', [5, 1, 2, 4, 5]),
+    ('\'', [2, 2, 0, 0, 0]),
+    ('(', [1, 2, 2, 2, 1]),
+    (')', [4, 2, 2, 2, 4]),
+    ('*', [5, 2, 7, 2, 5]),
+    ('+', [0, 2, 7, 2, 0]),
+    (',', [0, 0, 0, 2, 4]),
+    ('-', [0, 0, 7, 0, 0]),
+    ('.', [0, 0, 0, 0, 2]),
+    ('/', [1, 1, 2, 4, 4]),
+    (':', [0, 2, 0, 2, 0]),
+    ('<', [1, 2, 4, 2, 1]),
+    ('=', [0, 7, 0, 7, 0]),
+    ('>', [4, 2, 1, 2, 4]),
+    ('?', [6, 1, 2, 0, 2]),
+];
+
+const GLYPH_WIDTH: i32 = 4;
+const LINE_HEIGHT: i32 = 6;
+
+pub fn draw_pixel(pixels: &mut [u32], x: i32, y: i32, color: u32) {
+    if (0..SCREEN_WIDTH as i32).contains(&x) && (0..SCREEN_HEIGHT as i32).contains(&y) {
+        if let Some(p) = pixels.get_mut(y as usize * SCREEN_WIDTH + x as usize) {
+            *p = color & 0xFF_FFFF;
+        }
+    }
+}
+
+// Lowercase letters are drawn as capitals and anything without a glyph as ?
+pub fn draw_text(pixels: &mut [u32], x: i32, y: i32, text: &str, color: u32) {
+    let (mut column, mut row) = (x, y);
+    for c in text.chars() {
+        if c == '\n' {
+            column = x;
+            row += LINE_HEIGHT;
+            continue;
+        }
+        let glyph = glyph(c.to_ascii_uppercase()).unwrap_or_else(|| glyph('?').unwrap());
+        for (dy, bits) in glyph.iter().enumerate() {
+            for dx in 0..3 {
+                if bits & (4 >> dx) != 0 {
+                    draw_pixel(pixels, column + dx, row + dy as i32, color);
+                }
+            }
+        }
+        column += GLYPH_WIDTH;
+    }
+}
+
+fn glyph(c: char) -> Option<[u8; 5]> {
+    FONT.iter().find(|(g, _)| *g == c).map(|(_, rows)| *rows)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_draw_text() {
+        let mut pixels = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        draw_text(&mut pixels, 1, 2, "l1", 0xFFFFFF);
+        let lit: Vec<(usize, usize)> = (0..pixels.len())
+            .filter(|&i| pixels[i] != 0)
+            .map(|i| (i % SCREEN_WIDTH, i / SCREEN_WIDTH))
+            .collect();
+        // An L is its left column and bottom row
+        assert!(lit.contains(&(1, 2)) && lit.contains(&(1, 6)) && lit.contains(&(3, 6)));
+        assert!(!lit.contains(&(2, 2)));
+        // The 1 starts four pixels further on
+        assert!(lit.contains(&(6, 2)) && !lit.contains(&(5, 2)));
+        assert_eq!(lit.len(), 7 + 8);
+    }
+
+    #[test]
+    fn test_clipping() {
+        let mut pixels = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        draw_pixel(&mut pixels, -1, 0, 0xFF0000);
+        draw_pixel(&mut pixels, SCREEN_WIDTH as i32, 0, 0xFF0000);
+        draw_text(&mut pixels, 254, 238, "88", 0xFF0000);
+        // Only the top left corner of the first 8 is on screen
+        assert_eq!(pixels.iter().filter(|&&p| p != 0).count(), 3);
+        draw_pixel(&mut pixels, 0, 0, 0x12345678);
+        assert_eq!(pixels[0], 0x345678);
+    }
+}
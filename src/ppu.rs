@@ -12,12 +12,19 @@ pub mod vram;
 use self::registers::Registers;
 use self::renderer::Renderer;
 use self::result::PpuResult;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 pub struct Ppu {
     pub registers: Registers,
     pub renderer: Renderer,
 }
 
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Ppu {
     pub fn new() -> Self {
         let mut p = Ppu {
@@ -30,15 +37,12 @@ impl Ppu {
 
     pub fn tick(&mut self) -> PpuResult {
         let regs = &mut self.registers;
+        regs.tick();
         let r = self.renderer.tick(regs);
         self.renderer.step();
         r
     }
 
-    pub fn tick_decay(&mut self) {
-        self.registers.tick_decay();
-    }
-
     pub fn reset(&mut self) {
         self.registers.reset();
         self.renderer.reset();
@@ -51,8 +55,24 @@ impl Ppu {
     pub fn read_register(&mut self, address: u16) -> u8 {
         self.registers.read_register(address)
     }
+
+    pub fn peek_register(&self, address: u16) -> u8 {
+        self.registers.peek_register(address)
+    }
 }
 
 fn nth_bit<T: Into<u16>, U: Into<u16>>(x: T, n: U) -> u8 {
     ((x.into() >> n.into()) & 1) as u8
 }
+
+impl SaveState for Ppu {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.registers.save_state(state);
+        self.renderer.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.registers.load_state(state)?;
+        self.renderer.load_state(state)
+    }
+}
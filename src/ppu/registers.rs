@@ -3,8 +3,15 @@ use super::control::Control;
 use super::mask::Mask;
 use super::status::Status;
 use super::vram::Vram;
+use crate::cdl;
+use crate::rng::Rng;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
-use rand::{thread_rng, Rng};
+// Open bus bits hold their charge for around 600ms of PPU cycles. It varies
+// from bit to bit and console to console, so each machine rolls its own.
+const DECAY_CYCLES: u64 = 3_221_591;
+const DECAY_MIN_CYCLES: u64 = 2_684_659;
+const DECAY_MAX_CYCLES: u64 = 3_758_523;
 
 pub struct Registers {
     pub vram: Vram,
@@ -18,6 +25,10 @@ pub struct Registers {
     pub status: Status,
     latch: bool,
     open_bus: u8,
+    // The PPU cycle each open bus bit was last driven on, and how long it lasts after that
+    open_bus_stamps: [u64; 8],
+    decay_cycles: [u64; 8],
+    cycles: u64,
     pub force_nmi: bool,
     pub vblank_suppress: bool,
 }
@@ -36,6 +47,9 @@ impl Registers {
             status: Status(0),
             latch: false,
             open_bus: 0,
+            open_bus_stamps: [0; 8],
+            decay_cycles: [DECAY_CYCLES; 8],
+            cycles: 0,
             force_nmi: false,
             vblank_suppress: false,
         };
@@ -51,8 +65,18 @@ impl Registers {
         self.vram.reset();
     }
 
+    pub fn tick(&mut self) {
+        self.cycles += 1;
+    }
+
+    pub fn randomize_decay(&mut self, rng: &mut Rng) {
+        for cycles in self.decay_cycles.iter_mut() {
+            *cycles = rng.range(DECAY_MIN_CYCLES, DECAY_MAX_CYCLES);
+        }
+    }
+
     pub fn write_register(&mut self, address: u16, value: u8) {
-        self.open_bus = value;
+        self.set_open_bus(value, 0xFF);
         match address % 8 {
             0 => self.write_control(value),
             1 => self.write_mask(value),
@@ -67,36 +91,87 @@ impl Registers {
     }
 
     pub fn read_register(&mut self, address: u16) -> u8 {
-        let result = match address % 8 {
-            0 => self.open_bus,
-            1 => self.open_bus,
-            2 => self.read_status() | (self.open_bus & 0b11111),
-            3 => self.open_bus,
-            4 => self.read_oam_data(),
-            5 => self.open_bus,
-            6 => self.open_bus,
+        self.decay_open_bus();
+        // Only the bits a register actually drives get refreshed, the rest keep decaying
+        let (result, driven) = match address % 8 {
+            0 | 1 | 3 | 5 | 6 => (self.open_bus, 0),
+            2 => (self.read_status() | (self.open_bus & 0b11111), 0b1110_0000),
+            4 => (self.read_oam_data(), 0xFF),
             7 => {
                 if let 0x3f00..=0x3fff = self.v_address.address() {
-                    self.read_data() | (self.open_bus & 0b1100_0000)
+                    (self.read_data() | (self.open_bus & 0b1100_0000), 0b0011_1111)
                 } else {
-                    self.read_data()
+                    (self.read_data(), 0xFF)
                 }
             }
             _ => panic!("Invalid PPU register {:X}", address),
         };
-        self.open_bus = result;
+        self.set_open_bus(result, driven);
         result
     }
 
-    pub fn tick_decay(&mut self) {
-        let mut rng = thread_rng();
+    // A read by the renderer. The cartridge only sees the PPU's reads while
+    // rendering is switched on, the PPU leaves its bus alone otherwise.
+    pub fn fetch_byte(&self, address: u16) -> u8 {
+        if self.mask.rendering() {
+            self.vram.ppu_fetch(address);
+        }
+        self.vram.read_byte(address)
+    }
+
+    // A pattern table fetch, which the code/data logger counts as drawn when
+    // rendering is switched on
+    pub fn fetch_pattern_byte(&self, address: u16) -> u8 {
+        if self.mask.rendering() {
+            self.vram.log_chr(address, cdl::RENDERED);
+        }
+        self.fetch_byte(address)
+    }
+
+    // The value read_register would return, leaving the latch, flags,
+    // addresses and open bus alone
+    pub fn peek_register(&self, address: u16) -> u8 {
+        let open_bus = self.decayed_open_bus();
+        match address % 8 {
+            0 | 1 | 3 | 5 | 6 => open_bus,
+            2 => self.status.get() | (open_bus & 0b11111),
+            4 => self.read_oam_data(),
+            7 => {
+                let address = self.v_address.address();
+                let data = self.vram.peek_buffered_byte(address);
+                if let 0x3f00..=0x3fff = address {
+                    data | (open_bus & 0b1100_0000)
+                } else {
+                    data
+                }
+            }
+            _ => panic!("Invalid PPU register {:X}", address),
+        }
+    }
+
+    fn set_open_bus(&mut self, value: u8, driven: u8) {
+        self.open_bus = value;
         for i in 0..8 {
-            if rng.gen_bool(1.0 / 4.0) {
-                self.open_bus &= !(1 << i);
+            if driven & (1 << i) != 0 {
+                self.open_bus_stamps[i] = self.cycles;
             }
         }
     }
 
+    fn decay_open_bus(&mut self) {
+        self.open_bus = self.decayed_open_bus();
+    }
+
+    fn decayed_open_bus(&self) -> u8 {
+        let mut open_bus = self.open_bus;
+        for i in 0..8 {
+            if self.cycles.saturating_sub(self.open_bus_stamps[i]) >= self.decay_cycles[i] {
+                open_bus &= !(1 << i);
+            }
+        }
+        open_bus
+    }
+
     fn write_control(&mut self, value: u8) {
         let control = Control(value);
         if !self.control.nmi_on_vblank() && control.nmi_on_vblank() {
@@ -119,7 +194,7 @@ impl Registers {
         self.oam_address = self.oam_address.wrapping_add(1);
     }
 
-    fn read_oam_data(&mut self) -> u8 {
+    fn read_oam_data(&self) -> u8 {
         if self.oam_address % 4 == 2 {
             self.oam_ram[self.oam_address as usize] & 0b1110_0011
         } else {
@@ -142,6 +217,7 @@ impl Registers {
         if self.latch {
             self.t_address.set_low_byte(value);
             self.v_address = self.t_address.clone();
+            self.update_bus_address();
         } else {
             self.t_address.set_high_byte(value);
         }
@@ -151,6 +227,15 @@ impl Registers {
     fn write_data(&mut self, value: u8) {
         self.vram.write_byte(self.v_address.address(), value);
         self.v_address.increment(self.control.increment_amount());
+        self.update_bus_address();
+    }
+
+    // Outside rendering the PPU's address bus follows v, so the cartridge
+    // sees every change the CPU makes to it
+    fn update_bus_address(&self) {
+        if !self.mask.rendering() {
+            self.vram.ppu_address(self.v_address.address());
+        }
     }
 
     fn read_status(&mut self) -> u8 {
@@ -164,7 +249,54 @@ impl Registers {
     fn read_data(&mut self) -> u8 {
         let address = self.v_address.address();
         self.v_address.increment(self.control.increment_amount());
-        self.vram.buffered_read_byte(address)
+        let result = self.vram.buffered_read_byte(address);
+        self.update_bus_address();
+        result
+    }
+}
+
+impl SaveState for Registers {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.vram.save_state(state);
+        state.write_u16(self.t_address.0);
+        state.write_u16(self.v_address.0);
+        state.write_u8(self.fine_x);
+        state.write_bytes(&self.oam_ram);
+        state.write_u8(self.oam_address);
+        state.write_u8(self.control.0);
+        state.write_u8(self.mask.0);
+        state.write_u8(self.status.0);
+        state.write_bool(self.latch);
+        state.write_u8(self.open_bus);
+        for i in 0..8 {
+            state.write_u64(self.open_bus_stamps[i]);
+            state.write_u64(self.decay_cycles[i]);
+        }
+        state.write_u64(self.cycles);
+        state.write_bool(self.force_nmi);
+        state.write_bool(self.vblank_suppress);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.vram.load_state(state)?;
+        self.t_address = Address(state.read_u16()?);
+        self.v_address = Address(state.read_u16()?);
+        self.fine_x = state.read_u8()?;
+        state.read_bytes_into(&mut self.oam_ram)?;
+        self.oam_address = state.read_u8()?;
+        self.control = Control(state.read_u8()?);
+        self.mask = Mask(state.read_u8()?);
+        self.status = Status(state.read_u8()?);
+        self.latch = state.read_bool()?;
+        self.open_bus = state.read_u8()?;
+        for i in 0..8 {
+            self.open_bus_stamps[i] = state.read_u64()?;
+            self.decay_cycles[i] = state.read_u64()?;
+        }
+        self.cycles = state.read_u64()?;
+        self.force_nmi = state.read_bool()?;
+        self.vblank_suppress = state.read_bool()?;
+        Ok(())
     }
 }
 
@@ -172,6 +304,9 @@ impl Registers {
 mod test {
 
     use super::*;
+    use crate::cartridge::{build_test_rom, Cartridge, Mapper, Mirroring};
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
     #[test]
     fn test_write_control() {
@@ -268,6 +403,31 @@ mod test {
         assert_eq!(reg.read_register(0x2005), 0b0001_1111);
         assert_eq!(reg.read_register(0x2006), 0b0001_1111);
     }
+
+    #[test]
+    fn test_open_bus_decay() {
+        let mut reg = Registers::new();
+        reg.decay_cycles = [100, 100, 100, 100, 100, 200, 200, 200];
+        reg.write_register(0x2000, 0xFF);
+        reg.cycles = 99;
+        assert_eq!(reg.read_register(0x2000), 0xFF);
+        reg.cycles = 100;
+        assert_eq!(reg.read_register(0x2000), 0b1110_0000);
+
+        // Reading $2002 refreshes its top three bits only
+        reg.cycles = 150;
+        reg.status.0 = 0b1000_0000;
+        assert_eq!(reg.read_register(0x2002), 0b1000_0000);
+        reg.cycles = 349;
+        assert_eq!(reg.read_register(0x2000), 0b1000_0000);
+        reg.cycles = 350;
+        assert_eq!(reg.read_register(0x2000), 0b0000_0000);
+
+        reg.write_register(0x2000, 0xFF);
+        reg.cycles = 350 + 199;
+        assert_eq!(reg.read_register(0x2000), 0b1110_0000);
+    }
+
     #[test]
     fn test_read_oam_data() {
         let mut reg = Registers::new();
@@ -289,4 +449,111 @@ mod test {
         assert_eq!(reg.read_register(0x2007), 2);
         assert_eq!(reg.read_register(0x2007), 3);
     }
+
+    #[test]
+    fn test_peek_has_no_side_effects() {
+        let mut reg = Registers::new();
+        reg.latch = true;
+        reg.status.0 = 0b1110_0000;
+        assert_eq!(reg.peek_register(0x2002), 0b1110_0000);
+        assert_eq!(reg.latch, true);
+        assert_eq!(reg.status.vblank(), true);
+
+        reg.vram.write_byte(0x2001, 1);
+        reg.v_address.0 = 0x2001;
+        reg.read_register(0x2007);
+        assert_eq!(reg.peek_register(0x2007), 1);
+        assert_eq!(reg.peek_register(0x2007), 1);
+        assert_eq!(reg.v_address.0, 0x2002);
+    }
+
+    // Records what the PPU shows the cartridge
+    struct BusLog(Rc<RefCell<Vec<(&'static str, u16)>>>);
+
+    impl Mapper for BusLog {
+        fn read_prg_byte(&self, _: u16) -> u8 {
+            0
+        }
+        fn write_prg_byte(&mut self, _: u16, _: u8) {}
+        fn read_chr_byte(&self, _: u16) -> u8 {
+            0
+        }
+        fn write_chr_byte(&mut self, _: u16, _: u8) {}
+        fn mirroring(&self) -> Mirroring {
+            Mirroring::Vertical
+        }
+        fn prg_rom_offset(&self, _: u16) -> Option<usize> {
+            None
+        }
+        fn chr_rom_offset(&self, _: u16) -> Option<usize> {
+            None
+        }
+        fn prg_ram(&self) -> &[u8] {
+            &[]
+        }
+        fn prg_ram_mut(&mut self) -> &mut [u8] {
+            &mut []
+        }
+        fn ppu_fetch(&mut self, address: u16) {
+            self.0.borrow_mut().push(("fetch", address));
+        }
+        fn ppu_address(&mut self, address: u16) {
+            self.0.borrow_mut().push(("address", address));
+        }
+    }
+
+    impl SaveState for BusLog {
+        fn save_state(&self, _: &mut StateWriter) {}
+        fn load_state(&mut self, _: &mut StateReader) -> Result<(), StateError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_bus_addresses_reach_cartridge() {
+        let data = build_test_rom(0, &[0; 0x8000], &[0; 0x2000]);
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut cartridge = Cartridge::new(&data).unwrap();
+        cartridge.set_mapper(Box::new(BusLog(log.clone())));
+
+        let mut reg = Registers::new();
+        reg.vram.set_cartridge(Rc::new(RefCell::new(cartridge)));
+        // Outside rendering the bus follows v as the CPU moves it
+        reg.write_register(0x2006, 0x12);
+        reg.write_register(0x2006, 0x34);
+        reg.write_register(0x2007, 0);
+        reg.read_register(0x2007);
+        assert_eq!(
+            *log.borrow(),
+            vec![("address", 0x1234), ("address", 0x1235), ("address", 0x1236)]
+        );
+
+        // While rendering only the renderer's fetches show up
+        log.borrow_mut().clear();
+        reg.write_register(0x2001, 0x18);
+        reg.write_register(0x2006, 0x00);
+        reg.write_register(0x2006, 0x00);
+        reg.fetch_byte(0x2040);
+        assert_eq!(*log.borrow(), vec![("address", 0x2040), ("fetch", 0x2040)]);
+    }
+
+    #[test]
+    fn test_address_writes_clock_mmc3() {
+        // MMC3
+        let data = build_test_rom(0x40, &[0; 0x8000], &[0; 0x2000]);
+        let cartridge = Rc::new(RefCell::new(Cartridge::new(&data).unwrap()));
+        cartridge.borrow_mut().write_prg_byte(0xC001, 0);
+        cartridge.borrow_mut().write_prg_byte(0xE001, 0);
+
+        let mut reg = Registers::new();
+        reg.vram.set_cartridge(cartridge.clone());
+        for _ in 0..3 {
+            cartridge.borrow_mut().tick();
+        }
+        // Games clock the MMC3's IRQ counter by toggling A12 through $2006
+        reg.write_register(0x2006, 0x10);
+        assert!(!cartridge.borrow().irq_flag());
+        reg.write_register(0x2006, 0x00);
+        assert!(cartridge.borrow().irq_flag());
+    }
 }
@@ -3,6 +3,8 @@ use super::nth_bit;
 use super::sprite::Sprite;
 use super::PpuResult;
 use super::Registers;
+use crate::region::Region;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct BitPlane<T> {
@@ -24,6 +26,7 @@ pub struct Renderer {
     pub primary_oam: Vec<Sprite>,
     pub secondary_oam: Vec<Sprite>,
     pub pixels: Vec<u32>,
+    pub region: Region,
 }
 
 impl Renderer {
@@ -42,6 +45,7 @@ impl Renderer {
             nametable_entry: 0,
             attribute_entry: 0,
             pixels: Vec::with_capacity(256 * 240),
+            region: Region::Ntsc,
         };
         r.reset();
         r
@@ -61,21 +65,23 @@ impl Renderer {
     }
 
     pub fn tick(&mut self, registers: &mut Registers) -> PpuResult {
+        let pre_render = self.region.scanlines() - 1;
+        let vblank = self.region.vblank_scanline();
         let mut r = match (self.scanline, self.dot) {
             (0..=239, _) => {
                 self.tick_sprites(false, registers);
                 self.tick_pixel(registers);
                 self.tick_background(false, registers);
-                self.tick_result(registers)
+                PpuResult::None
             }
-            (261, _) => {
+            (scanline, _) if scanline == pre_render => {
                 self.tick_sprites(true, registers);
                 self.tick_pixel(registers);
                 self.tick_background(true, registers);
-                self.tick_result(registers)
+                PpuResult::None
             }
             (240, 0) => PpuResult::Draw,
-            (241, 1) => {
+            (scanline, 1) if scanline == vblank => {
                 if !registers.vblank_suppress {
                     registers.status.set_vblank(true);
                     if registers.control.nmi_on_vblank() {
@@ -112,8 +118,13 @@ impl Renderer {
                     registers.status.set_sprite_zero_hit(false);
                 }
             }
-            257 => self.eval_sprites(registers), // TOD - should set oamaddr to 0?
-            321 => self.load_sprites(registers),
+            257..=320 => {
+                if self.dot == 257 {
+                    self.eval_sprites(registers); // TOD - should set oamaddr to 0?
+                }
+                self.fetch_sprite(registers);
+            }
+            321 => self.primary_oam = self.secondary_oam.clone(),
             _ => (),
         }
     }
@@ -140,13 +151,13 @@ impl Renderer {
                     self.reload_shift_registers();
                 }
                 2 => {
-                    self.nametable_entry = registers.vram.read_byte(self.scratch_address);
+                    self.nametable_entry = registers.fetch_byte(self.scratch_address);
                 }
                 3 => {
                     self.scratch_address = registers.v_address.attribute_address();
                 }
                 4 => {
-                    self.attribute_entry = registers.vram.read_byte(self.scratch_address);
+                    self.attribute_entry = registers.fetch_byte(self.scratch_address);
                     if registers.v_address.coarse_y() & 2 != 0 {
                         self.attribute_entry >>= 4;
                     }
@@ -159,13 +170,13 @@ impl Renderer {
                         + registers.v_address.tile_offset(self.nametable_entry);
                 }
                 6 => {
-                    self.background_latch.low = registers.vram.read_byte(self.scratch_address);
+                    self.background_latch.low = registers.fetch_pattern_byte(self.scratch_address);
                 }
                 7 => {
                     self.scratch_address += 8;
                 }
                 0 => {
-                    self.background_latch.high = registers.vram.read_byte(self.scratch_address);
+                    self.background_latch.high = registers.fetch_pattern_byte(self.scratch_address);
 
                     if registers.mask.rendering() {
                         registers.v_address.scroll_x();
@@ -174,7 +185,7 @@ impl Renderer {
                 _ => panic!("Impossible math"),
             },
             256 => {
-                self.background_latch.high = registers.vram.read_byte(self.scratch_address);
+                self.background_latch.high = registers.fetch_pattern_byte(self.scratch_address);
                 if registers.mask.rendering() {
                     registers.v_address.scroll_y();
                 }
@@ -202,11 +213,12 @@ impl Renderer {
                 self.scratch_address = registers.v_address.nametable_address();
             }
             338 => {
-                self.nametable_entry = registers.vram.read_byte(self.scratch_address);
+                self.nametable_entry = registers.fetch_byte(self.scratch_address);
             }
             340 => {
-                self.nametable_entry = registers.vram.read_byte(self.scratch_address);
-                if pre && registers.mask.rendering() && self.odd_frame {
+                self.nametable_entry = registers.fetch_byte(self.scratch_address);
+                let skip = self.odd_frame && self.region.skips_odd_frame_dot();
+                if pre && registers.mask.rendering() && skip {
                     self.dot += 1;
                 }
             }
@@ -215,14 +227,6 @@ impl Renderer {
         }
     }
 
-    fn tick_result(&self, registers: &mut Registers) -> PpuResult {
-        if self.dot == 260 && registers.mask.rendering() {
-            PpuResult::Scanline
-        } else {
-            PpuResult::None
-        }
-    }
-
     fn render_pixel(&mut self, x: usize, y: usize, registers: &mut Registers) -> Option<u8> {
         if y < 240 && x < 256 {
             let background_color = self.render_background_pixel(x, registers);
@@ -289,7 +293,7 @@ impl Renderer {
         if self.dot >= 341 {
             self.dot %= 341;
             self.scanline += 1;
-            if self.scanline > 261 {
+            if self.scanline >= self.region.scanlines() {
                 self.scanline = 0;
                 self.odd_frame = !self.odd_frame;
             }
@@ -320,14 +324,40 @@ impl Renderer {
         }
     }
 
-    fn load_sprites(&mut self, registers: &mut Registers) {
-        let mut sprites = self.secondary_oam.clone();
-        for sprite in sprites.iter_mut() {
-            let tile_address = sprite.tile_address(self.scanline, registers.control);
-            sprite.data_low = registers.vram.read_byte(tile_address);
-            sprite.data_high = registers.vram.read_byte(tile_address + 8);
+    // Each of the eight sprite slots takes 8 dots: two nametable reads the
+    // PPU throws away, then the pattern. Mappers count on seeing all of
+    // them, so empty slots still fetch tile $FF like the real PPU does.
+    fn fetch_sprite(&mut self, registers: &mut Registers) {
+        let slot = (self.dot - 257) / 8;
+        let high = match (self.dot - 257) % 8 {
+            1 | 3 => {
+                registers.fetch_byte(registers.v_address.nametable_address());
+                return;
+            }
+            5 => false,
+            7 => true,
+            _ => return,
+        };
+        let plane = if high { 8 } else { 0 };
+        match self.secondary_oam.get_mut(slot) {
+            Some(sprite) => {
+                let tile_address = sprite.tile_address(self.scanline, registers.control);
+                let data = registers.fetch_pattern_byte(tile_address + plane);
+                if high {
+                    sprite.data_high = data;
+                } else {
+                    sprite.data_low = data;
+                }
+            }
+            None => {
+                let tile_address = if registers.control.large_sprites() {
+                    0x1FE0
+                } else {
+                    registers.control.sprite_tile_base() + 0xFF0
+                };
+                registers.fetch_byte(tile_address + plane);
+            }
         }
-        self.primary_oam = sprites;
     }
 
     fn reload_shift_registers(&mut self) {
@@ -358,6 +388,64 @@ impl Renderer {
     }
 }
 
+impl SaveState for Renderer {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.background_latch.low);
+        state.write_u8(self.background_latch.high);
+        state.write_u16(self.background_shift.low);
+        state.write_u16(self.background_shift.high);
+        state.write_u8(self.attribute_latch.low);
+        state.write_u8(self.attribute_latch.high);
+        state.write_u8(self.attribute_shift.low);
+        state.write_u8(self.attribute_shift.high);
+        state.write_usize(self.scanline);
+        state.write_usize(self.dot);
+        state.write_bool(self.odd_frame);
+        state.write_u16(self.scratch_address);
+        state.write_u8(self.nametable_entry);
+        state.write_u8(self.attribute_entry);
+        for oam in [&self.primary_oam, &self.secondary_oam] {
+            state.write_usize(oam.len());
+            for sprite in oam.iter() {
+                sprite.save_state(state);
+            }
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.background_latch.low = state.read_u8()?;
+        self.background_latch.high = state.read_u8()?;
+        self.background_shift.low = state.read_u16()?;
+        self.background_shift.high = state.read_u16()?;
+        self.attribute_latch.low = state.read_u8()?;
+        self.attribute_latch.high = state.read_u8()?;
+        self.attribute_shift.low = state.read_u8()?;
+        self.attribute_shift.high = state.read_u8()?;
+        self.scanline = state.read_usize()?;
+        self.dot = state.read_usize()?;
+        if self.scanline >= self.region.scanlines() || self.dot > 340 {
+            return Err(StateError::Invalid("PPU position out of range"));
+        }
+        self.odd_frame = state.read_bool()?;
+        self.scratch_address = state.read_u16()?;
+        self.nametable_entry = state.read_u8()?;
+        self.attribute_entry = state.read_u8()?;
+        for oam in [&mut self.primary_oam, &mut self.secondary_oam] {
+            let len = state.read_usize()?;
+            if len > 8 {
+                return Err(StateError::Invalid("too many sprites on a scanline"));
+            }
+            oam.clear();
+            for _ in 0..len {
+                let mut sprite = Sprite::new(0, &[0; 4]);
+                sprite.load_state(state)?;
+                oam.push(sprite);
+            }
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -408,7 +496,7 @@ mod test {
         // add the PRG-ROM
         data.extend_from_slice(&[0u8; 2 * 0x4000]);
 
-        Rc::new(RefCell::new(Cartridge::new(&data)))
+        Rc::new(RefCell::new(Cartridge::new(&data).unwrap()))
     }
 
     #[test]
@@ -422,9 +510,12 @@ mod test {
             regs.vram.write_byte(i, i as u8);
         }
 
-        renderer.secondary_oam.push(Sprite::new(0, &[5, 3, 1, 2]));
+        regs.oam_ram[0..4].copy_from_slice(&[5, 3, 1, 2]);
         renderer.scanline = 6;
-        renderer.load_sprites(&mut regs);
+        for dot in 257..=321 {
+            renderer.dot = dot;
+            renderer.tick_sprites(false, &mut regs);
+        }
 
         assert_eq!(renderer.secondary_oam[0].x, renderer.primary_oam[0].x);
         assert_eq!(renderer.secondary_oam[0].y, renderer.primary_oam[0].y);
@@ -469,6 +560,32 @@ mod test {
         assert_eq!(renderer.dot, 0);
         assert_eq!(renderer.scanline, 0);
         assert_eq!(renderer.odd_frame, true);
+
+        // PAL and Dendy frames are 312 scanlines
+        renderer.region = Region::Pal;
+        renderer.dot = 340;
+        renderer.scanline = 261;
+        renderer.step();
+        assert_eq!(renderer.scanline, 262);
+        renderer.dot = 340;
+        renderer.scanline = 311;
+        renderer.step();
+        assert_eq!(renderer.scanline, 0);
+    }
+
+    #[test]
+    fn test_dendy_vblank() {
+        let mut regs = Registers::new();
+        regs.write_register(0x2000, 0x80);
+        let mut renderer = Renderer::new();
+        renderer.region = Region::Dendy;
+        renderer.scanline = 241;
+        renderer.dot = 1;
+        assert_eq!(renderer.tick(&mut regs), PpuResult::None);
+        assert!(!regs.status.vblank());
+        renderer.scanline = 291;
+        assert_eq!(renderer.tick(&mut regs), PpuResult::Nmi);
+        assert!(regs.status.vblank());
     }
 
     #[test]
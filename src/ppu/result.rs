@@ -2,6 +2,5 @@
 pub enum PpuResult {
     Nmi,
     Draw,
-    Scanline,
     None,
 }
@@ -1,5 +1,6 @@
 use super::control::Control;
 use super::nth_bit;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 
 bitfield! {
     #[derive(Copy, Clone, PartialEq)]
@@ -80,6 +81,29 @@ impl Sprite {
     }
 }
 
+impl SaveState for Sprite {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.x);
+        state.write_u8(self.y);
+        state.write_u8(self.status.0);
+        state.write_u8(self.tile_index.0);
+        state.write_u8(self.data_low);
+        state.write_u8(self.data_high);
+        state.write_usize(self.oam_index);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.x = state.read_u8()?;
+        self.y = state.read_u8()?;
+        self.status = SpriteStatus(state.read_u8()?);
+        self.tile_index = SpriteTileIndex(state.read_u8()?);
+        self.data_low = state.read_u8()?;
+        self.data_high = state.read_u8()?;
+        self.oam_index = state.read_usize()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -1,4 +1,6 @@
 use crate::cartridge::{Cartridge, Mirroring};
+use crate::cdl;
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -12,6 +14,12 @@ pub struct Vram {
     cartridge: Option<Rc<RefCell<Cartridge>>>,
 }
 
+impl Default for Vram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Vram {
     pub fn new() -> Self {
         Vram {
@@ -41,50 +49,106 @@ impl Vram {
     }
 
     pub fn write_byte(&mut self, address: u16, value: u8) {
-        let mirroring = self.mirroring();
         match address {
             0x0000..=0x1FFF => match self.cartridge {
                 Some(ref c) => c.borrow_mut().write_chr_byte(address, value),
                 None => panic!("tried to write to non-existant cartridge memory"),
             },
-            0x2000..=0x3EFF => self.nametables[mirror_nametable(mirroring, address)] = value,
+            0x2000..=0x3EFF => match self.cartridge {
+                Some(ref c) => c
+                    .borrow_mut()
+                    .write_nametable(address, value, &mut self.nametables),
+                None => {
+                    let i = mirror_nametable(Mirroring::None, address);
+                    if let Some(n) = self.nametables.get_mut(i) {
+                        *n = value;
+                    }
+                }
+            },
             0x3F00..=0x3FFF => self.palettes[mirror_palette(address)] = value,
             _ => (),
         };
     }
 
-    pub fn read_byte(&mut self, address: u16) -> u8 {
-        let mirroring = self.mirroring();
+    // The cartridge decides where nametables live, the console's RAM is
+    // only one of the places it can put them
+    fn read_nametable(&self, address: u16) -> u8 {
+        match self.cartridge {
+            Some(ref c) => c.borrow().read_nametable(address, &self.nametables),
+            None => self
+                .nametables
+                .get(mirror_nametable(Mirroring::None, address))
+                .copied()
+                .unwrap_or(0),
+        }
+    }
+
+    // Lets the cartridge see an address the renderer is about to read
+    pub fn ppu_fetch(&self, address: u16) {
+        if let Some(ref c) = self.cartridge {
+            c.borrow_mut().ppu_fetch(address);
+        }
+    }
+
+    // Lets the cartridge see an address the PPU put on its bus without
+    // reading it
+    pub fn ppu_address(&self, address: u16) {
+        if let Some(ref c) = self.cartridge {
+            c.borrow_mut().ppu_address(address);
+        }
+    }
+
+    pub fn read_byte(&self, address: u16) -> u8 {
         match address {
             0x0000..=0x1FFF => match self.cartridge {
                 Some(ref c) => c.borrow().read_chr_byte(address),
                 None => panic!("tried to read non-existant cartridge memory"),
             },
-            0x2000..=0x3EFF => self.nametables[mirror_nametable(mirroring, address)],
+            0x2000..=0x3EFF => self.read_nametable(address),
             0x3F00..=0x3FFF => self.palettes[mirror_palette(address)],
             _ => 0,
         }
     }
 
+    // What buffered_read_byte would return, without refilling the buffer
+    pub fn peek_buffered_byte(&self, address: u16) -> u8 {
+        if address < 0x3F00 {
+            self.read_buffer
+        } else {
+            self.read_byte(address)
+        }
+    }
+
+    pub fn log_chr(&self, address: u16, flags: u8) {
+        if let Some(ref c) = self.cartridge {
+            c.borrow_mut().log_chr(address, flags);
+        }
+    }
+
     pub fn buffered_read_byte(&mut self, address: u16) -> u8 {
+        if address < 0x2000 {
+            self.log_chr(address, cdl::READ);
+        }
         if address < 0x3F00 {
             let result = self.read_buffer;
             self.read_buffer = self.read_byte(address);
             result
         } else {
-            let mirroring = self.mirroring();
-            self.read_buffer = self.nametables[mirror_nametable(mirroring, address)];
+            self.read_buffer = self.read_nametable(address);
             self.read_byte(address)
         }
     }
 }
 
-fn mirror_nametable(mirroring: Mirroring, address: u16) -> usize {
+pub fn mirror_nametable(mirroring: Mirroring, address: u16) -> usize {
     let address = address as usize;
     let result = match mirroring {
         Mirroring::None => address - 0x2000,
         Mirroring::Horizontal => ((address / 2) & NAMETABLE_SIZE) + (address % NAMETABLE_SIZE),
         Mirroring::Vertical => address % (2 * NAMETABLE_SIZE),
+        Mirroring::SingleScreenA => address % NAMETABLE_SIZE,
+        Mirroring::SingleScreenB => NAMETABLE_SIZE + address % NAMETABLE_SIZE,
+        Mirroring::FourScreen => address % (4 * NAMETABLE_SIZE),
     };
     result
 }
@@ -98,9 +162,25 @@ fn mirror_palette(address: u16) -> usize {
     }
 }
 
+impl SaveState for Vram {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.nametables);
+        state.write_bytes(&self.palettes);
+        state.write_u8(self.read_buffer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes_into(&mut self.nametables)?;
+        state.read_bytes_into(&mut self.palettes)?;
+        self.read_buffer = state.read_u8()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::cartridge::build_test_rom;
 
     #[test]
     fn test_read_byte_nametable() {
@@ -151,7 +231,7 @@ mod test {
             data.push(i as u8);
         }
 
-        Rc::new(RefCell::new(Cartridge::new(&data)))
+        Rc::new(RefCell::new(Cartridge::new(&data).unwrap()))
     }
 
     #[test]
@@ -228,6 +308,44 @@ mod test {
         assert_eq!(mirror_nametable(Mirroring::Vertical, 0x3E01), 0x601);
     }
 
+    #[test]
+    fn test_mirror_nametable_single_screen() {
+        for base in [0x2000, 0x2400, 0x2800, 0x2C00, 0x3000] {
+            assert_eq!(mirror_nametable(Mirroring::SingleScreenA, base + 0x201), 0x201);
+            assert_eq!(mirror_nametable(Mirroring::SingleScreenB, base + 0x201), 0x601);
+        }
+    }
+
+    #[test]
+    fn test_mirror_nametable_four_screen() {
+        assert_eq!(mirror_nametable(Mirroring::FourScreen, 0x2001), 0x001);
+        assert_eq!(mirror_nametable(Mirroring::FourScreen, 0x2401), 0x401);
+        assert_eq!(mirror_nametable(Mirroring::FourScreen, 0x2801), 0x801);
+        assert_eq!(mirror_nametable(Mirroring::FourScreen, 0x2C01), 0xC01);
+        assert_eq!(mirror_nametable(Mirroring::FourScreen, 0x3C01), 0xC01);
+    }
+
+    #[test]
+    fn test_four_screen_nametables() {
+        let mut v = Vram::new();
+        let cartridge = build_cartridge();
+        v.set_cartridge(cartridge.clone());
+        assert_eq!(v.mirroring(), Mirroring::Horizontal);
+
+        let data = build_test_rom(0x08, &[0; 0x8000], &[0; 0x2000]);
+        let cartridge = Rc::new(RefCell::new(Cartridge::new(&data).unwrap()));
+        v.set_cartridge(cartridge.clone());
+        assert_eq!(v.mirroring(), Mirroring::FourScreen);
+        for (i, address) in [0x2005, 0x2405, 0x2805, 0x2C05].iter().enumerate() {
+            v.write_byte(*address, i as u8 + 1);
+        }
+        assert_eq!(v.nametables[0x005], 1);
+        assert_eq!(v.nametables[0x405], 2);
+        assert_eq!(cartridge.borrow().read_nametable(0x2805, &[0; 0x800]), 3);
+        assert_eq!(v.read_byte(0x2C05), 4);
+        assert_eq!(v.read_byte(0x3805), 3);
+    }
+
     #[test]
     fn test_mirror_palette() {
         assert_eq!(mirror_palette(0x3F01), 1);
@@ -0,0 +1,216 @@
+// Narrows down where a game keeps a variable, like its lives counter, by
+// comparing snapshots of RAM. Start a search, let the game change the value,
+// then keep only the addresses whose contents changed the same way. Both the
+// console's RAM and whichever PRG-RAM the cartridge has at $6000-$7FFF
+// are searched.
+
+use crate::bus::Bus;
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum View {
+    U8,
+    S8,
+    // 16-bit views are little-endian, like the 6502
+    U16,
+    S16,
+}
+
+impl View {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "u8" => Some(View::U8),
+            "s8" => Some(View::S8),
+            "u16" => Some(View::U16),
+            "s16" => Some(View::S16),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for View {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            View::U8 => "u8",
+            View::S8 => "s8",
+            View::U16 => "u16",
+            View::S16 => "s16",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Condition {
+    // Compared with the previous snapshot
+    Same,
+    Changed,
+    Increased,
+    Decreased,
+    // Compared with a number, read through the search's view
+    Equals(i32),
+}
+
+struct Snapshot {
+    ram: Vec<u8>,
+    // $6000-$7FFF as the CPU sees it, so mappers that bank PRG-RAM show the
+    // bank that's currently switched in
+    prg_ram: Vec<u8>,
+}
+
+impl Snapshot {
+    fn new(bus: &Bus) -> Self {
+        let prg_ram = match bus.cartridge {
+            Some(ref c) => c.borrow().prg_ram().len().min(0x2000),
+            None => 0,
+        };
+        let prg_ram = (0x6000..0x6000 + prg_ram as u16)
+            .map(|a| bus.peek(a))
+            .collect();
+        Snapshot {
+            ram: bus.ram.to_vec(),
+            prg_ram,
+        }
+    }
+
+    fn addresses(&self) -> impl Iterator<Item = u16> {
+        let prg_ram = self.prg_ram.len() as u16;
+        (0..self.ram.len() as u16).chain(0x6000..0x6000 + prg_ram)
+    }
+
+    fn read(&self, address: u16) -> Option<u8> {
+        match address {
+            0x0000..=0x07FF => self.ram.get(address as usize).copied(),
+            0x6000..=0x7FFF => self.prg_ram.get(address as usize - 0x6000).copied(),
+            _ => None,
+        }
+    }
+
+    fn value(&self, address: u16, view: View) -> Option<i32> {
+        let low = self.read(address)?;
+        Some(match view {
+            View::U8 => low as i32,
+            View::S8 => low as i8 as i32,
+            View::U16 | View::S16 => {
+                let word = u16::from_le_bytes([low, self.read(address.checked_add(1)?)?]);
+                match view {
+                    View::S16 => word as i16 as i32,
+                    _ => word as i32,
+                }
+            }
+        })
+    }
+}
+
+pub struct RamSearch {
+    pub view: View,
+    snapshot: Snapshot,
+    candidates: Vec<u16>,
+}
+
+impl RamSearch {
+    // Starts with every address as a candidate
+    pub fn new(bus: &Bus, view: View) -> Self {
+        let snapshot = Snapshot::new(bus);
+        let candidates = snapshot
+            .addresses()
+            .filter(|&a| snapshot.value(a, view).is_some())
+            .collect();
+        RamSearch {
+            view,
+            snapshot,
+            candidates,
+        }
+    }
+
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+
+    // The value at `address` when the last snapshot was taken
+    pub fn value(&self, address: u16) -> Option<i32> {
+        self.snapshot.value(address, self.view)
+    }
+
+    // Takes a new snapshot and keeps the candidates that meet the condition,
+    // returning how many are left
+    pub fn filter(&mut self, bus: &Bus, condition: Condition) -> usize {
+        let current = Snapshot::new(bus);
+        let view = self.view;
+        let previous = &self.snapshot;
+        self.candidates.retain(|&a| {
+            let (old, new) = match (previous.value(a, view), current.value(a, view)) {
+                (Some(old), Some(new)) => (old, new),
+                _ => return false,
+            };
+            match condition {
+                Condition::Same => new == old,
+                Condition::Changed => new != old,
+                Condition::Increased => new > old,
+                Condition::Decreased => new < old,
+                Condition::Equals(n) => new == n,
+            }
+        });
+        self.snapshot = current;
+        self.candidates.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::build_test_rom;
+
+    #[test]
+    fn test_filter() {
+        let mut bus = Bus::new();
+        bus.ram[0x75] = 3;
+        bus.ram[0x76] = 3;
+        let mut search = RamSearch::new(&bus, View::U8);
+        assert_eq!(search.candidates().len(), 0x800);
+
+        bus.ram[0x75] = 2;
+        bus.ram[0x80] = 1;
+        assert_eq!(search.filter(&bus, Condition::Changed), 2);
+        assert_eq!(search.filter(&bus, Condition::Same), 2);
+        bus.ram[0x75] = 1;
+        bus.ram[0x80] = 2;
+        assert_eq!(search.filter(&bus, Condition::Decreased), 1);
+        assert_eq!(search.candidates(), &[0x75]);
+        assert_eq!(search.filter(&bus, Condition::Equals(1)), 1);
+        assert_eq!(search.value(0x75), Some(1));
+    }
+
+    #[test]
+    fn test_views() {
+        let mut bus = Bus::new();
+        bus.ram[0x10] = 0xFF;
+        bus.ram[0x11] = 0x01;
+        let search = RamSearch::new(&bus, View::S8);
+        assert_eq!(search.value(0x10), Some(-1));
+        let search = RamSearch::new(&bus, View::U16);
+        assert_eq!(search.value(0x10), Some(0x01FF));
+        // The last byte of RAM has nothing after it to make a word with
+        assert_eq!(search.candidates().len(), 0x7FF);
+        bus.ram[0x11] = 0xFF;
+        let search = RamSearch::new(&bus, View::S16);
+        assert_eq!(search.value(0x10), Some(-1));
+    }
+
+    #[test]
+    fn test_banked_prg_ram() {
+        // MMC5 with 64kb of PRG-RAM
+        let data = build_test_rom(0x50, &[0; 0x4000], &[0; 0x2000]);
+        let mut bus = Bus::new();
+        bus.load_rom_from_memory(&data).unwrap();
+        bus.write_byte(0x5102u16, 2);
+        bus.write_byte(0x5103u16, 1);
+        bus.write_byte(0x5113u16, 1);
+        bus.write_byte(0x6000u16, 0x42);
+
+        // The search sees the second bank, which is what's mapped
+        let search = RamSearch::new(&bus, View::U8);
+        assert_eq!(search.candidates().len(), 0x800 + 0x2000);
+        assert_eq!(search.value(0x6000), Some(0x42));
+    }
+}
@@ -0,0 +1,79 @@
+// The console's region decides how fast it runs. PAL and Dendy consoles
+// draw 312 scanlines a frame instead of 262, and PAL also runs the CPU at
+// 16/5 PPU dots per cycle and its APU from different period tables.
+// https://www.nesdev.org/wiki/Cycle_reference_chart
+
+use std::time::Duration;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    pub fn frame_rate(&self) -> f64 {
+        match self {
+            Region::Ntsc => 60.0988,
+            Region::Pal | Region::Dendy => 50.0070,
+        }
+    }
+
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.frame_rate())
+    }
+
+    // Counting the pre-render line, which is always the last
+    pub fn scanlines(&self) -> usize {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    // Dendy waits 51 idle lines after the picture before starting vblank,
+    // which keeps NMI handlers written for NTSC timing working
+    pub fn vblank_scanline(&self) -> usize {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    // Only the NTSC PPU drops a dot from every other frame
+    pub fn skips_odd_frame_dot(&self) -> bool {
+        *self == Region::Ntsc
+    }
+
+    // How many PPU dots run during CPU cycle number `cycle`. PAL's ratio is
+    // 3.2, which comes out as one extra dot every fifth cycle.
+    pub fn ppu_dots(&self, cycle: u64) -> usize {
+        match self {
+            Region::Pal if cycle.is_multiple_of(5) => 4,
+            _ => 3,
+        }
+    }
+
+    // The audio is sampled at close to 44.1kHz, rounding so that a little
+    // too much is made rather than too little
+    pub fn cpu_cycles_per_sample(&self) -> u64 {
+        match self {
+            Region::Ntsc | Region::Dendy => 40,
+            Region::Pal => 37,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pal_dots() {
+        let dots: usize = (1..=5).map(|c| Region::Pal.ppu_dots(c)).sum();
+        assert_eq!(dots, 16);
+        let dots: usize = (1..=5).map(|c| Region::Dendy.ppu_dots(c)).sum();
+        assert_eq!(dots, 15);
+    }
+}
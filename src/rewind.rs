@@ -0,0 +1,319 @@
+// Rewind keeps a ring buffer of machine snapshots taken every `interval`
+// frames, along with the controller input for each frame in between. Stepping
+// back a frame restores the closest snapshot and re-runs the recorded input up
+// to the frame before, so only every Nth state has to be kept around.
+//
+// Only the newest snapshot is stored whole. Older ones are XORed against the
+// snapshot that follows them and run-length encoded, which shrinks them to a
+// few KB since most of the machine does not change over a handful of frames.
+
+use crate::nes::{Nes, Port};
+use std::collections::VecDeque;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RewindConfig {
+    // How far back the buffer reaches
+    pub seconds: u32,
+    // Upper bound on the memory used by snapshots, whichever limit is hit first wins
+    pub max_bytes: usize,
+    // Frames between snapshots. Higher saves memory but costs more re-simulation per step.
+    pub interval: u32,
+}
+
+impl Default for RewindConfig {
+    fn default() -> Self {
+        RewindConfig {
+            seconds: 30,
+            max_bytes: 64 * 1024 * 1024,
+            interval: 4,
+        }
+    }
+}
+
+struct Snapshot {
+    delta: Vec<u8>,
+    // Controller state for each frame run after this snapshot was taken
+    inputs: Vec<[u8; 2]>,
+}
+
+pub struct Rewind {
+    config: RewindConfig,
+    max_snapshots: usize,
+    current: Vec<u8>,
+    current_inputs: Vec<[u8; 2]>,
+    history: VecDeque<Snapshot>,
+    history_bytes: usize,
+}
+
+impl Rewind {
+    pub fn new(config: RewindConfig, frame_rate: f64) -> Self {
+        let frames = (config.seconds as f64 * frame_rate).ceil() as usize;
+        let interval = config.interval.max(1);
+        Rewind {
+            config: RewindConfig { interval, ..config },
+            max_snapshots: (frames / interval as usize).max(1),
+            current: Vec::new(),
+            current_inputs: Vec::new(),
+            history: VecDeque::new(),
+            history_bytes: 0,
+        }
+    }
+
+    pub fn config(&self) -> RewindConfig {
+        self.config
+    }
+
+    // Number of frames that can currently be stepped back
+    pub fn frames(&self) -> usize {
+        let older: usize = self.history.iter().map(|s| s.inputs.len()).sum();
+        (older + self.current_inputs.len()).saturating_sub(1)
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.history_bytes + self.current.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.current.clear();
+        self.current_inputs.clear();
+        self.history.clear();
+        self.history_bytes = 0;
+    }
+
+    // Call once per frame, after setting the buttons and before `Nes::step_frame`
+    pub fn record(&mut self, nes: &Nes) {
+        if self.current.is_empty() || self.current_inputs.len() >= self.config.interval as usize {
+            self.snapshot(nes);
+        }
+        self.current_inputs.push([nes.buttons(Port::One), nes.buttons(Port::Two)]);
+    }
+
+    // Starts a new snapshot right away. Use after anything that changes the
+    // machine outside of `record`, like loading a save state, so the recorded
+    // input does not get replayed on top of the wrong state.
+    pub fn snapshot(&mut self, nes: &Nes) {
+        let state = nes.save_state();
+        if !self.current.is_empty() {
+            let snapshot = Snapshot {
+                delta: compress(&self.current, &state),
+                inputs: std::mem::take(&mut self.current_inputs),
+            };
+            self.history_bytes += snapshot.delta.len();
+            self.history.push_back(snapshot);
+        }
+        self.current = state;
+        self.current_inputs.clear();
+
+        while !self.history.is_empty()
+            && (self.history.len() + 1 > self.max_snapshots
+                || self.memory_used() > self.config.max_bytes)
+        {
+            let oldest = self.history.pop_front().unwrap();
+            self.history_bytes -= oldest.delta.len();
+        }
+    }
+
+    // Puts the machine back one frame. Returns false once the buffer is used up.
+    pub fn step_back(&mut self, nes: &mut Nes) -> bool {
+        if self.current_inputs.len() > 1 {
+            self.current_inputs.pop();
+        } else {
+            // Landing exactly on the snapshot would leave a stale picture, so
+            // replay the whole previous stretch instead, which ends on the same frame.
+            let older = match self.history.pop_back() {
+                Some(s) => s,
+                None => return false,
+            };
+            self.history_bytes -= older.delta.len();
+            self.current = decompress(&older.delta, &self.current);
+            self.current_inputs = older.inputs;
+        }
+
+        if nes.load_state(&self.current).is_err() {
+            self.clear();
+            return false;
+        }
+        for input in self.current_inputs.iter() {
+            nes.set_buttons(Port::One, input[0]);
+            nes.set_buttons(Port::Two, input[1]);
+            nes.step_frame();
+            nes.drain_audio();
+        }
+        true
+    }
+}
+
+// Encodes `older` against `newer` as the older length followed by pairs of
+// (unchanged run, changed run) lengths, each changed run followed by its XORed bytes.
+fn compress(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let diff = |i: usize| older[i] ^ newer.get(i).copied().unwrap_or(0);
+
+    let mut out = Vec::new();
+    write_varint(&mut out, older.len());
+    let mut i = 0;
+    while i < older.len() {
+        let start = i;
+        while i < older.len() && diff(i) == 0 {
+            i += 1;
+        }
+        write_varint(&mut out, i - start);
+
+        let start = i;
+        while i < older.len() && diff(i) != 0 {
+            i += 1;
+        }
+        write_varint(&mut out, i - start);
+        out.extend((start..i).map(diff));
+    }
+    out
+}
+
+fn decompress(delta: &[u8], newer: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = read_varint(delta, &mut pos);
+    let mut out = newer.to_vec();
+    out.resize(len, 0);
+
+    let mut i = 0;
+    while i < len {
+        i += read_varint(delta, &mut pos);
+        let changed = read_varint(delta, &mut pos);
+        for b in out[i..i + changed].iter_mut() {
+            *b ^= delta[pos];
+            pos += 1;
+        }
+        i += changed;
+    }
+    out
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let b = data[*pos];
+        *pos += 1;
+        value |= ((b & 0x7f) as usize) << shift;
+        if b & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::{build_test_prg, build_test_rom};
+    use crate::controller::Button;
+
+    fn build_rom() -> Vec<u8> {
+        // Counts frames in $00 off the NMI, and copies the controller into $01
+        let mut prg = build_test_prg(&[
+            0xa9, 0x80, // LDA #$80
+            0x8d, 0x00, 0x20, // STA $2000
+            0x4c, 0x05, 0x80, // JMP $8005
+        ]);
+        let nmi = [
+            0xe6, 0x00, // INC $00
+            0xa9, 0x01, // LDA #$01
+            0x8d, 0x16, 0x40, // STA $4016
+            0xa9, 0x00, // LDA #$00
+            0x8d, 0x16, 0x40, // STA $4016
+            0xad, 0x16, 0x40, // LDA $4016
+            0x85, 0x01, // STA $01
+            0x40, // RTI
+        ];
+        prg[0x100..0x100 + nmi.len()].copy_from_slice(&nmi);
+        prg[0x7FFA] = 0x00;
+        prg[0x7FFB] = 0x81;
+        build_test_rom(0, &prg, &[])
+    }
+
+    fn run(nes: &mut Nes, rewind: &mut Rewind, buttons: u8) {
+        nes.set_buttons(Port::One, buttons);
+        rewind.record(nes);
+        nes.step_frame();
+    }
+
+    #[test]
+    fn test_compress() {
+        let older = vec![1, 2, 3, 0, 0, 0, 7, 8, 9, 10];
+        let newer = vec![1, 2, 4, 0, 0, 0, 7, 0, 9];
+        let delta = compress(&older, &newer);
+        assert_eq!(older, decompress(&delta, &newer));
+
+        let same = vec![0x55; 1000];
+        let delta = compress(&same, &same);
+        assert!(delta.len() < 8);
+        assert_eq!(same, decompress(&delta, &same));
+        assert_eq!(Vec::<u8>::new(), decompress(&compress(&[], &same), &same));
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut nes = Nes::new();
+        nes.load_rom(&build_rom()).unwrap();
+        let mut rewind = Rewind::new(RewindConfig::default(), 60.0);
+        assert!(!rewind.step_back(&mut nes));
+
+        let mut frames = Vec::new();
+        for i in 0..10 {
+            run(&mut nes, &mut rewind, if i % 3 == 0 { Button::A as u8 } else { 0 });
+            frames.push((nes.cpu.bus.ram[0], nes.cpu.bus.ram[1], nes.frame_buffer().to_vec()));
+        }
+        assert_eq!(9, rewind.frames());
+
+        for i in (0..9).rev() {
+            assert!(rewind.step_back(&mut nes));
+            let (count, button, ref pixels) = frames[i];
+            assert_eq!(count, nes.cpu.bus.ram[0]);
+            assert_eq!(button, nes.cpu.bus.ram[1]);
+            assert_eq!(&pixels[..], nes.frame_buffer());
+        }
+        assert_eq!(0, rewind.frames());
+        assert!(!rewind.step_back(&mut nes));
+
+        // Carrying on after a rewind records over the old future
+        run(&mut nes, &mut rewind, 0);
+        assert_eq!(frames[1].0, nes.cpu.bus.ram[0]);
+        assert_eq!(1, rewind.frames());
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut nes = Nes::new();
+        nes.load_rom(&build_rom()).unwrap();
+
+        let config = RewindConfig {
+            seconds: 1,
+            interval: 4,
+            ..RewindConfig::default()
+        };
+        let mut rewind = Rewind::new(config, 20.0);
+        for _ in 0..40 {
+            run(&mut nes, &mut rewind, 0);
+        }
+        // Five snapshots of four frames each, less the one we are on
+        assert_eq!(19, rewind.frames());
+
+        let config = RewindConfig {
+            max_bytes: 1,
+            ..RewindConfig::default()
+        };
+        let mut rewind = Rewind::new(config, 60.0);
+        for _ in 0..40 {
+            run(&mut nes, &mut rewind, 0);
+        }
+        assert!(rewind.history.is_empty());
+        assert_eq!(nes.save_state().len(), rewind.memory_used());
+    }
+}
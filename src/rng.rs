@@ -0,0 +1,74 @@
+// All randomness in the machine comes from one of these, owned by the Bus and
+// saved along with it, so two runs with the same seed and input always match.
+// It is xorshift64*, which is plenty for emulating analog noise.
+
+use crate::savestate::{SaveState, StateError, StateReader, StateWriter};
+
+pub const DEFAULT_SEED: u64 = 0x4e45_531a_2c9e_1f35;
+
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck on zero, so scramble the seed with splitmix64 first
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        Rng {
+            state: if z == 0 { DEFAULT_SEED } else { z },
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // A value in low..high
+    pub fn range(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next_u64() % (high - low)
+    }
+}
+
+impl SaveState for Rng {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u64(self.state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.state = state.read_u64()?;
+        if self.state == 0 {
+            return Err(StateError::Invalid("random number generator state is zero"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_seeded() {
+        let mut a = Rng::new(1);
+        let mut b = Rng::new(1);
+        let mut c = Rng::new(2);
+        let a: Vec<u64> = (0..4).map(|_| a.next_u64()).collect();
+        let b: Vec<u64> = (0..4).map(|_| b.next_u64()).collect();
+        let c: Vec<u64> = (0..4).map(|_| c.next_u64()).collect();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+
+        let mut rng = Rng::new(0);
+        for _ in 0..100 {
+            assert!((10..20).contains(&rng.range(10, 20)));
+        }
+    }
+}
@@ -0,0 +1,229 @@
+// Save states are a flat little-endian byte stream. Each component writes its
+// fields in a fixed order and reads them back in the same order, so any change
+// to what a component saves must bump STATE_VERSION.
+
+use std::error::Error;
+use std::fmt;
+
+pub const STATE_MAGIC: [u8; 4] = *b"NESS";
+pub const STATE_VERSION: u16 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    RomMismatch,
+    NoCartridge,
+    Truncated,
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(v) => write!(
+                f,
+                "save state version {} is not supported (expected {})",
+                v, STATE_VERSION
+            ),
+            StateError::RomMismatch => write!(f, "save state was made with a different ROM"),
+            StateError::NoCartridge => write!(f, "no cartridge is loaded"),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Invalid(what) => write!(f, "save state is corrupt: {}", what),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+pub trait SaveState {
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+pub struct StateWriter {
+    pub data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_i64(&mut self, value: i64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_f64(&mut self, value: f64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_usize(&mut self, value: usize) {
+        self.write_u64(value as u64);
+    }
+
+    // Length-prefixed, so that readers can check the size matches
+    pub fn write_bytes(&mut self, value: &[u8]) {
+        self.write_u32(value.len() as u32);
+        self.data.extend_from_slice(value);
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data, position: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() - self.position < n {
+            return Err(StateError::Truncated);
+        }
+        let result = &self.data[self.position..self.position + n];
+        self.position += n;
+        Ok(result)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Invalid("bad boolean")),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let mut b = [0; 2];
+        b.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(b))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64, StateError> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(i64::from_le_bytes(b))
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64, StateError> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(f64::from_le_bytes(b))
+    }
+
+    pub fn read_usize(&mut self) -> Result<usize, StateError> {
+        Ok(self.read_u64()? as usize)
+    }
+
+    // Reads a length-prefixed block into `out`, which must be the same size
+    pub fn read_bytes_into(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        let len = self.read_u32()? as usize;
+        if len != out.len() {
+            return Err(StateError::Invalid("memory size mismatch"));
+        }
+        out.copy_from_slice(self.take(len)?);
+        Ok(())
+    }
+
+    pub fn finished(&self) -> bool {
+        self.position == self.data.len()
+    }
+}
+
+// FNV-1a, used to tie a save state to the ROM it was made with
+pub fn hash(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in data {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    h
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut w = StateWriter::new();
+        w.write_u8(0x12);
+        w.write_bool(true);
+        w.write_u16(0x3456);
+        w.write_u64(0x0102_0304_0506_0708);
+        w.write_i64(-2);
+        w.write_f64(0.5);
+        w.write_bytes(&[1, 2, 3]);
+
+        let mut r = StateReader::new(&w.data);
+        assert_eq!(Ok(0x12), r.read_u8());
+        assert_eq!(Ok(true), r.read_bool());
+        assert_eq!(Ok(0x3456), r.read_u16());
+        assert_eq!(Ok(0x0102_0304_0506_0708), r.read_u64());
+        assert_eq!(Ok(-2), r.read_i64());
+        assert_eq!(Ok(0.5), r.read_f64());
+        let mut out = [0; 3];
+        assert_eq!(Ok(()), r.read_bytes_into(&mut out));
+        assert_eq!([1, 2, 3], out);
+        assert!(r.finished());
+        assert_eq!(Err(StateError::Truncated), r.read_u8());
+    }
+
+    #[test]
+    fn test_size_mismatch() {
+        let mut w = StateWriter::new();
+        w.write_bytes(&[1, 2, 3]);
+        let mut r = StateReader::new(&w.data);
+        let mut out = [0; 4];
+        assert_eq!(
+            Err(StateError::Invalid("memory size mismatch")),
+            r.read_bytes_into(&mut out)
+        );
+    }
+}
@@ -0,0 +1,354 @@
+// Rhai scripts that ride along with the emulation loop. A script's top level
+// runs once when it is loaded and registers callbacks:
+//
+//     on_frame(|| text(8, 8, "LIVES " + read(0x75), 0xFFFFFF));
+//     on_exec(0x8000, |pc| print("reset"));
+//     on_read(0x75, |address, value| ...);
+//     on_write(0x75, |address, value| ...);
+//     on_input(|| set_buttons(0, 0x08));
+//
+// Input callbacks run when the game strobes the controllers, so buttons they
+// set are the ones the game reads. Callbacks can use read, write,
+// set_buttons, buttons, pixel, text, save_state and load_state.
+
+use crate::bus::Access;
+use crate::nes::{Nes, Port};
+use crate::overlay;
+use rhai::{Blob, Dynamic, Engine, EvalAltResult, FnPtr, FuncArgs, AST, INT};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+
+const CONTROLLER_STROBE: u16 = 0x4016;
+
+#[derive(Debug, PartialEq)]
+pub enum ScriptError {
+    Parse(String),
+    Run(String),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::Parse(e) => write!(f, "script does not parse: {}", e),
+            ScriptError::Run(e) => write!(f, "script failed: {}", e),
+        }
+    }
+}
+
+impl Error for ScriptError {}
+
+impl From<Box<EvalAltResult>> for ScriptError {
+    fn from(e: Box<EvalAltResult>) -> Self {
+        ScriptError::Run(e.to_string())
+    }
+}
+
+#[derive(Default)]
+struct Hooks {
+    frame: Vec<FnPtr>,
+    input: Vec<FnPtr>,
+    exec: BTreeMap<u16, Vec<FnPtr>>,
+    read: BTreeMap<u16, Vec<FnPtr>>,
+    write: BTreeMap<u16, Vec<FnPtr>>,
+}
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    // The machine lives in here while the script runs, so the functions
+    // registered with the engine can reach it. Otherwise it holds a spare.
+    nes: Rc<RefCell<Nes>>,
+    hooks: Rc<RefCell<Hooks>>,
+}
+
+impl Script {
+    // Compiles the script and runs its top level against `nes`
+    pub fn load(nes: &mut Nes, source: &str) -> Result<Self, ScriptError> {
+        let shared = Rc::new(RefCell::new(Nes::new()));
+        let hooks = Rc::new(RefCell::new(Hooks::default()));
+        let mut engine = Engine::new();
+        register_machine(&mut engine, &shared);
+        register_hooks(&mut engine, &hooks);
+
+        let ast = engine
+            .compile(source)
+            .map_err(|e| ScriptError::Parse(e.to_string()))?;
+        let mut script = Script {
+            engine,
+            ast,
+            nes: shared,
+            hooks,
+        };
+        script.with_nes(nes, |s| s.engine.run_ast(&s.ast).map_err(ScriptError::from))?;
+        Ok(script)
+    }
+
+    // Runs the machine to the end of the frame, calling the script's hooks
+    // along the way
+    pub fn run_frame(&mut self, nes: &mut Nes) -> Result<(), ScriptError> {
+        self.watch(nes);
+        let result = self.with_nes(nes, |s| s.step_frame());
+        let watchpoints = &mut nes.cpu.bus.watchpoints;
+        watchpoints.logged_reads.clear();
+        watchpoints.logged_writes.clear();
+        watchpoints.log = None;
+        result
+    }
+
+    fn step_frame(&mut self) -> Result<(), ScriptError> {
+        loop {
+            let pc = self.nes.borrow().cpu.registers().pc;
+            let exec = self.hooks.borrow().exec.get(&pc).cloned();
+            for f in exec.unwrap_or_default() {
+                self.call(&f, (pc as INT,))?;
+            }
+
+            let frame_done = self.nes.borrow_mut().step_instruction();
+
+            let accesses = {
+                let mut nes = self.nes.borrow_mut();
+                nes.cpu.bus.watchpoints.log.replace(Vec::new()).unwrap_or_default()
+            };
+            for (access, address, value) in accesses {
+                self.accessed(access, address, value)?;
+            }
+
+            if frame_done {
+                break;
+            }
+        }
+        let frame = self.hooks.borrow().frame.clone();
+        for f in frame {
+            self.call(&f, ())?;
+        }
+        Ok(())
+    }
+
+    fn accessed(&mut self, access: Access, address: u16, value: u8) -> Result<(), ScriptError> {
+        let (callbacks, input) = {
+            let hooks = self.hooks.borrow();
+            let callbacks = match access {
+                Access::Read => hooks.read.get(&address),
+                Access::Write => hooks.write.get(&address),
+            };
+            // The game has latched the buttons once it ends the strobe
+            let strobe_done =
+                access == Access::Write && address == CONTROLLER_STROBE && value & 1 == 0;
+            (
+                callbacks.cloned().unwrap_or_default(),
+                if strobe_done {
+                    hooks.input.clone()
+                } else {
+                    Vec::new()
+                },
+            )
+        };
+        for f in input {
+            self.call(&f, ())?;
+        }
+        for f in callbacks {
+            self.call(&f, (address as INT, value as INT))?;
+        }
+        Ok(())
+    }
+
+    // Makes the Bus log accesses to every address the script hooks, without
+    // touching the debugger's watchpoints
+    fn watch(&self, nes: &mut Nes) {
+        let hooks = self.hooks.borrow();
+        let watchpoints = &mut nes.cpu.bus.watchpoints;
+        watchpoints.logged_reads.extend(hooks.read.keys());
+        watchpoints.logged_writes.extend(hooks.write.keys());
+        if !hooks.input.is_empty() {
+            watchpoints.logged_writes.insert(CONTROLLER_STROBE);
+        }
+        watchpoints.log = Some(Vec::new());
+    }
+
+    fn call(&self, f: &FnPtr, args: impl FuncArgs) -> Result<(), ScriptError> {
+        f.call::<Dynamic>(&self.engine, &self.ast, args)
+            .map(|_| ())
+            .map_err(ScriptError::from)
+    }
+
+    fn with_nes<T, F: FnOnce(&mut Self) -> T>(&mut self, nes: &mut Nes, f: F) -> T {
+        std::mem::swap(nes, &mut self.nes.borrow_mut());
+        let result = f(self);
+        std::mem::swap(nes, &mut self.nes.borrow_mut());
+        result
+    }
+}
+
+fn register_machine(engine: &mut Engine, nes: &Rc<RefCell<Nes>>) {
+    let n = nes.clone();
+    engine.register_fn("read", move |address: INT| -> INT {
+        n.borrow().cpu.bus.peek(address as u16) as INT
+    });
+    let n = nes.clone();
+    engine.register_fn("write", move |address: INT, value: INT| {
+        n.borrow_mut()
+            .cpu
+            .bus
+            .unclocked_write_byte(address as u16, value as u8);
+    });
+    let n = nes.clone();
+    engine.register_fn(
+        "buttons",
+        move |port: INT| -> Result<INT, Box<EvalAltResult>> {
+            Ok(n.borrow().buttons(controller_port(port)?) as INT)
+        },
+    );
+    let n = nes.clone();
+    engine.register_fn(
+        "set_buttons",
+        move |port: INT, buttons: INT| -> Result<(), Box<EvalAltResult>> {
+            n.borrow_mut()
+                .set_buttons(controller_port(port)?, buttons as u8);
+            Ok(())
+        },
+    );
+    let n = nes.clone();
+    engine.register_fn("pixel", move |x: INT, y: INT, color: INT| {
+        let pixels = &mut n.borrow_mut().cpu.bus.ppu.renderer.pixels;
+        overlay::draw_pixel(pixels, x as i32, y as i32, color as u32);
+    });
+    let n = nes.clone();
+    engine.register_fn("text", move |x: INT, y: INT, text: &str, color: INT| {
+        let pixels = &mut n.borrow_mut().cpu.bus.ppu.renderer.pixels;
+        overlay::draw_text(pixels, x as i32, y as i32, text, color as u32);
+    });
+    let n = nes.clone();
+    engine.register_fn("save_state", move || -> Blob { n.borrow().save_state() });
+    let n = nes.clone();
+    engine.register_fn(
+        "load_state",
+        move |state: Blob| -> Result<(), Box<EvalAltResult>> {
+            n.borrow_mut()
+                .load_state(&state)
+                .map_err(|e| e.to_string().into())
+        },
+    );
+}
+
+fn controller_port(port: INT) -> Result<Port, Box<EvalAltResult>> {
+    usize::try_from(port)
+        .ok()
+        .and_then(Port::from_index)
+        .ok_or_else(|| format!("no controller port {}", port).into())
+}
+
+fn register_hooks(engine: &mut Engine, hooks: &Rc<RefCell<Hooks>>) {
+    let h = hooks.clone();
+    engine.register_fn("on_frame", move |f: FnPtr| h.borrow_mut().frame.push(f));
+    let h = hooks.clone();
+    engine.register_fn("on_input", move |f: FnPtr| h.borrow_mut().input.push(f));
+    let h = hooks.clone();
+    engine.register_fn("on_exec", move |address: INT, f: FnPtr| {
+        h.borrow_mut()
+            .exec
+            .entry(address as u16)
+            .or_default()
+            .push(f)
+    });
+    let h = hooks.clone();
+    engine.register_fn("on_read", move |address: INT, f: FnPtr| {
+        h.borrow_mut()
+            .read
+            .entry(address as u16)
+            .or_default()
+            .push(f)
+    });
+    let h = hooks.clone();
+    engine.register_fn("on_write", move |address: INT, f: FnPtr| {
+        h.borrow_mut()
+            .write
+            .entry(address as u16)
+            .or_default()
+            .push(f)
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::{build_test_prg, build_test_rom};
+
+    // Strobes the controller, stores what port 0 reads at $10, and loops
+    fn build_nes() -> Nes {
+        let program = [
+            0xa9, 0x01, 0x8d, 0x16, 0x40, // LDA #1, STA $4016
+            0xa9, 0x00, 0x8d, 0x16, 0x40, // LDA #0, STA $4016
+            0xad, 0x16, 0x40, 0x85, 0x10, // LDA $4016, STA $10
+            0x4c, 0x00, 0x80, // JMP $8000
+        ];
+        let mut nes = Nes::new();
+        nes.load_rom(&build_test_rom(0, &build_test_prg(&program), &[])).unwrap();
+        nes
+    }
+
+    #[test]
+    fn test_hooks() {
+        let mut nes = build_nes();
+        let source = r#"
+            let state = save_state();
+            load_state(state);
+            on_input(|| set_buttons(0, 1));
+            on_write(0x10, |address, value| write(0x20, value));
+            on_exec(0x8000, |pc| write(0x21, read(0x21) + 1));
+            on_frame(|| text(0, 0, "HI", 0xFF0000));
+        "#;
+        let mut script = Script::load(&mut nes, source).unwrap();
+        script.run_frame(&mut nes).unwrap();
+        assert_eq!(nes.cpu.bus.ram[0x10], 0x41);
+        assert_eq!(nes.cpu.bus.ram[0x20], 0x41);
+        assert!(nes.cpu.bus.ram[0x21] > 0);
+        assert_eq!(nes.frame_buffer()[0], 0xFF0000);
+        assert_eq!(nes.cpu.bus.watchpoints.log, None);
+    }
+
+    #[test]
+    fn test_debugger_watchpoints_untouched() {
+        let mut nes = build_nes();
+        nes.cpu.bus.watchpoints.reads.insert(0x4016);
+        let source = r#"
+            on_input(|| ());
+            on_write(0x10, |address, value| ());
+            on_read(0x4016, |address, value| ());
+        "#;
+        let mut script = Script::load(&mut nes, source).unwrap();
+        script.run_frame(&mut nes).unwrap();
+
+        let watchpoints = &nes.cpu.bus.watchpoints;
+        assert_eq!(watchpoints.reads.iter().collect::<Vec<_>>(), vec![&0x4016]);
+        assert!(watchpoints.writes.is_empty());
+        assert!(watchpoints.logged_reads.is_empty());
+        assert!(watchpoints.logged_writes.is_empty());
+        // The debugger's own watchpoint still reports its hit
+        assert_eq!(watchpoints.hit.map(|(_, address, _)| address), Some(0x4016));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut nes = build_nes();
+        assert!(matches!(
+            Script::load(&mut nes, "on_frame(||"),
+            Err(ScriptError::Parse(_))
+        ));
+        let mut script = Script::load(&mut nes, "on_frame(|| load_state(blob(3)));").unwrap();
+        assert!(matches!(
+            script.run_frame(&mut nes),
+            Err(ScriptError::Run(_))
+        ));
+        // The machine is handed back even when the script fails
+        assert!(nes.cpu.bus.cartridge.is_some());
+
+        let mut script = Script::load(&mut nes, "on_input(|| set_buttons(2, 1));").unwrap();
+        assert!(matches!(
+            script.run_frame(&mut nes),
+            Err(ScriptError::Run(_))
+        ));
+    }
+}
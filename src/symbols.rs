@@ -0,0 +1,329 @@
+// Labels and comments for debugging, read from FCEUX name lists (.nl) and
+// ca65/ld65 debug info files (.dbg). Labels in PRG-ROM are keyed by their
+// offset in the ROM rather than by CPU address, so the same address can have
+// a different label depending on which bank the mapper has switched in.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+// FCEUX numbers name list banks in 16KB units
+const NL_BANK_SIZE: usize = 0x4000;
+const INES_HEADER_SIZE: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SymbolError {
+    UnsupportedVersion(String),
+    BadLine(usize),
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolError::UnsupportedVersion(v) => {
+                write!(f, "debug info version {} is not supported", v)
+            }
+            SymbolError::BadLine(line) => write!(f, "bad symbol on line {}", line),
+        }
+    }
+}
+
+impl Error for SymbolError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub comment: String,
+    // Arrays and tables cover more than one byte
+    pub size: usize,
+}
+
+impl Symbol {
+    // Comment and label lines to show above the code at this symbol
+    pub fn heading(&self) -> String {
+        let mut heading = String::new();
+        for line in self.comment.lines() {
+            heading.push_str("; ");
+            heading.push_str(line);
+            heading.push('\n');
+        }
+        if !self.name.is_empty() {
+            heading.push_str(&self.name);
+            heading.push_str(":\n");
+        }
+        heading
+    }
+}
+
+pub struct Symbols {
+    // Keyed by CPU address: RAM, registers and anything else the mapper doesn't bank
+    cpu: BTreeMap<usize, Symbol>,
+    // Keyed by PRG-ROM offset
+    rom: BTreeMap<usize, Symbol>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols {
+            cpu: BTreeMap::new(),
+            rom: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cpu.is_empty() && self.rom.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cpu.len() + self.rom.len()
+    }
+
+    // The symbol that starts exactly at `address`. `rom_offset` is where the
+    // mapper currently maps that address in PRG-ROM, if it does.
+    pub fn get(&self, address: u16, rom_offset: Option<usize>) -> Option<&Symbol> {
+        rom_offset
+            .and_then(|offset| self.rom.get(&offset))
+            .or_else(|| self.cpu.get(&(address as usize)))
+    }
+
+    // A name for `address`, written as name+N inside an array
+    pub fn label(&self, address: u16, rom_offset: Option<usize>) -> Option<String> {
+        let rom = rom_offset.and_then(|offset| containing(&self.rom, offset));
+        rom.or_else(|| containing(&self.cpu, address as usize))
+    }
+
+    // `bank` is the PRG-ROM bank of a game.nes.N.nl file, or None for
+    // game.nes.ram.nl. Lines look like $C000#Name#Comment, where $0300/10
+    // covers 16 bytes and lines starting with \ continue the comment.
+    pub fn parse_nl(&mut self, text: &str, bank: Option<usize>) -> Result<(), SymbolError> {
+        // Which map and key the previous line went to, for continued comments
+        let mut last: Option<(bool, usize)> = None;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if let Some(more) = line.strip_prefix('\\') {
+                let map = match last {
+                    Some((true, _)) => &mut self.rom,
+                    _ => &mut self.cpu,
+                };
+                if let Some(symbol) = last.and_then(|(_, key)| map.get_mut(&key)) {
+                    symbol.comment.push('\n');
+                    symbol.comment.push_str(more);
+                }
+                continue;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let bad_line = || SymbolError::BadLine(i + 1);
+            let mut fields = line.splitn(3, '#');
+            let location = fields.next().unwrap();
+            let name = fields.next().ok_or_else(bad_line)?;
+            let comment = fields.next().unwrap_or("").trim_end_matches('#');
+            let location = location.strip_prefix('$').ok_or_else(bad_line)?;
+            let (address, size) = match location.split_once('/') {
+                Some((a, s)) => (a, usize::from_str_radix(s, 16).map_err(|_| bad_line())?),
+                None => (location, 1),
+            };
+            let address = u16::from_str_radix(address, 16).map_err(|_| bad_line())?;
+
+            let symbol = Symbol {
+                name: name.to_string(),
+                comment: comment.to_string(),
+                size: size.max(1),
+            };
+            let (rom, key) = match bank {
+                Some(bank) if address >= 0x8000 => (
+                    true,
+                    bank * NL_BANK_SIZE + (address as usize % NL_BANK_SIZE),
+                ),
+                _ => (false, address as usize),
+            };
+            let map = if rom { &mut self.rom } else { &mut self.cpu };
+            map.entry(key).or_insert(symbol);
+            last = Some((rom, key));
+        }
+        Ok(())
+    }
+
+    // Reads the labels from an ld65 --dbgfile. Segments that made it into
+    // the .nes file give PRG-ROM offsets, everything else is a CPU address.
+    pub fn parse_dbg(&mut self, text: &str) -> Result<(), SymbolError> {
+        // Segment id to start address and offset in the .nes file
+        let mut segments: BTreeMap<usize, (u16, Option<usize>)> = BTreeMap::new();
+        let mut labels = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let bad_line = || SymbolError::BadLine(i + 1);
+            let (kind, attributes) = match line.split_once('\t') {
+                Some(pair) => pair,
+                None if line.trim().is_empty() => continue,
+                None => return Err(bad_line()),
+            };
+            let attributes = parse_attributes(attributes).ok_or_else(bad_line)?;
+            let get = |key: &str| attributes.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+            let number = |key: &str| get(key).and_then(parse_number).ok_or_else(bad_line);
+
+            match kind {
+                "version" if get("major") != Some("2") => {
+                    let version = format!(
+                        "{}.{}",
+                        get("major").unwrap_or("?"),
+                        get("minor").unwrap_or("?")
+                    );
+                    return Err(SymbolError::UnsupportedVersion(version));
+                }
+                "seg" => {
+                    let start = number("start")? as u16;
+                    let file_offset = get("ooffs").and_then(parse_number);
+                    segments.insert(number("id")?, (start, file_offset));
+                }
+                "sym" if get("type") == Some("lab") => {
+                    let name = get("name").ok_or_else(bad_line)?.trim_matches('"');
+                    let size = get("size").and_then(parse_number).unwrap_or(1);
+                    let segment = get("seg").and_then(parse_number);
+                    labels.push((name.to_string(), number("val")? as u16, size, segment));
+                }
+                _ => (),
+            }
+        }
+
+        for (name, address, size, segment) in labels {
+            let symbol = Symbol {
+                name,
+                comment: String::new(),
+                size: size.max(1),
+            };
+            match segment.and_then(|id| segments.get(&id)) {
+                Some(&(start, Some(file_offset)))
+                    if address >= 0x8000 && file_offset >= INES_HEADER_SIZE =>
+                {
+                    let offset = file_offset - INES_HEADER_SIZE + address.wrapping_sub(start) as usize;
+                    self.rom.entry(offset).or_insert(symbol);
+                }
+                _ => {
+                    self.cpu.entry(address as usize).or_insert(symbol);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Symbols {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn containing(map: &BTreeMap<usize, Symbol>, key: usize) -> Option<String> {
+    let (&start, symbol) = map.range(..=key).next_back()?;
+    match key - start {
+        _ if symbol.name.is_empty() => None,
+        0 => Some(symbol.name.clone()),
+        n if n < symbol.size => Some(format!("{}+{}", symbol.name, n)),
+        _ => None,
+    }
+}
+
+// Splits key=value,key="quoted, value" pairs
+fn parse_attributes(text: &str) -> Option<Vec<(&str, &str)>> {
+    let mut attributes = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let (key, after) = rest.split_once('=')?;
+        let end = if let Some(quoted) = after.strip_prefix('"') {
+            quoted.find('"')? + 2
+        } else {
+            after.find(',').unwrap_or(after.len())
+        };
+        attributes.push((key, &after[..end]));
+        rest = after[end..].strip_prefix(',').unwrap_or(&after[end..]);
+    }
+    Some(attributes)
+}
+
+fn parse_number(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_nl() {
+        let mut symbols = Symbols::new();
+        let ram = "$0000#temp#\n$0300/10#buffer#Sprite buffer\n\\second line\n";
+        symbols.parse_nl(ram, None).unwrap();
+        let bank = "$C000#Reset#Entry point\r\n$C010##Comment only\n";
+        symbols.parse_nl(bank, Some(2)).unwrap();
+
+        assert_eq!(Some("temp".to_string()), symbols.label(0x0000, None));
+        assert_eq!(Some("buffer+15".to_string()), symbols.label(0x030F, None));
+        assert_eq!(None, symbols.label(0x0310, None));
+        assert_eq!(
+            "Sprite buffer\nsecond line",
+            symbols.get(0x0300, None).unwrap().comment
+        );
+
+        // Bank 2 starts 32KB into the ROM
+        assert_eq!(
+            Some("Reset".to_string()),
+            symbols.label(0xC000, Some(0x8000))
+        );
+        assert_eq!(None, symbols.label(0xC000, Some(0x4000)));
+        assert_eq!(None, symbols.label(0xC010, Some(0x8010)));
+        assert_eq!(
+            "Comment only",
+            symbols.get(0xC010, Some(0x8010)).unwrap().comment
+        );
+
+        assert_eq!(
+            Err(SymbolError::BadLine(1)),
+            symbols.parse_nl("C000#NoDollar#", Some(0))
+        );
+        assert_eq!(
+            Err(SymbolError::BadLine(2)),
+            symbols.parse_nl("\n$zz#Bad#", None)
+        );
+    }
+
+    #[test]
+    fn test_parse_dbg() {
+        let text = "version\tmajor=2,minor=0\n\
+            info\tcsym=0,file=1,seg=3,sym=4\n\
+            file\tid=0,name=\"main, game.s\",size=100,mtime=0x5F000000,mod=0\n\
+            seg\tid=0,name=\"HEADER\",start=0x000000,size=0x0010,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=0\n\
+            seg\tid=1,name=\"CODE\",start=0x00C000,size=0x4000,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16400\n\
+            seg\tid=2,name=\"BSS\",start=0x000300,size=0x0100,addrsize=absolute,type=rw\n\
+            sym\tid=0,name=\"reset\",addrsize=absolute,scope=0,def=1,val=0xC004,seg=1,type=lab\n\
+            sym\tid=1,name=\"buffer\",addrsize=absolute,size=4,scope=0,def=2,val=0x300,seg=2,type=lab\n\
+            sym\tid=2,name=\"SPEED\",addrsize=zeropage,scope=0,def=3,val=0x3,type=equ\n\
+            sym\tid=3,name=\"nmi\",addrsize=absolute,scope=0,def=4,val=0xC000,seg=1,type=lab\n";
+        let mut symbols = Symbols::new();
+        symbols.parse_dbg(text).unwrap();
+
+        assert_eq!(3, symbols.len());
+        assert_eq!(
+            Some("reset".to_string()),
+            symbols.label(0xC004, Some(0x4004))
+        );
+        assert_eq!(Some("nmi".to_string()), symbols.label(0xC000, Some(0x4000)));
+        assert_eq!(None, symbols.label(0xC000, Some(0x0000)));
+        assert_eq!(Some("buffer+3".to_string()), symbols.label(0x0303, None));
+        assert_eq!(None, symbols.label(0x0003, None));
+
+        assert_eq!(
+            Err(SymbolError::UnsupportedVersion("3.1".to_string())),
+            Symbols::new().parse_dbg("version\tmajor=3,minor=1\n")
+        );
+        assert_eq!(
+            Err(SymbolError::BadLine(1)),
+            Symbols::new().parse_dbg("sym\tid=0,name=\"x\",val=zz,type=lab\n")
+        );
+    }
+}
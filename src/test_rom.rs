@@ -0,0 +1,157 @@
+// Runs test ROMs that follow blargg's conventions: once $6001-$6003 hold the
+// signature DE B0 61, $6000 is a status byte and $6004 on is a zero-terminated
+// text message. Status $80 means still running, $81 means press reset, and
+// anything below $80 is the final result where 0 is a pass.
+
+use crate::nes::Nes;
+
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_NEEDS_RESET: u8 = 0x81;
+
+// Tests want at least 100ms between asking for a reset and getting one
+const RESET_DELAY_FRAMES: u32 = 10;
+
+const TEXT_START: u16 = 0x6004;
+const TEXT_END: u16 = 0x8000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TestStatus {
+    Passed,
+    Failed(u8),
+    // The ROM never reported a result within the frame limit
+    TimedOut,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestResult {
+    pub status: TestStatus,
+    pub frames: u32,
+    pub text: String,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.status == TestStatus::Passed
+    }
+}
+
+// Runs the loaded ROM for up to `max_frames` frames or until it reports a result
+pub fn run(nes: &mut Nes, max_frames: u32) -> TestResult {
+    let mut reset_at = None;
+    for frame in 1..=max_frames {
+        nes.step_frame();
+        nes.drain_audio();
+
+        if !has_signature(nes) {
+            continue;
+        }
+        match read(nes, 0x6000) {
+            STATUS_RUNNING => (),
+            STATUS_NEEDS_RESET => match reset_at {
+                None => reset_at = Some(frame + RESET_DELAY_FRAMES),
+                Some(f) if f <= frame => {
+                    nes.reset();
+                    reset_at = None;
+                }
+                Some(_) => (),
+            },
+            status => {
+                return TestResult {
+                    status: if status == 0 {
+                        TestStatus::Passed
+                    } else {
+                        TestStatus::Failed(status)
+                    },
+                    frames: frame,
+                    text: text(nes),
+                }
+            }
+        }
+    }
+
+    TestResult {
+        status: TestStatus::TimedOut,
+        frames: max_frames,
+        text: if has_signature(nes) {
+            text(nes)
+        } else {
+            String::new()
+        },
+    }
+}
+
+fn read(nes: &Nes, address: u16) -> u8 {
+    nes.cpu.bus.peek(address)
+}
+
+fn has_signature(nes: &Nes) -> bool {
+    (0..3).all(|i| read(nes, 0x6001 + i) == SIGNATURE[i as usize])
+}
+
+fn text(nes: &Nes) -> String {
+    let mut bytes = Vec::new();
+    for address in TEXT_START..TEXT_END {
+        match read(nes, address) {
+            0 => break,
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8_lossy(&bytes).trim_end().to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::{build_test_prg, build_test_rom};
+
+    fn build_rom(result: u8) -> Vec<u8> {
+        let mut program = Vec::new();
+        let mut store = |value: u8, address: u16| {
+            // LDA #value, STA address
+            program.extend_from_slice(&[0xa9, value, 0x8d, address as u8, (address >> 8) as u8]);
+        };
+        store(STATUS_RUNNING, 0x6000);
+        store(0xde, 0x6001);
+        store(0xb0, 0x6002);
+        store(0x61, 0x6003);
+        for (i, &c) in b"Done\n".iter().chain(&[0]).enumerate() {
+            store(c, TEXT_START + i as u16);
+        }
+        store(result, 0x6000);
+        let end = 0x8000 + program.len() as u16;
+        // JMP to itself
+        program.extend_from_slice(&[0x4c, end as u8, (end >> 8) as u8]);
+        build_test_rom(0, &build_test_prg(&program), &[])
+    }
+
+    fn run_rom(rom: &[u8]) -> TestResult {
+        let mut nes = Nes::new();
+        nes.load_rom(rom).unwrap();
+        run(&mut nes, 10)
+    }
+
+    #[test]
+    fn test_passed() {
+        let result = run_rom(&build_rom(0));
+        assert_eq!(TestStatus::Passed, result.status);
+        assert_eq!(1, result.frames);
+        assert_eq!("Done", result.text);
+        assert!(result.passed());
+    }
+
+    #[test]
+    fn test_failed() {
+        let result = run_rom(&build_rom(3));
+        assert_eq!(TestStatus::Failed(3), result.status);
+        assert!(!result.passed());
+    }
+
+    #[test]
+    fn test_timed_out() {
+        let result = run_rom(&build_rom(STATUS_RUNNING));
+        assert_eq!(TestStatus::TimedOut, result.status);
+        assert_eq!(10, result.frames);
+        assert_eq!("Done", result.text);
+    }
+}